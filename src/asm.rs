@@ -0,0 +1,421 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::number;
+use crate::symbols::SymbolTable;
+use crate::instruction::{self, ArithmeticLogic, Audio, Display, IRegister, Instruction, Register, Skip, Subroutine, TimerInstruction};
+
+// Assembles the mnemonics printed by `disasm` in Cowgod syntax. A line holds an optional
+// `label:`, then an instruction or directive, then an optional `; comment`:
+//
+//     SPEED equ 3
+//     start:  LD I, sprite        ; labels may be used before they are defined
+//             ADD V0, SPEED
+//             JP start
+//     sprite: db 0xF0, 0x90, %11110000
+//             include "font.asm"
+//
+// Numbers are decimal, 0x/# hexadecimal or 0b/% binary, and operands may add or subtract
+// numbers, labels and constants.
+
+pub struct Assembly {
+    pub program: Vec<u8>,
+    pub symbols: SymbolTable
+}
+
+#[derive(Debug)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub message: String
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// A statement with its place in the source and the address it was laid out at
+struct Line {
+    file: String,
+    line: usize,
+    addr: usize,
+    statement: Statement
+}
+
+enum Statement {
+    Instruction(String, Vec<String>),
+    Bytes(Vec<String>),
+    Words(Vec<String>)
+}
+
+#[derive(Default)]
+struct Assembler {
+    lines: Vec<Line>,
+    labels: BTreeMap<String, u16>,
+    constants: BTreeMap<String, i64>,
+    includes: Vec<PathBuf>,
+    addr: usize
+}
+
+// Assembles the file at `path` for a program loaded at `offset`
+pub fn assemble_file(path: &str, offset: u16) -> Result<Assembly, AsmError> {
+    let mut assembler = Assembler { addr: offset as usize, ..Assembler::default() };
+    assembler.include(Path::new(path), path, 0)?;
+    assembler.finish(offset)
+}
+
+// Assembles source that is not in a file; `include` paths are relative to the working directory
+pub fn assemble(source: &str, name: &str, offset: u16) -> Result<Assembly, AsmError> {
+    let mut assembler = Assembler { addr: offset as usize, ..Assembler::default() };
+    assembler.read(source, name, Path::new(""))?;
+    assembler.finish(offset)
+}
+
+impl Assembler {
+    fn include(&mut self, path: &Path, from: &str, line: usize) -> Result<(), AsmError> {
+        let error = |message: String| AsmError { file: from.to_string(), line, message };
+        let name = path.display().to_string();
+        let source = fs::read_to_string(path).map_err(|e| error(format!("cannot read `{name}`: {e}")))?;
+        let canonical = path.canonicalize().unwrap_or(path.to_path_buf());
+        if self.includes.contains(&canonical) { return Err(error(format!("`{name}` includes itself"))) }
+
+        self.includes.push(canonical);
+        self.read(&source, &name, path.parent().unwrap_or(Path::new("")))?;
+        self.includes.pop();
+        Ok(())
+    }
+
+    // First pass: defines labels and constants and lays every statement out in memory
+    fn read(&mut self, source: &str, file: &str, directory: &Path) -> Result<(), AsmError> {
+        for (number, text) in source.lines().enumerate() {
+            let line = number + 1;
+            let error = |message: String| AsmError { file: file.to_string(), line, message };
+            let mut text = text.split(';').next().unwrap_or("").trim();
+
+            if let Some((label, rest)) = text.split_once(':') {
+                let label = label.trim();
+                if is_name(label) {
+                    if self.is_defined(label) { return Err(error(format!("`{label}` is already defined"))) }
+                    self.labels.insert(label.to_string(), self.addr as u16);
+                    text = rest.trim();
+                }
+            }
+            if text.is_empty() { continue }
+
+            let (keyword, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+            let operands = operands.trim();
+            if let Some((name, value)) = equ(text) {
+                if !is_name(name) { return Err(error(format!("invalid constant name `{name}`"))) }
+                if self.is_defined(name) { return Err(error(format!("`{name}` is already defined"))) }
+                let value = self.evaluate(value).map_err(error)?;
+                self.constants.insert(name.to_string(), value);
+                continue;
+            }
+
+            let statement = match keyword.to_ascii_lowercase().as_str() {
+                "include" => {
+                    let path = operands.strip_prefix('"').and_then(|path| path.strip_suffix('"'))
+                        .ok_or_else(|| error(String::from("include needs a quoted path")))?;
+                    self.include(&directory.join(path), file, line)?;
+                    continue;
+                },
+                "db" => Statement::Bytes(split_operands(operands)),
+                "dw" => Statement::Words(split_operands(operands)),
+                _ => Statement::Instruction(keyword.to_ascii_uppercase(), split_operands(operands))
+            };
+            let length = match &statement {
+                Statement::Bytes(values) => values.len(),
+                Statement::Words(values) => 2 * values.len(),
+                Statement::Instruction(_, operands) if operands.get(1).and_then(|operand| is_long(operand)).is_some() => 4,
+                Statement::Instruction(..) => 2
+            };
+            self.lines.push(Line { file: file.to_string(), line, addr: self.addr, statement });
+            self.addr += length;
+        }
+        Ok(())
+    }
+
+    // Second pass: encodes every statement now that all labels are known
+    fn finish(self, offset: u16) -> Result<Assembly, AsmError> {
+        let mut program = Vec::new();
+        for line in &self.lines {
+            let error = |message: String| AsmError { file: line.file.clone(), line: line.line, message };
+            if line.addr > 0xFFFF { return Err(error(String::from("program does not fit in 64 KiB"))) }
+
+            match &line.statement {
+                Statement::Bytes(values) => {
+                    for value in values { program.push(self.number(value, -128, 0xFF).map_err(error)? as u8); }
+                },
+                Statement::Words(values) => {
+                    for value in values {
+                        let word = self.number(value, -0x8000, 0xFFFF).map_err(error)? as u16;
+                        program.extend_from_slice(&word.to_be_bytes());
+                    }
+                },
+                Statement::Instruction(mnemonic, operands) => {
+                    let instruction = self.instruction(mnemonic, operands).map_err(error)?;
+                    program.extend_from_slice(&instruction::encode(instruction).to_be_bytes());
+                    if let Some(long) = operands.get(1).and_then(|operand| is_long(operand)) {
+                        let addr = self.number(long, 0, 0xFFFF).map_err(error)? as u16;
+                        program.extend_from_slice(&addr.to_be_bytes());
+                    }
+                }
+            }
+        }
+        debug_assert_eq!(program.len(), self.addr - offset as usize);
+
+        let mut symbols = SymbolTable::new();
+        for (name, addr) in &self.labels { symbols.insert(*addr as usize, name); }
+        Ok(Assembly { program, symbols })
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[String]) -> Result<Instruction, String> {
+        let operands: Vec<Operand> = operands.iter().map(|operand| Operand::parse(operand)).collect();
+        let byte = |value: &str| self.number(value, -128, 0xFF).map(|byte| byte as u8);
+        let addr = |value: &str| self.number(value, 0, 0xFFF).map(|addr| addr as usize);
+        let nibble = |value: &str| self.number(value, 0, 0xF).map(|n| n as u8);
+        let alu = |operation| match operands[..] {
+            [Operand::V(x), Operand::V(y)] => Ok(Instruction::ArithmeticLogic(operation, x, y)),
+            _ => Err(format!("{mnemonic} takes two registers"))
+        };
+        let shift = |operation| match operands[..] {
+            [Operand::V(x)] => Ok(Instruction::ArithmeticLogic(operation, x, x)),
+            _ => alu(operation)
+        };
+        let none = |instruction| if operands.is_empty() { Ok(instruction) } else { Err(format!("{mnemonic} takes no operands")) };
+
+        match (mnemonic, &operands[..]) {
+            ("CLS", _) => none(Instruction::Display(Display::Clear)),
+            ("RET", _) => none(Instruction::Subroutine(Subroutine::Return)),
+            ("SCL", _) => none(Instruction::Display(Display::ScrollLeft)),
+            ("SCR", _) => none(Instruction::Display(Display::ScrollRight)),
+            ("LOW", _) => none(Instruction::Display(Display::LowResolution)),
+            ("HIGH", _) => none(Instruction::Display(Display::HighResolution)),
+            ("EXIT", _) => none(Instruction::Exit),
+            ("AUDIO", _) => none(Instruction::Audio(Audio::LoadPattern)),
+            ("SCD", [Operand::Value(n)]) => Ok(Instruction::Display(Display::ScrollDown(nibble(n)?))),
+            ("SCU", [Operand::Value(n)]) => Ok(Instruction::Display(Display::ScrollUp(nibble(n)?))),
+            ("PLANE", [Operand::Value(mask)]) => Ok(Instruction::Display(Display::SelectPlanes(nibble(mask)?))),
+            ("JP", [Operand::Value(target)]) => Ok(Instruction::Jump(addr(target)?)),
+            ("JP", [Operand::V(0), Operand::Value(target)]) => Ok(Instruction::JumpOffset(addr(target)?, 0)),
+            ("CALL", [Operand::Value(target)]) => Ok(Instruction::Subroutine(Subroutine::Call(addr(target)?))),
+            ("SE", [Operand::V(x), Operand::V(y)]) => Ok(Instruction::Skip(Skip::EqualRegister, *x, *y as u8)),
+            ("SE", [Operand::V(x), Operand::Value(value)]) => Ok(Instruction::Skip(Skip::Equal, *x, byte(value)?)),
+            ("SNE", [Operand::V(x), Operand::V(y)]) => Ok(Instruction::Skip(Skip::NotEqualRegister, *x, *y as u8)),
+            ("SNE", [Operand::V(x), Operand::Value(value)]) => Ok(Instruction::Skip(Skip::NotEqual, *x, byte(value)?)),
+            ("SKP", [Operand::V(x)]) => Ok(Instruction::Skip(Skip::KeyPressed, *x, 0)),
+            ("SKNP", [Operand::V(x)]) => Ok(Instruction::Skip(Skip::KeyNotPressed, *x, 0)),
+            ("OR", _) => alu(ArithmeticLogic::BitwiseOr),
+            ("AND", _) => alu(ArithmeticLogic::BitwiseAnd),
+            ("XOR", _) => alu(ArithmeticLogic::BitwiseXor),
+            ("SUB", _) => alu(ArithmeticLogic::SubtractWithBorrow),
+            ("SUBN", _) => alu(ArithmeticLogic::SubtractYWithBorrow),
+            ("SHR", _) => shift(ArithmeticLogic::ShiftRight),
+            ("SHL", _) => shift(ArithmeticLogic::ShiftLeft),
+            ("ADD", [Operand::V(x), Operand::V(y)]) => Ok(Instruction::ArithmeticLogic(ArithmeticLogic::AddsWithCarry, *x, *y)),
+            ("ADD", [Operand::V(x), Operand::Value(value)]) => Ok(Instruction::Register(Register::Add, *x, byte(value)?)),
+            ("ADD", [Operand::I, Operand::V(x)]) => Ok(Instruction::IRegister(IRegister::AddRegister(*x))),
+            ("RND", [Operand::V(x), Operand::Value(value)]) => Ok(Instruction::RandomByte(*x, byte(value)?)),
+            ("DRW", [Operand::V(x), Operand::V(y), Operand::Value(n)]) => Ok(Instruction::Display(Display::DrawSprite(*x, *y, nibble(n)?))),
+            ("LD", [Operand::V(x), Operand::V(y)]) => Ok(Instruction::Register(Register::Copy, *x, *y as u8)),
+            ("LD", [Operand::V(x), Operand::Value(value)]) => Ok(Instruction::Register(Register::Set, *x, byte(value)?)),
+            ("LD", [Operand::V(x), Operand::Keyword("DT")]) => Ok(Instruction::Register(Register::SetToDelayTimer, *x, 0)),
+            ("LD", [Operand::V(x), Operand::Keyword("K")]) => Ok(Instruction::WaitKeyPress(*x)),
+            ("LD", [Operand::V(x), Operand::Keyword("[I]")]) => Ok(Instruction::Register(Register::ReadFromMemory, *x, 0)),
+            ("LD", [Operand::V(x), Operand::Keyword("R")]) => Ok(Instruction::Register(Register::ReadFlags, *x, 0)),
+            ("LD", [Operand::Range(x, y), Operand::Keyword("[I]")]) => Ok(Instruction::Register(Register::ReadRange, *x, *y as u8)),
+            ("LD", [Operand::Keyword("[I]"), Operand::V(x)]) => Ok(Instruction::Register(Register::StoreInMemory, *x, 0)),
+            ("LD", [Operand::Keyword("[I]"), Operand::Range(x, y)]) => Ok(Instruction::Register(Register::StoreRange, *x, *y as u8)),
+            ("LD", [Operand::Keyword("R"), Operand::V(x)]) => Ok(Instruction::Register(Register::StoreFlags, *x, 0)),
+            ("LD", [Operand::I, Operand::Long]) => Ok(Instruction::IRegister(IRegister::SetLong)),
+            ("LD", [Operand::I, Operand::Value(target)]) => Ok(Instruction::IRegister(IRegister::Set(addr(target)? as u16))),
+            ("LD", [Operand::Keyword("F"), Operand::V(x)]) => Ok(Instruction::IRegister(IRegister::SetToLocationSprite(*x))),
+            ("LD", [Operand::Keyword("HF"), Operand::V(x)]) => Ok(Instruction::IRegister(IRegister::SetToLocationBigSprite(*x))),
+            ("LD", [Operand::Keyword("DT"), Operand::V(x)]) => Ok(Instruction::Timer(TimerInstruction::SetDelay, *x)),
+            ("LD", [Operand::Keyword("ST"), Operand::V(x)]) => Ok(Instruction::Timer(TimerInstruction::SetSound, *x)),
+            ("LD", [Operand::Keyword("B"), Operand::V(x)]) => Ok(Instruction::StoreBCD(*x)),
+            ("LD", [Operand::Keyword("PITCH"), Operand::V(x)]) => Ok(Instruction::Audio(Audio::SetPitch(*x))),
+            ("SCD" | "SCU" | "PLANE" | "JP" | "CALL" | "SE" | "SNE" | "SKP" | "SKNP" | "ADD" | "RND" | "DRW" | "LD", _) => {
+                Err(format!("invalid operands for {mnemonic}"))
+            },
+            _ => Err(format!("unknown instruction `{mnemonic}`"))
+        }
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.labels.contains_key(name) || self.constants.contains_key(name)
+    }
+
+    // Evaluates an operand and checks it lies in min..=max
+    fn number(&self, text: &str, min: i64, max: i64) -> Result<i64, String> {
+        let value = self.evaluate(text)?;
+        if value < min || value > max { return Err(format!("`{text}` is {value}, outside {min}..={max}")) }
+        Ok(value)
+    }
+
+    // Numbers, labels and constants joined by + and -, with an optional leading -
+    fn evaluate(&self, text: &str) -> Result<i64, String> {
+        let text = text.trim();
+        let (mut sign, rest): (i64, &str) = match text.strip_prefix('-') {
+            Some(rest) => (-1, rest),
+            None => (1, text)
+        };
+        let mut total: i64 = 0;
+        let mut term = String::new();
+        for c in rest.chars().chain(['+']) {
+            if c != '+' && c != '-' {
+                term.push(c);
+                continue;
+            }
+
+            let name = term.trim();
+            if name.is_empty() { return Err(format!("missing value in `{text}`")) }
+            let value = number::parse(name)
+                .or_else(|| self.constants.get(name).copied())
+                .or_else(|| self.labels.get(name).map(|addr| *addr as i64))
+                .ok_or_else(|| format!("unknown name `{name}`"))?;
+            total = sign.checked_mul(value).and_then(|value| total.checked_add(value))
+                .ok_or_else(|| format!("`{text}` overflows"))?;
+            sign = if c == '-' { -1 } else { 1 };
+            term.clear();
+        }
+        Ok(total)
+    }
+}
+
+enum Operand<'a> {
+    V(usize),
+    Range(usize, usize),    // VX-VY
+    I,
+    Long,                   // LONG addr, written as a second word after F000
+    Keyword(&'static str),
+    Value(&'a str)
+}
+
+impl<'a> Operand<'a> {
+    fn parse(text: &'a str) -> Operand<'a> {
+        const KEYWORDS: [&str; 8] = ["DT", "ST", "K", "[I]", "R", "F", "HF", "B"];
+
+        let upper = text.to_ascii_uppercase();
+        if let Some(x) = register(text) { return Operand::V(x) }
+        if let Some((x, y)) = text.split_once('-').and_then(|(x, y)| Some((register(x.trim())?, register(y.trim())?))) {
+            return Operand::Range(x, y);
+        }
+        if upper == "I" { return Operand::I }
+        if upper == "PITCH" { return Operand::Keyword("PITCH") }
+        if is_long(text).is_some() { return Operand::Long }
+        match KEYWORDS.iter().find(|keyword| **keyword == upper) {
+            Some(keyword) => Operand::Keyword(keyword),
+            None => Operand::Value(text)
+        }
+    }
+}
+
+fn register(text: &str) -> Option<usize> {
+    let digit = text.strip_prefix('V').or(text.strip_prefix('v'))?;
+    if digit.len() != 1 { return None }
+    usize::from_str_radix(digit, 16).ok()
+}
+
+// The address of a `LONG addr` operand
+fn is_long(operand: &str) -> Option<&str> {
+    let (keyword, addr) = operand.split_once(char::is_whitespace)?;
+    keyword.eq_ignore_ascii_case("LONG").then_some(addr.trim())
+}
+
+// `NAME equ VALUE`
+fn equ(text: &str) -> Option<(&str, &str)> {
+    let mut words = text.splitn(3, char::is_whitespace);
+    let (name, keyword, value) = (words.next()?, words.next()?, words.next()?);
+    keyword.eq_ignore_ascii_case("equ").then_some((name, value.trim()))
+}
+
+fn split_operands(text: &str) -> Vec<String> {
+    if text.is_empty() { return Vec::new() }
+    text.split(',').map(|operand| operand.trim().to_string()).collect()
+}
+
+fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> (usize, String) {
+        let error = assemble(source, "test.asm", 0x200).err().unwrap();
+        assert_eq!(error.file, "test.asm");
+        (error.line, error.message)
+    }
+
+    #[test]
+    fn resolves_forward_labels_constants_and_data() {
+        let source = "
+SPEED equ 3
+start:  LD I, sprite
+        ADD V0, SPEED + 1
+        JP start
+sprite: db 0xF0, #90, %1001, -1
+        dw sprite - 2";
+        let assembly = assemble(source, "test.asm", 0x200).unwrap();
+        assert_eq!(assembly.program, [0xA2, 0x06, 0x70, 0x04, 0x12, 0x00, 0xF0, 0x90, 0x09, 0xFF, 0x02, 0x04]);
+        assert_eq!(assembly.symbols.name(0x206), Some("sprite"));
+    }
+
+    #[test]
+    fn long_loads_take_two_words() {
+        let assembly = assemble("LD I, LONG data\nEXIT\ndata: db 1", "test.asm", 0x200).unwrap();
+        assert_eq!(assembly.program, [0xF0, 0x00, 0x02, 0x06, 0x00, 0xFD, 0x01]);
+    }
+
+    #[test]
+    fn reports_operands_out_of_range() {
+        assert_eq!(error("CLS\nLD V0, 256"), (2, String::from("`256` is 256, outside -128..=255")));
+        assert_eq!(error("CLS\nCLS\nJP 0x1000"), (3, String::from("`0x1000` is 4096, outside 0..=4095")));
+        assert_eq!(error("DRW V0, V1, 16").0, 1);
+        assert_eq!(error("\n\ndb 1, 2, 300").0, 3);
+        assert_eq!(error("LD I, LONG 0x10000").0, 1);
+    }
+
+    #[test]
+    fn reports_duplicate_and_unknown_names() {
+        assert_eq!(error("a: CLS\nb: CLS\na: RET"), (3, String::from("`a` is already defined")));
+        assert_eq!(error("SIZE equ 1\nSIZE equ 2"), (2, String::from("`SIZE` is already defined")));
+        assert_eq!(error("CLS\nJP nowhere\nnowhere2: RET"), (2, String::from("unknown name `nowhere`")));
+        assert_eq!(error("X equ Y\nY equ 1"), (1, String::from("unknown name `Y`")));
+    }
+
+    #[test]
+    fn reports_overflow() {
+        assert_eq!(error("CLS\nLD V0, 0x7FFFFFFFFFFFFFFF + 1"), (2, String::from("`0x7FFFFFFFFFFFFFFF + 1` overflows")));
+        assert_eq!(error("BIG equ 0x7FFFFFFFFFFFFFFF\nSMALL equ -BIG - 2").0, 2);
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        assert_eq!(error("CLS\n\nFOO V1"), (3, String::from("unknown instruction `FOO`")));
+        assert_eq!(error("SE V1"), (1, String::from("invalid operands for SE")));
+        assert_eq!(error("CLS V1"), (1, String::from("CLS takes no operands")));
+    }
+
+    #[test]
+    fn errors_in_included_files_name_that_file() {
+        let directory = std::env::temp_dir().join(format!("chip-8-asm-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("main.asm"), "CLS\ninclude \"part.asm\"\n").unwrap();
+        fs::write(directory.join("part.asm"), "RET\nJP missing\n").unwrap();
+
+        let error = assemble_file(directory.join("main.asm").to_str().unwrap(), 0x200).err().unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(error.file.ends_with("part.asm"), "{error}");
+        assert_eq!((error.line, error.message.as_str()), (2, "unknown name `missing`"));
+    }
+}
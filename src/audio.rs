@@ -0,0 +1,105 @@
+use crate::chip::Chip8;
+use crate::config::Config;
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+pub struct Beeper {
+    frequency: f32,
+    volume: f32,
+    phase: f32,
+    muted: bool
+}
+
+impl Beeper {
+    pub fn new(config: &Config) -> Beeper {
+        Beeper {
+            frequency: config.beep_frequency as f32,
+            volume: config.volume.clamp(0.0, 1.0),
+            phase: 0.0,
+            muted: config.muted
+        }
+    }
+
+    pub fn toggle_mute(&mut self) { self.muted = !self.muted; }
+
+    // Fills `buffer` with 16-bit mono samples for as long as the sound timer is running
+    pub fn fill(&mut self, buffer: &mut [i16], chip8: &Chip8) {
+        if self.muted || !chip8.is_beeping() {
+            buffer.fill(0);
+            self.phase = 0.0;
+            return;
+        }
+
+        let amplitude = (self.volume * i16::MAX as f32) as i16;
+
+        match chip8.audio_pattern() {
+            // XO-CHIP plays its 128-bit pattern buffer at 4000 * 2^((pitch - 64) / 48) bits per second
+            Some(pattern) => {
+                let rate = 4000.0 * 2f32.powf((chip8.pitch() as f32 - 64.0) / 48.0);
+                for sample in buffer.iter_mut() {
+                    let bit = self.phase as usize;
+                    let on = pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+                    *sample = if on { amplitude } else { -amplitude };
+                    self.phase = (self.phase + rate / SAMPLE_RATE as f32) % 128.0;
+                }
+            },
+            None => {
+                for sample in buffer.iter_mut() {
+                    *sample = if self.phase < 0.5 { amplitude } else { -amplitude };
+                    self.phase = (self.phase + self.frequency / SAMPLE_RATE as f32) % 1.0;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypad::Keypad;
+    use crate::rom::Rom;
+
+    // LD V0, 10; LD ST, V0, stopped before the sound timer is set
+    fn about_to_beep(config: &Config) -> Chip8 {
+        let mut chip8 = Chip8::new(Rom::new(vec![0x60, 0x0A, 0xF0, 0x18]), config).unwrap();
+        chip8.run_cycle(Keypad::new()).unwrap();
+        chip8
+    }
+
+    fn samples(config: &Config, chip8: &Chip8, length: usize) -> Vec<i16> {
+        let mut buffer = vec![1; length];
+        Beeper::new(config).fill(&mut buffer, chip8);
+        buffer
+    }
+
+    #[test]
+    fn silent_while_the_sound_timer_is_zero() {
+        let config = Config::default();
+        let mut chip8 = about_to_beep(&config);
+        assert!(samples(&config, &chip8, 100).iter().all(|sample| *sample == 0));
+
+        chip8.run_cycle(Keypad::new()).unwrap();
+        assert!(chip8.is_beeping());
+        assert!(samples(&config, &chip8, 100).iter().any(|sample| *sample != 0));
+
+        let muted = Config { muted: true, ..Config::default() };
+        assert!(samples(&muted, &chip8, 100).iter().all(|sample| *sample == 0));
+    }
+
+    #[test]
+    fn square_wave_has_the_configured_period_and_volume() {
+        let config = Config { beep_frequency: 441, volume: 0.5, ..Config::default() };
+        let mut chip8 = about_to_beep(&config);
+        chip8.run_cycle(Keypad::new()).unwrap();
+
+        // 44100 / 441 = 100 samples per period, half of them high
+        let buffer = samples(&config, &chip8, 4410);
+        let amplitude = i16::MAX / 2;
+        assert!(buffer.iter().all(|sample| sample.abs() == amplitude));
+        assert!(buffer[..49].iter().all(|sample| *sample > 0));
+        assert!(buffer[51..99].iter().all(|sample| *sample < 0));
+
+        let rising = buffer.windows(2).filter(|pair| pair[0] < 0 && pair[1] > 0).count();
+        assert_eq!(rising, 44);
+    }
+}
@@ -0,0 +1,176 @@
+use serde_json::Value;
+use crate::config::{Config, Platform, Quirks};
+use crate::database::{apply_quirks, parse_color, tickrate_hertz, QuirkFlag, RomSettings};
+use crate::error::Chip8Error;
+use crate::gif;
+
+// An Octo cartridge is a GIF whose first frame carries a JSON document in the low two bits
+// of each palette index, four pixels per byte with the most significant bits first. The
+// document is prefixed with its length as a 32-bit big-endian number and holds the Octo
+// source as `program` and the emulator settings as `options`.
+pub struct Cartridge {
+    pub source: String,
+    pub settings: RomSettings
+}
+
+pub fn is_cartridge(bytes: &[u8]) -> bool { gif::is_gif(bytes) }
+
+pub fn decode(bytes: &[u8]) -> Result<Cartridge, Chip8Error> {
+    let error = |reason: &str| Chip8Error::BadCartridge { reason: reason.to_string() };
+
+    let pixels = gif::first_frame(bytes).map_err(error)?;
+    let payload: Vec<u8> = pixels.chunks_exact(4)
+        .map(|pixels| pixels.iter().fold(0, |byte, pixel| (byte << 2) | (pixel & 3)))
+        .collect();
+    let length = payload.get(..4).ok_or_else(|| error("no data in the image"))?;
+    let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+    let text = payload.get(4..4 + length).ok_or_else(|| error("data is truncated"))?;
+    let text = std::str::from_utf8(text).map_err(|_| error("data is not text"))?;
+
+    let json: Value = serde_json::from_str(text).map_err(|e| error(&format!("invalid JSON: {e}")))?;
+    let source = json.get("program").and_then(Value::as_str).ok_or_else(|| error("no program"))?;
+    let settings = json.get("options").map(rom_settings).unwrap_or_default();
+
+    Ok(Cartridge { source: source.to_string(), settings })
+}
+
+const QUIRK_FLAGS: [QuirkFlag; 6] = [
+    ("shiftQuirks", |quirks| &mut quirks.shift_uses_vy, true),
+    ("loadStoreQuirks", |quirks| &mut quirks.load_store_increments_i, true),
+    ("jumpQuirks", |quirks| &mut quirks.jump_uses_vx, false),
+    ("logicQuirks", |quirks| &mut quirks.logic_resets_vf, false),
+    ("clipQuirks", |quirks| &mut quirks.clip_sprites, false),
+    ("vBlankQuirks", |quirks| &mut quirks.display_wait, false)
+];
+
+fn rom_settings(options: &Value) -> RomSettings {
+    let mut settings = RomSettings::default();
+
+    // Octo picks the platform through the largest program it accepts
+    let (platform, mut quirks) = match options.get("maxSize").and_then(Value::as_u64) {
+        Some(size) if size > 3584 => (Platform::XoChip, Quirks::xo_chip()),
+        Some(3583 | 3584) => (Platform::SuperChip, Quirks::super_chip()),
+        _ => (Platform::Chip8, Quirks::cosmac_vip())
+    };
+    apply_quirks(&mut quirks, options, &QUIRK_FLAGS);
+    settings.platform = Some(platform);
+    settings.quirks = Some(quirks);
+    settings.cpu_hertz = options.get("tickrate").and_then(tickrate_hertz);
+
+    let colors = ["backgroundColor", "fillColor", "fillColor2", "blendColor"];
+    if colors.iter().any(|name| options.get(name).is_some()) {
+        let mut palette = Config::default().palette;
+        for (color, name) in palette.iter_mut().zip(colors) {
+            if let Some(rgb) = options.get(name).and_then(Value::as_str).and_then(parse_color) { *color = rgb; }
+        }
+        settings.palette = Some(palette);
+    }
+
+    settings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_octo_options() {
+        let options: Value = serde_json::from_str(r##"{"maxSize": 3584, "tickrate": 20, "shiftQuirks": true, "clipQuirks": false, "fillColor": "#FF0000"}"##).unwrap();
+        let settings = rom_settings(&options);
+        let quirks = settings.quirks.unwrap();
+        assert_eq!(settings.platform, Some(Platform::SuperChip));
+        assert_eq!(settings.cpu_hertz, Some(1200));
+        assert!(!quirks.shift_uses_vy);
+        assert!(!quirks.clip_sprites);
+        assert_eq!(settings.palette.unwrap()[1], 0xFF0000);
+    }
+
+    #[test]
+    fn ignores_impossible_tickrates() {
+        for tickrate in ["0", "18446744073709551615", "\"fast\""] {
+            let options: Value = serde_json::from_str(&format!(r#"{{"tickrate": {tickrate}}}"#)).unwrap();
+            assert_eq!(rom_settings(&options).cpu_hertz, None, "{tickrate}");
+        }
+    }
+
+    // A cartridge image holding `json`: the length-prefixed text two bits per pixel, written
+    // with a clear code every two pixels so the LZW codes stay three bits wide
+    fn cartridge_gif(json: &str, declared_length: usize) -> Vec<u8> {
+        let mut payload = (declared_length as u32).to_be_bytes().to_vec();
+        payload.extend_from_slice(json.as_bytes());
+        let mut pixels: Vec<u8> = payload.iter()
+            .flat_map(|byte| [6, 4, 2, 0].map(|shift| (byte >> shift) & 3))
+            .collect();
+        let width = 64;
+        pixels.resize(pixels.len().div_ceil(width) * width, 0);
+
+        let mut codes = Vec::new();
+        for pair in pixels.chunks(2) {
+            codes.push(4);
+            codes.extend(pair);
+        }
+        codes.push(5);
+        let mut data = vec![0u8; (codes.len() * 3).div_ceil(8)];
+        for (i, code) in codes.iter().enumerate() {
+            for bit in 0..3 {
+                if code & (1 << bit) != 0 { data[(i * 3 + bit) / 8] |= 1 << ((i * 3 + bit) % 8); }
+            }
+        }
+
+        let height = pixels.len() / width;
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend([width as u8, 0, height as u8, (height >> 8) as u8, 0x81, 0, 0]);
+        gif.extend([0; 12]);
+        gif.extend([0x2C, 0, 0, 0, 0, width as u8, 0, height as u8, (height >> 8) as u8, 0, 2]);
+        for block in data.chunks(255) {
+            gif.push(block.len() as u8);
+            gif.extend_from_slice(block);
+        }
+        gif.extend([0, 0x3B]);
+        gif
+    }
+
+    fn reason(gif: &[u8]) -> String {
+        match decode(gif) {
+            Err(Chip8Error::BadCartridge { reason }) => reason,
+            _ => panic!("expected a bad cartridge")
+        }
+    }
+
+    const JSON: &str = r##"{"program": ": main v0 := 7 exit", "options": {"maxSize": 3584, "tickrate": 20, "fillColor": "#FF0000"}}"##;
+
+    #[test]
+    fn decodes_a_cartridge() {
+        let gif = cartridge_gif(JSON, JSON.len());
+        assert!(is_cartridge(&gif));
+        let cartridge = decode(&gif).unwrap();
+        assert_eq!(cartridge.source, ": main v0 := 7 exit");
+        assert_eq!(cartridge.settings.platform, Some(Platform::SuperChip));
+        assert_eq!(cartridge.settings.cpu_hertz, Some(1200));
+        assert_eq!(cartridge.settings.palette.unwrap()[1], 0xFF0000);
+
+        // Anything after the declared length is padding
+        assert_eq!(decode(&cartridge_gif(&format!("{JSON}garbage"), JSON.len())).unwrap().source, cartridge.source);
+    }
+
+    #[test]
+    fn loads_a_cartridge_as_a_rom() {
+        let path = std::env::temp_dir().join(format!("chip-8-cartridge-{}.gif", std::process::id()));
+        std::fs::write(&path, cartridge_gif(JSON, JSON.len())).unwrap();
+        let rom = crate::rom::Rom::read_rom(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let rom = rom.unwrap();
+        assert_eq!(rom.program, [0x60, 0x07, 0x00, 0xFD]);
+        assert_eq!(rom.settings.platform, Some(Platform::SuperChip));
+        assert_eq!(rom.origin, Some(0x200));
+    }
+
+    #[test]
+    fn rejects_broken_cartridges() {
+        assert_eq!(reason(&cartridge_gif(JSON, JSON.len() + 100)), "data is truncated");
+        assert_eq!(reason(&cartridge_gif(r#"{"options": {}}"#, 15)), "no program");
+        assert!(reason(&cartridge_gif("{", 1)).starts_with("invalid JSON"));
+        assert_eq!(reason(&[0xFF, 0xFE]), "not a GIF image");
+    }
+}
@@ -1,8 +1,15 @@
 use crate::stack::StackPointer;
 use crate::timer::Timer;
-use crate::config::Config;
+use crate::config::{Config, Platform, Quirks};
+use crate::error::Chip8Error;
+use crate::framebuffer::Framebuffer;
+use crate::keypad::Keypad;
+use crate::savestate::{platform_from_u8, quirk_bits, quirks_from_bits, Snapshot, StateReader, StateWriter, MAGIC, VERSION};
 use super::rom::Rom;
-use rand::prelude::*;
+use crate::rng::Rng;
+use crate::symbols::SymbolTable;
+use crate::trace::Tracer;
+use crate::instruction::{self, ArithmeticLogic, Audio, Display, IRegister, Instruction, Register, Skip, Subroutine, TimerInstruction};
 
 const SPRITES: [[u8;5]; 16] = [
     [0xF0, 0x90, 0x90, 0x90, 0xF0], // 0
@@ -23,65 +30,39 @@ const SPRITES: [[u8;5]; 16] = [
     [0xF0, 0x80, 0xF0, 0x80, 0x80], // F
 ];
 
-enum ArithmeticLogic {
-    BitwiseOr,
-    BitwiseAnd,
-    BitwiseXor,
-    AddsWithCarry,
-    SubtractWithBorrow,
-    SubtractYWithBorrow,
-    ShiftLeft,
-    ShiftRight,
-}
-
-enum Display{
-    Clear,
-    DisplayBytes(usize, usize, u8)
-}
-
-enum Subroutine {
-    Return,
-    Call(usize)
-}
-
-enum Skip {
-    SkipIfEqual,
-    SkipIfNotEqual,
-    SkipIfKeyPressed,
-    SkipIfKeyNotPressed
-}
-
-enum Register {
-    Set,
-    Add,
-    SetToDelayTimer,
-    StoreInMemory,
-    ReadFromMemory
-}
+const BIG_SPRITES: [[u8;10]; 16] = [
+    [0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C], // 0
+    [0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C], // 1
+    [0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF], // 2
+    [0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C], // 3
+    [0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06], // 4
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C], // 5
+    [0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C], // 6
+    [0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60], // 7
+    [0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C], // 8
+    [0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C], // 9
+    [0x3C, 0x7E, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3], // A
+    [0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC], // B
+    [0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C], // C
+    [0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC], // D
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xFF, 0xFF], // E
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xC0, 0xC0], // F
+];
 
-enum TimerInstruction{
-    SetDelay,
-    SetSound
-}
+const BIG_SPRITES_ADDR: usize = 0x50;
 
-enum IRegister {
-    Set(u16),
-    AddRegister(usize),
-    SetToLocationSprite(usize)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Access {
+    Read,
+    Write
 }
 
-enum Instruction {
-    Display(Display),
-    Subroutine(Subroutine),
-    Skip(Skip, usize, u8),
-    ArithmeticLogic(ArithmeticLogic, usize, u8),
-    Jump(usize),
-    Register(Register, usize, u8),
-    IRegister(IRegister),
-    Timer(TimerInstruction, usize),
-    RandomByte(usize, u8),
-    StoreBCD(usize),
-    WaitKeyPress(usize)
+// A data access made by an instruction: `length` bytes from `addr`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MemoryAccess {
+    pub access: Access,
+    pub addr: usize,
+    pub length: usize
 }
 
 pub struct Chip8 {
@@ -89,16 +70,32 @@ pub struct Chip8 {
     i_register: u16,
     delay_timer: Timer,
     sound_timer: Timer,
-    memory: [u8; 4096],
+    memory: Vec<u8>,
     pc: usize,
     sp: StackPointer,
-    pub display: [u64; 32],
+    rpl_flags: [u8; 16],
+    platform: Platform,
+    quirks: Quirks,
+    cpu_hertz: u32,
+    frame_acc: u32,
+    vblank: bool,
+    exited: bool,
+    waiting_key: Option<u8>,
+    audio_pattern: Option<[u8; 16]>,
+    pitch: u8,
+    display: Framebuffer,
+    rom_hash: [u8; 20],
+    seed: u64,
+    rng: Rng,
+    accesses: Vec<MemoryAccess>,
+    symbols: SymbolTable,
+    tracer: Option<Tracer>,
     pub draw_flag: bool
 }
 
 impl Chip8 {
-    pub fn new(rom: Rom, config: &Config) -> Chip8{
-        let mut memory: [u8; 4096] = [0; 4096];
+    pub fn new(rom: Rom, config: &Config) -> Result<Chip8, Chip8Error>{
+        let mut memory = vec![0; config.platform.memory_size()];
         
         let mut i = 0;
         for sprite in SPRITES {
@@ -107,12 +104,18 @@ impl Chip8 {
                 i += 1;
             }
         }
-        let offset = config.rom_offset as usize;
-        for (i, addr) in (offset..(rom.length + offset)).enumerate(){
-            memory[addr] = rom.program[i];
+        for (i, byte) in BIG_SPRITES.iter().flatten().enumerate() {
+            memory[BIG_SPRITES_ADDR + i] = *byte;
+        }
+        let offset = rom.load_address(config) as usize;
+        let length = rom.program.len();
+        if offset + length > memory.len() {
+            return Err(Chip8Error::RomTooLarge { size: length, max: memory.len().saturating_sub(offset) });
         }
+        memory[offset..offset + length].copy_from_slice(&rom.program);
+        let seed = config.seed.unwrap_or_else(Rng::random_seed);
 
-        Chip8 {
+        Ok(Chip8 {
             registers: [0; 16],
             i_register: 0,
             delay_timer: Timer::new(config.delay_timer_hertz),
@@ -120,139 +123,298 @@ impl Chip8 {
             memory,
             pc: offset,
             sp: StackPointer::new(),
-            display: [0; 32],
+            rpl_flags: [0; 16],
+            platform: config.platform,
+            quirks: config.quirks,
+            cpu_hertz: config.cpu_hertz.max(1),
+            frame_acc: 0,
+            vblank: false,
+            exited: false,
+            waiting_key: None,
+            audio_pattern: None,
+            pitch: 64,
+            display: Framebuffer::new(),
+            rom_hash: rom.digest(),
+            seed,
+            rng: Rng::new(seed),
+            accesses: Vec::new(),
+            symbols: rom.symbols,
+            tracer: None,
             draw_flag: false
+        })
+    }
+
+    pub fn registers(&self) -> &[u8; 16] { &self.registers }
+
+    pub fn i_register(&self) -> u16 { self.i_register }
+
+    pub fn pc(&self) -> usize { self.pc }
+
+    pub fn stack(&self) -> &[usize] { self.sp.as_slice() }
+
+    pub fn memory(&self) -> &[u8] { &self.memory }
+
+    pub fn framebuffer(&self) -> &Framebuffer { &self.display }
+
+    pub fn delay_timer(&self) -> u8 { self.delay_timer.get() }
+
+    pub fn sound_timer(&self) -> u8 { self.sound_timer.get() }
+
+    pub fn rpl_flags(&self) -> &[u8; 16] { &self.rpl_flags }
+
+    pub fn platform(&self) -> Platform { self.platform }
+
+    pub fn quirks(&self) -> Quirks { self.quirks }
+
+    pub fn has_exited(&self) -> bool { self.exited }
+
+    pub fn is_beeping(&self) -> bool { self.sound_timer.get() > 0 }
+
+    pub fn audio_pattern(&self) -> Option<&[u8; 16]> { self.audio_pattern.as_ref() }
+
+    pub fn pitch(&self) -> u8 { self.pitch }
+
+    // SHA-1 of the ROM the machine was started with
+    pub fn rom_hash(&self) -> &[u8; 20] { &self.rom_hash }
+
+    pub fn seed(&self) -> u64 { self.seed }
+
+    // Labels of the program, when it came with any
+    pub fn symbols(&self) -> &SymbolTable { &self.symbols }
+
+    // Traces every instruction run from now on
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) { self.tracer = tracer; }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> { self.tracer.take() }
+
+    // Memory read and written by the last cycle
+    pub fn memory_accesses(&self) -> &[MemoryAccess] { &self.accesses }
+
+    // True after the cycle that crossed a 60 Hz frame boundary
+    pub fn is_vblank(&self) -> bool { self.vblank }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        writer.bytes(&MAGIC);
+        writer.u16(VERSION);
+        writer.bytes(&self.rom_hash);
+
+        writer.u8(self.platform as u8);
+        writer.u8(quirk_bits(&self.quirks));
+        writer.u32(self.cpu_hertz);
+        writer.bytes(&self.registers);
+        writer.u16(self.i_register);
+        self.delay_timer.save(&mut writer);
+        self.sound_timer.save(&mut writer);
+        writer.u32(self.memory.len() as u32);
+        writer.bytes(&self.memory);
+        writer.u32(self.pc as u32);
+        self.sp.save(&mut writer);
+        writer.bytes(&self.rpl_flags);
+        writer.u32(self.frame_acc);
+        writer.bool(self.vblank);
+        writer.bool(self.exited);
+        writer.bool(self.waiting_key.is_some());
+        writer.u8(self.waiting_key.unwrap_or(0));
+        writer.bool(self.audio_pattern.is_some());
+        writer.bytes(&self.audio_pattern.unwrap_or([0; 16]));
+        writer.u8(self.pitch);
+        self.display.save(&mut writer);
+        writer.bytes(&self.seed.to_le_bytes());
+        self.rng.save(&mut writer);
+
+        writer.into_bytes()
+    }
+
+    // Replaces the whole machine with a saved one; on error the machine is left untouched
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), Chip8Error> {
+        let mut reader = StateReader::new(state);
+        if reader.array::<4>().ok() != Some(MAGIC) { return Err(Chip8Error::BadSaveState { reason: "not a save state" }) }
+        let version = reader.u16()?;
+        if version != VERSION { return Err(Chip8Error::UnsupportedSaveState { version }) }
+        if reader.array::<20>()? != self.rom_hash { return Err(Chip8Error::SaveStateRomMismatch) }
+
+        let platform = platform_from_u8(reader.u8()?)?;
+        let quirks = quirks_from_bits(reader.u8()?);
+        let cpu_hertz = reader.u32()?.max(1);
+        let registers = reader.array()?;
+        let i_register = reader.u16()?;
+        let mut delay_timer = Timer::new(0);
+        delay_timer.load(&mut reader)?;
+        let mut sound_timer = Timer::new(0);
+        sound_timer.load(&mut reader)?;
+        let memory_size = reader.u32()? as usize;
+        if memory_size != platform.memory_size() { return Err(Chip8Error::BadSaveState { reason: "memory size does not match the platform" }) }
+        let memory = reader.bytes(memory_size)?.to_vec();
+        let pc = reader.u32()? as usize;
+        let mut sp = StackPointer::new();
+        sp.load(&mut reader)?;
+        let rpl_flags = reader.array()?;
+        let frame_acc = reader.u32()?;
+        let vblank = reader.bool()?;
+        let exited = reader.bool()?;
+        let waiting_key = (reader.bool()?, reader.u8()?);
+        let audio_pattern = (reader.bool()?, reader.array()?);
+        let pitch = reader.u8()?;
+        let mut display = Framebuffer::new();
+        display.load(&mut reader)?;
+        let seed = u64::from_le_bytes(reader.array()?);
+        let mut rng = Rng::new(seed);
+        rng.load(&mut reader)?;
+        reader.finish()?;
+
+        // Anything the CPU could not have produced would overflow or index out of range later
+        if frame_acc >= cpu_hertz { return Err(Chip8Error::BadSaveState { reason: "invalid frame counter" }) }
+        if !delay_timer.is_valid(cpu_hertz) || !sound_timer.is_valid(cpu_hertz) {
+            return Err(Chip8Error::BadSaveState { reason: "invalid timer state" })
+        }
+        if waiting_key.0 && waiting_key.1 > 0xF { return Err(Chip8Error::BadSaveState { reason: "invalid key" }) }
+
+        *self = Chip8 {
+            registers,
+            i_register,
+            delay_timer,
+            sound_timer,
+            memory,
+            pc,
+            sp,
+            rpl_flags,
+            platform,
+            quirks,
+            cpu_hertz,
+            frame_acc,
+            vblank,
+            exited,
+            waiting_key: Some(waiting_key.1).filter(|_| waiting_key.0),
+            audio_pattern: Some(audio_pattern.1).filter(|_| audio_pattern.0),
+            pitch,
+            display,
+            rom_hash: self.rom_hash,
+            seed,
+            rng,
+            accesses: Vec::new(),
+            symbols: std::mem::take(&mut self.symbols),
+            tracer: self.tracer.take(),
+            draw_flag: true
+        };
+        Ok(())
+    }
+
+    // Executes a single instruction
+    pub fn step(&mut self, keypad: Keypad) -> Result<(), Chip8Error> { self.run_cycle(keypad) }
+
+    // Executes instructions up to and including the next 60 Hz frame boundary
+    pub fn run_frame(&mut self, keypad: Keypad) -> Result<(), Chip8Error> {
+        loop {
+            self.run_cycle(keypad)?;
+            if self.vblank || self.exited { return Ok(()) }
         }
     }
 
-    pub fn run_cycle(&mut self, key_pressed: Option<u8>) {
-        self.delay_timer.check();
-        let instruction = self.fetch();
-        let instruction_type = self.decode(instruction);
-        self.execute(instruction_type, key_pressed)
+    pub fn run_cycle(&mut self, keypad: Keypad) -> Result<(), Chip8Error> {
+        if self.exited { return Ok(()) }
+        self.accesses.clear();
+
+        self.delay_timer.check(self.cpu_hertz);
+        self.sound_timer.check(self.cpu_hertz);
+
+        // 60 Hz frame boundary in emulated time, used by the display wait quirk
+        self.frame_acc += 60;
+        self.vblank = self.frame_acc >= self.cpu_hertz;
+        if self.vblank { self.frame_acc %= self.cpu_hertz; }
+
+        let Some(mut tracer) = self.tracer.take() else { return self.fetch_and_execute(keypad) };
+        let traced = tracer.record(self);
+        let result = traced.and_then(|_| self.fetch_and_execute(keypad));
+        // The emulation error is the one worth reporting, even if the trace could not be written
+        if result.is_err() { let _ = tracer.dump(); }
+        self.tracer = Some(tracer);
+        result
+    }
+
+    fn fetch_and_execute(&mut self, keypad: Keypad) -> Result<(), Chip8Error> {
+        let pc = self.pc;
+        let instruction = self.fetch()?;
+        let instruction_type = instruction::decode(instruction, self.platform)
+            .ok_or(Chip8Error::UnknownOpcode { pc, opcode: instruction })?;
+        self.execute(instruction_type, pc, keypad)
     }
 
-    fn fetch(&mut self) -> u16{
+    fn fetch(&mut self) -> Result<u16, Chip8Error>{
+        if self.pc + 1 >= self.memory.len() { return Err(Chip8Error::PcOutOfBounds { pc: self.pc }) }
+
         let instruction = ((self.memory[self.pc] as u16) << 8) + self.memory[self.pc + 1] as u16;
         self.pc += 2;
     
-        instruction
+        Ok(instruction)
     }
 
-    fn decode(&self, instruction: u16) -> Instruction {
-        let i = (0xF000 & instruction) >> 12;
-        let x = ((0x0F00 & instruction) >> 8) as usize;
-        let y = ((0x00F0 & instruction) >> 4) as usize;
-        let n = (0x000F & instruction) as u8;
-        let nn = (0x00FF & instruction) as u8;
-        let nnn = (0x0FFF & instruction) as u16;
-    
-        match i {
-            0x0 => {
-                match nn {
-                    0xE0 => Instruction::Display(Display::Clear),
-                    0xEE => Instruction::Subroutine(Subroutine::Return),
-                    _ => todo!()
-                }
-            },
-            0x1 => Instruction::Jump(nnn as usize),
-            0x2 => Instruction::Subroutine(Subroutine::Call(nnn as usize)),
-            0x3 => Instruction::Skip(Skip::SkipIfEqual, x, nn),
-            0x4 => Instruction::Skip(Skip::SkipIfNotEqual, x, nn),
-            0x5 => {
-                let nn = self.registers[y];
-                Instruction::Skip(Skip::SkipIfEqual, x, nn)
-            },
-            0x6 => Instruction::Register(Register::Set, x, nn),
-            0x7 => Instruction::Register(Register::Add, x, nn),
-            0x8 => {
-                let nn = self.registers[y];
-                match n {
-                    0x0 => Instruction::Register(Register::Set, x, nn),
-                    0x1 => Instruction::ArithmeticLogic(ArithmeticLogic::BitwiseOr, x, nn),
-                    0x2 => Instruction::ArithmeticLogic(ArithmeticLogic::BitwiseAnd, x, nn),
-                    0x3 => Instruction::ArithmeticLogic(ArithmeticLogic::BitwiseXor, x, nn),
-                    0x4 => Instruction::ArithmeticLogic(ArithmeticLogic::AddsWithCarry, x, nn),
-                    0x5 => Instruction::ArithmeticLogic(ArithmeticLogic::SubtractWithBorrow, x, nn),
-                    0x6 => Instruction::ArithmeticLogic(ArithmeticLogic::ShiftRight, x, nn),
-                    0x7 => Instruction::ArithmeticLogic(ArithmeticLogic::SubtractYWithBorrow, x, nn),
-                    0xE => Instruction::ArithmeticLogic(ArithmeticLogic::ShiftLeft, x, nn),
-                    _ => todo!()
-                }
-            },
-            0x9 => {
-                let nn = self.registers[y];
-                Instruction::Skip(Skip::SkipIfNotEqual, x, nn)
-            },
-            0xA => Instruction::IRegister(IRegister::Set(nnn)),
-            0xB => {
-                let nnn = nnn + self.registers[0] as u16;
-                Instruction::Jump(nnn as usize)
-            },
-            0xC => Instruction::RandomByte(x, nn),
-            0xD => Instruction::Display(Display::DisplayBytes(x, y, n)),
-            0xE => {
-                match nn {
-                    0x9E => Instruction::Skip(Skip::SkipIfKeyPressed, x, 0),
-                    0xA1 => Instruction::Skip(Skip::SkipIfKeyNotPressed, x, 0),
-                    _ => todo!()
-                }
-            },
-            0xF => {
-                match nn {
-                    0x07 => Instruction::Register(Register::SetToDelayTimer, x, 0),
-                    0x0A => Instruction::WaitKeyPress(x),
-                    0x15 => Instruction::Timer(TimerInstruction::SetDelay, x),
-                    0x18 => Instruction::Timer(TimerInstruction::SetSound, x),
-                    0x1E => Instruction::IRegister(IRegister::AddRegister(x)),
-                    0x29 => Instruction::IRegister(IRegister::SetToLocationSprite(x)),
-                    0x33 => Instruction::StoreBCD(x),
-                    0x55 => Instruction::Register(Register::StoreInMemory, x, 0),
-                    0x65 => Instruction::Register(Register::ReadFromMemory, x, 0),
-                    _ => todo!()
-                }
-            },
-            _ => todo!()
+    // Bounds-checks an access and records it for watchpoints
+    fn check_memory(&mut self, pc: usize, addr: usize, length: usize, access: Access) -> Result<(), Chip8Error> {
+        if addr + length > self.memory.len() {
+            return Err(Chip8Error::MemoryOutOfBounds { pc, addr: addr.max(self.memory.len()) });
         }
+        self.accesses.push(MemoryAccess { access, addr, length });
+        Ok(())
     }
 
-    fn execute(&mut self, instruction: Instruction, key_pressed: Option<u8>){
+    fn execute(&mut self, instruction: Instruction, pc: usize, keypad: Keypad) -> Result<(), Chip8Error>{
         match instruction {
             Instruction::Jump(addr) => self.pc = addr,
+            Instruction::JumpOffset(addr, x) => {
+                let offset = if self.quirks.jump_uses_vx { self.registers[x] } else { self.registers[0] };
+                self.pc = addr + offset as usize;
+            },
             Instruction::Subroutine(typ) => {
                 match typ {
-                    Subroutine::Return => self.pc = self.sp.pop(),
+                    Subroutine::Return => {
+                        self.pc = self.sp.pop().ok_or(Chip8Error::StackUnderflow { pc })?
+                    },
                     Subroutine::Call(addr) => {
-                        self.sp.push(self.pc);
+                        self.sp.push(self.pc).ok_or(Chip8Error::StackOverflow { pc })?;
                         self.pc = addr
                     }
                 }
             },
-            Instruction::ArithmeticLogic(typ, addr, value) => {
+            Instruction::ArithmeticLogic(typ, addr, y) => {
+                let value = self.registers[y];
                 match typ {
-                    ArithmeticLogic::BitwiseOr => self.registers[addr] |= value,
-                    ArithmeticLogic::BitwiseAnd => self.registers[addr] &= value,
-                    ArithmeticLogic::BitwiseXor => self.registers[addr] ^= value,
+                    ArithmeticLogic::BitwiseOr => {
+                        self.registers[addr] |= value;
+                        self.logic_reset_vf();
+                    },
+                    ArithmeticLogic::BitwiseAnd => {
+                        self.registers[addr] &= value;
+                        self.logic_reset_vf();
+                    },
+                    ArithmeticLogic::BitwiseXor => {
+                        self.registers[addr] ^= value;
+                        self.logic_reset_vf();
+                    },
                     ArithmeticLogic::AddsWithCarry => {
                         let (value, carry) = self.registers[addr].overflowing_add(value);
-                        self.registers[0xF] = if carry {1} else {0};
                         self.registers[addr] = value;
+                        self.registers[0xF] = if carry {1} else {0};
                     },
                     ArithmeticLogic::ShiftRight => {
-                        self.registers[0xF] = 0x01 & self.registers[addr];
-                        self.registers[addr] >>= 1;
+                        let value = if self.quirks.shift_uses_vy { value } else { self.registers[addr] };
+                        self.registers[addr] = value >> 1;
+                        self.registers[0xF] = 0x01 & value;
                     },
                     ArithmeticLogic::ShiftLeft => {
-                        self.registers[0xF] = (0x80 & self.registers[addr]) >> 7;
-                        self.registers[addr] <<= 1;
+                        let value = if self.quirks.shift_uses_vy { value } else { self.registers[addr] };
+                        self.registers[addr] = value << 1;
+                        self.registers[0xF] = (0x80 & value) >> 7;
                     },
                     ArithmeticLogic::SubtractWithBorrow => {
-                        self.registers[0xF] = if self.registers[addr] >= value {1} else {0};
+                        let flag = if self.registers[addr] >= value {1} else {0};
                         self.registers[addr] = self.registers[addr].overflowing_sub(value).0;
+                        self.registers[0xF] = flag;
                     },
                     ArithmeticLogic::SubtractYWithBorrow => {
-                        self.registers[0xF] = if self.registers[addr] <= value {1} else {0};
+                        let flag = if self.registers[addr] <= value {1} else {0};
                         self.registers[addr] = value.overflowing_sub(self.registers[addr]).0;
+                        self.registers[0xF] = flag;
                     }
                 }
             },
@@ -260,45 +422,62 @@ impl Chip8 {
                 match typ {
                     Register::Add => self.registers[addr] = self.registers[addr].overflowing_add(value).0,
                     Register::Set => self.registers[addr] = value,
+                    Register::Copy => self.registers[addr] = self.registers[value as usize],
                     Register::SetToDelayTimer => self.registers[addr] = self.delay_timer.get(),
                     Register::ReadFromMemory => {
-                        let mut j = self.i_register as usize;
-                        for i in 0..=addr{
-                            self.registers[i] = self.memory[j];
-                            j += 1;
-                        }
+                        let index = self.i_register as usize;
+                        self.check_memory(pc, index, addr + 1, Access::Read)?;
+                        self.registers[..=addr].copy_from_slice(&self.memory[index..=index + addr]);
+                        self.load_store_increment_i(addr);
                     },
                     Register::StoreInMemory => {
-                        let mut j = self.i_register as usize;
-                        for i in 0..=addr{
-                            self.memory[j] = self.registers[i];
-                            j += 1;
+                        let index = self.i_register as usize;
+                        self.check_memory(pc, index, addr + 1, Access::Write)?;
+                        self.memory[index..=index + addr].copy_from_slice(&self.registers[..=addr]);
+                        self.load_store_increment_i(addr);
+                    },
+                    Register::StoreFlags => self.rpl_flags[..=addr].copy_from_slice(&self.registers[..=addr]),
+                    Register::ReadFlags => self.registers[..=addr].copy_from_slice(&self.rpl_flags[..=addr]),
+                    Register::StoreRange => {
+                        let index = self.i_register as usize;
+                        let range = register_range(addr, value as usize);
+                        self.check_memory(pc, index, range.len(), Access::Write)?;
+                        for (i, register) in range.into_iter().enumerate() {
+                            self.memory[index + i] = self.registers[register];
+                        }
+                    },
+                    Register::ReadRange => {
+                        let index = self.i_register as usize;
+                        let range = register_range(addr, value as usize);
+                        self.check_memory(pc, index, range.len(), Access::Read)?;
+                        for (i, register) in range.into_iter().enumerate() {
+                            self.registers[register] = self.memory[index + i];
                         }
                     },
                 }
             },
             Instruction::Skip(typ, addr, value) => {
                 let x = self.registers[addr];
-                match typ {
-                    Skip::SkipIfEqual => self.pc += if x == value {2} else {0},
-                    Skip::SkipIfNotEqual => self.pc += if x != value {2} else {0},
-                    Skip::SkipIfKeyPressed => {
-                        if let Some(key_pressed) = key_pressed {
-                            self.pc += if x == key_pressed {2} else {0};
-                        }
-                    },
-                    Skip::SkipIfKeyNotPressed => {
-                        if let Some(key_pressed) = key_pressed {
-                            self.pc += if x != key_pressed {2} else {0};
-                        }
-                    }
-                }
+                let skip = match typ {
+                    Skip::Equal => x == value,
+                    Skip::NotEqual => x != value,
+                    Skip::EqualRegister => x == self.registers[value as usize],
+                    Skip::NotEqualRegister => x != self.registers[value as usize],
+                    Skip::KeyPressed => keypad.is_pressed(x),
+                    Skip::KeyNotPressed => !keypad.is_pressed(x)
+                };
+                if skip { self.skip_instruction(); }
             },
             Instruction::IRegister(typ) => {
                 match typ {
                     IRegister::Set(nnn) => self.i_register = nnn,
-                    IRegister::AddRegister(addr) => self.i_register += self.registers[addr] as u16,
-                    IRegister::SetToLocationSprite(addr) => self.i_register = self.registers[addr] as u16 * 5,
+                    IRegister::AddRegister(addr) => self.i_register = self.i_register.wrapping_add(self.registers[addr] as u16),
+                    IRegister::SetToLocationSprite(addr) => self.i_register = (self.registers[addr] & 0xF) as u16 * 5,
+                    IRegister::SetToLocationBigSprite(addr) => {
+                        self.i_register = (BIG_SPRITES_ADDR + (self.registers[addr] & 0xF) as usize * 10) as u16
+                    },
+                    // The address is the second half of the instruction, not a data read
+                    IRegister::SetLong => self.i_register = self.fetch()?,
                 }
             },
             Instruction::Timer(typ, addr) => {
@@ -311,62 +490,626 @@ impl Chip8 {
             Instruction::StoreBCD(addr) => {
                 let decimal = self.registers[addr];
                 let index = self.i_register as usize;
+                self.check_memory(pc, index, 3, Access::Write)?;
                 self.memory[index] = decimal / 100;
                 self.memory[index + 1] = (decimal / 10) % 10;
                 self.memory[index + 2] = decimal % 10;
             },
             Instruction::RandomByte(addr, value) => {
-                let mut rng = thread_rng();
-                let number: u8 = rng.gen_range(0..=value);
-    
-                self.registers[addr] = number & value;
+                self.registers[addr] = self.rng.next_u8() & value;
             },
             Instruction::Display(typ) => {
-                self.draw_flag = true;
                 match typ {
-                    Display::Clear => self.display = [0; 32],
-                    Display::DisplayBytes(x, y, n) => {
-                        let mut sprites: [u8; 16] = [0;16];
-                        let x = self.registers[x];
-                        let mut y = self.registers[y] as usize;
-
-                        (self.i_register..self.i_register + n as u16).enumerate()
-                            .for_each(|(i, addr)| sprites[i] = self.memory[addr as usize]);
-    
-                        let shift: i32 = 64 - (x as i32 + 8);
-                        let mut flag = false;
-                
-                        for sprite in sprites {
-                            let ones_before = self.display[y].count_ones();
-                            let sprite = sprite as u64;
-                
-                            if shift >= 0 { self.display[y] ^= sprite << shift }
-                            else {
-                                let mut sprite_wrapped = sprite >> (-1*shift);
-                                sprite_wrapped += sprite << (64 + shift);
-                                self.display[y] ^= sprite_wrapped;
-                            }
-                
-                            let ones_after = self.display[y].count_ones();
-                            if !flag && ((ones_before + sprite.count_ones()) > ones_after) {
-                                flag = true;
-                                self.registers[0xF] = 1;
-                            }
-                
-                            y += 1;
-                            if y == 32 { y = 0; }
+                    Display::Clear => self.display.clear(),
+                    Display::ScrollDown(n) => self.display.scroll_down(n as usize),
+                    Display::ScrollUp(n) => self.display.scroll_up(n as usize),
+                    Display::ScrollLeft => self.display.scroll_left(4),
+                    Display::ScrollRight => self.display.scroll_right(4),
+                    Display::LowResolution => self.display.set_hires(false),
+                    Display::HighResolution => self.display.set_hires(true),
+                    Display::SelectPlanes(mask) => self.display.select_planes(mask),
+                    Display::DrawSprite(x, y, n) => {
+                        if self.quirks.display_wait && !self.vblank {
+                            self.pc -= 2;
+                            return Ok(());
                         }
+
+                        let x = self.registers[x] as usize;
+                        let y = self.registers[y] as usize;
+                        let mut index = self.i_register as usize;
+                        let mut collisions = 0;
+                        let mut height = 0;
+
+                        // With both XO-CHIP planes selected the sprite data for plane 2 follows the data for plane 1
+                        let planes = self.display.selected_plane_indices().collect::<Vec<usize>>();
+                        for plane in planes {
+                            let (sprite, width) = self.sprite_at(pc, index, n)?;
+                            index += if width == 16 { 32 } else { n as usize };
+                            height = sprite.len();
+                            collisions += self.display.draw_sprite(plane, x, y, &sprite, width, self.quirks.clip_sprites);
+                        }
+
+                        // SUPER-CHIP 1.1 in hires counts the rows that collided or were clipped off the bottom
+                        self.registers[0xF] = if self.platform == Platform::SuperChip && self.display.is_hires() {
+                            let screen_height = self.display.height();
+                            let bottom = y % screen_height + height;
+                            let clipped = if self.quirks.clip_sprites { bottom.saturating_sub(screen_height) } else { 0 };
+                            (collisions + clipped) as u8
+                        } else if collisions > 0 {1} else {0};
                     }
                 }
+                self.draw_flag = true;
             },
+            // Like the COSMAC VIP, FX0A only completes once the pressed key is released again.
+            // The instruction is re-executed every cycle until then, so the timers keep running.
             Instruction::WaitKeyPress(addr) => {
-                match key_pressed {
-                    Some(key_pressed) => {
-                        self.registers[addr] = key_pressed;
+                match self.waiting_key {
+                    Some(key) if !keypad.is_pressed(key) => {
+                        self.registers[addr] = key;
+                        self.waiting_key = None;
                     },
-                    None => self.pc -= 2,
+                    Some(_) => self.pc -= 2,
+                    None => {
+                        self.waiting_key = keypad.first_pressed();
+                        self.pc -= 2;
+                    }
                 }
-            }
+            },
+            Instruction::Audio(typ) => {
+                match typ {
+                    Audio::LoadPattern => {
+                        let index = self.i_register as usize;
+                        self.check_memory(pc, index, 16, Access::Read)?;
+                        let mut pattern = [0; 16];
+                        pattern.copy_from_slice(&self.memory[index..index + 16]);
+                        self.audio_pattern = Some(pattern);
+                    },
+                    Audio::SetPitch(addr) => self.pitch = self.registers[addr]
+                }
+            },
+            Instruction::Exit => self.exited = true
+        }
+
+        Ok(())
+    }
+    fn sprite_at(&mut self, pc: usize, index: usize, n: u8) -> Result<(Vec<u16>, usize), Chip8Error> {
+        if n == 0 && self.platform != Platform::Chip8 {
+            self.check_memory(pc, index, 32, Access::Read)?;
+            let sprite = self.memory[index..index + 32].chunks(2)
+                .map(|row| ((row[0] as u16) << 8) | row[1] as u16)
+                .collect();
+            Ok((sprite, 16))
+        } else {
+            self.check_memory(pc, index, n as usize, Access::Read)?;
+            let sprite = self.memory[index..index + n as usize].iter()
+                .map(|row| *row as u16)
+                .collect();
+            Ok((sprite, 8))
+        }
+    }
+
+    // XO-CHIP's F000 NNNN is four bytes long, so skipping over it has to skip both words
+    fn skip_instruction(&mut self) {
+        let long = self.platform == Platform::XoChip
+            && self.pc + 1 < self.memory.len()
+            && self.memory[self.pc] == 0xF0
+            && self.memory[self.pc + 1] == 0x00;
+        self.pc += if long {4} else {2};
+    }
+
+    fn logic_reset_vf(&mut self) {
+        if self.quirks.logic_resets_vf { self.registers[0xF] = 0; }
+    }
+
+    fn load_store_increment_i(&mut self, x: usize) {
+        if self.quirks.load_store_increments_i {
+            let step = if self.quirks.increment_i_by_x { x } else { x + 1 };
+            self.i_register = self.i_register.wrapping_add(step as u16);
+        }
+    }
+}
+
+// Registers X through Y in the order 5XY2/5XY3 transfer them, which is descending when X > Y
+fn register_range(x: usize, y: usize) -> Vec<usize> {
+    if x <= y { (x..=y).collect() } else { (y..=x).rev().collect() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Draws random sprites and calls a subroutine forever, with the delay timer running
+    const PROGRAM: [u8; 16] = [
+        0x60, 0x3C,     // 200: LD V0, 60
+        0xF0, 0x15,     // 202: LD DT, V0
+        0xC1, 0x3F,     // 204: RND V1, 0x3F
+        0xC2, 0x1F,     // 206: RND V2, 0x1F
+        0xD1, 0x25,     // 208: DRW V1, V2, 5
+        0x22, 0x0E,     // 20A: CALL 0x20E
+        0x12, 0x04,     // 20C: JP 0x204
+        0x00, 0xEE,     // 20E: RET
+    ];
+
+    // Offsets into the save state of a CHIP-8 machine with an empty stack
+    const DELAY_TIMER: usize = 50;
+    const SOUND_TIMER: usize = DELAY_TIMER + 9;
+    const FRAME_ACC: usize = SOUND_TIMER + 9 + 4 + 0x1000 + 4 + 1 + 16;
+    const WAITING_KEY: usize = FRAME_ACC + 6;
+
+    fn machine() -> Chip8 {
+        let config = Config { seed: Some(1), ..Config::default() };
+        Chip8::new(Rom::new(PROGRAM.to_vec()), &config).unwrap()
+    }
+
+    fn run(chip8: &mut Chip8, cycles: usize) {
+        for _ in 0..cycles { chip8.run_cycle(Keypad::new()).unwrap(); }
+    }
+
+    fn rejects(state: &[u8], reason: &str) {
+        let mut chip8 = machine();
+        run(&mut chip8, 10);
+        let before = chip8.save_state();
+        match chip8.load_state(state) {
+            Err(Chip8Error::BadSaveState { reason: actual }) => assert_eq!(actual, reason),
+            other => panic!("expected `{reason}`, got {other:?}")
         }
+        assert_eq!(chip8.save_state(), before);
+    }
+
+    #[test]
+    fn long_load_does_not_record_a_read() {
+        let config = Config { platform: Platform::XoChip, ..Config::default() };
+        let mut chip8 = Chip8::new(Rom::new(vec![0xF0, 0x00, 0x12, 0x34]), &config).unwrap();
+        run(&mut chip8, 1);
+        assert_eq!(chip8.i_register(), 0x1234);
+        assert_eq!(chip8.pc(), 0x204);
+        assert!(chip8.memory_accesses().is_empty());
+
+        // A long load in the last word of memory has its address past the end
+        chip8.pc = 0xFFFE;
+        chip8.memory[0xFFFE..].copy_from_slice(&[0xF0, 0x00]);
+        assert!(matches!(chip8.run_cycle(Keypad::new()), Err(Chip8Error::PcOutOfBounds { pc: 0x10000 })));
+    }
+
+    #[test]
+    fn save_state_round_trips() {
+        let mut chip8 = machine();
+        run(&mut chip8, 1000);
+        let state = chip8.save_state();
+        run(&mut chip8, 1000);
+
+        let mut restored = machine();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        run(&mut restored, 1000);
+        assert_eq!(restored.save_state(), chip8.save_state());
+    }
+
+    #[test]
+    fn load_state_rejects_other_files() {
+        let state = machine().save_state();
+        rejects(b"not a state", "not a save state");
+        rejects(&state[..state.len() - 1], "file is truncated");
+        rejects(&[state.as_slice(), &[0]].concat(), "unexpected data after the machine state");
+
+        for version in [VERSION - 1, VERSION + 1] {
+            let mut other_version = state.clone();
+            other_version[4..6].copy_from_slice(&version.to_le_bytes());
+            assert!(matches!(machine().load_state(&other_version), Err(Chip8Error::UnsupportedSaveState { version: v }) if v == version));
+        }
+
+        let mut other_rom = state.clone();
+        other_rom[6] ^= 1;
+        assert!(matches!(machine().load_state(&other_rom), Err(Chip8Error::SaveStateRomMismatch)));
+    }
+
+    #[test]
+    fn load_state_rejects_impossible_values() {
+        let state = machine().save_state();
+        let patched = |at: usize, bytes: &[u8]| {
+            let mut state = state.clone();
+            state[at..at + bytes.len()].copy_from_slice(bytes);
+            state
+        };
+
+        rejects(&patched(FRAME_ACC, &700u32.to_le_bytes()), "invalid frame counter");
+        rejects(&patched(FRAME_ACC, &u32::MAX.to_le_bytes()), "invalid frame counter");
+        rejects(&patched(DELAY_TIMER + 1, &256u32.to_le_bytes()), "invalid timer rate");
+        rejects(&patched(SOUND_TIMER + 5, &700u32.to_le_bytes()), "invalid timer state");
+        rejects(&patched(WAITING_KEY, &[1, 0x10]), "invalid key");
+
+        // A key that is only stored, not waited for, is ignored
+        machine().load_state(&patched(WAITING_KEY, &[0, 0x10])).unwrap();
+        machine().load_state(&patched(WAITING_KEY, &[1, 0xF])).unwrap();
+    }
+
+    fn load(platform: Platform, quirks: Quirks, program: &[u8]) -> Chip8 {
+        let config = Config { platform, quirks, seed: Some(1), ..Config::default() };
+        Chip8::new(Rom::new(program.to_vec()), &config).unwrap()
+    }
+
+    fn error(program: &[u8], cycles: usize) -> Chip8Error {
+        let mut chip8 = load(Platform::Chip8, Quirks::classic(), program);
+        for _ in 0..cycles - 1 { chip8.run_cycle(Keypad::new()).unwrap(); }
+        chip8.run_cycle(Keypad::new()).err().unwrap()
+    }
+
+    #[test]
+    fn reports_where_execution_failed() {
+        assert!(matches!(error(&[0x91, 0x21], 1), Chip8Error::UnknownOpcode { pc: 0x200, opcode: 0x9121 }));
+        assert!(matches!(error(&[0x00, 0xEE], 1), Chip8Error::StackUnderflow { pc: 0x200 }));
+        assert!(matches!(error(&[0x22, 0x00], 17), Chip8Error::StackOverflow { pc: 0x200 }));
+        assert!(matches!(error(&[0x1F, 0xFF], 2), Chip8Error::PcOutOfBounds { pc: 0xFFF }));
+        assert!(matches!(error(&[0xAF, 0xFF, 0xF1, 0x55], 2), Chip8Error::MemoryOutOfBounds { pc: 0x202, addr: 0x1000 }));
+    }
+
+    #[test]
+    fn font_address_only_uses_the_low_digit() {
+        let mut chip8 = load(Platform::Chip8, Quirks::classic(), &[0x60, 0x1A, 0xF0, 0x29]);
+        run(&mut chip8, 2);
+        assert_eq!(chip8.i_register(), 0xA * 5);
+    }
+
+    fn after(quirks: Quirks, program: &[u8], cycles: usize) -> Chip8 {
+        let mut chip8 = load(Platform::Chip8, quirks, program);
+        run(&mut chip8, cycles);
+        chip8
+    }
+
+    #[test]
+    fn shift_quirk_picks_the_source_register() {
+        let shift_uses_vy = Quirks { shift_uses_vy: true, ..Quirks::classic() };
+        // V0 = 5, V1 = 0x81, then 8016 or 801E
+        let right = [0x60, 0x05, 0x61, 0x81, 0x80, 0x16];
+        let left = [0x60, 0x05, 0x61, 0x81, 0x80, 0x1E];
+
+        assert_eq!(after(Quirks::classic(), &right, 3).registers()[0x0], 0x02);
+        assert_eq!(after(Quirks::classic(), &right, 3).registers()[0xF], 1);
+        assert_eq!(after(shift_uses_vy, &right, 3).registers()[0x0], 0x40);
+        assert_eq!(after(shift_uses_vy, &right, 3).registers()[0xF], 1);
+
+        assert_eq!(after(Quirks::classic(), &left, 3).registers()[0x0], 0x0A);
+        assert_eq!(after(Quirks::classic(), &left, 3).registers()[0xF], 0);
+        assert_eq!(after(shift_uses_vy, &left, 3).registers()[0x0], 0x02);
+        assert_eq!(after(shift_uses_vy, &left, 3).registers()[0xF], 1);
+    }
+
+    #[test]
+    fn load_store_quirk_advances_i() {
+        let increments = Quirks { load_store_increments_i: true, ..Quirks::classic() };
+        let by_x = Quirks { increment_i_by_x: true, ..increments };
+
+        for opcode in [0x55, 0x65] {
+            // LD I, 0x300; LD [I], V2 or LD V2, [I]
+            let program = [0xA3, 0x00, 0xF2, opcode];
+            assert_eq!(after(Quirks::classic(), &program, 2).i_register(), 0x300);
+            assert_eq!(after(increments, &program, 2).i_register(), 0x303);
+            assert_eq!(after(by_x, &program, 2).i_register(), 0x302);
+        }
+    }
+
+    #[test]
+    fn jump_quirk_picks_the_offset_register() {
+        // V0 = 0x10, V2 = 0x20, JP V0, 0x230
+        let program = [0x60, 0x10, 0x62, 0x20, 0xB2, 0x30];
+        assert_eq!(after(Quirks::classic(), &program, 3).pc(), 0x240);
+        assert_eq!(after(Quirks { jump_uses_vx: true, ..Quirks::classic() }, &program, 3).pc(), 0x250);
+    }
+
+    #[test]
+    fn logic_quirk_resets_vf() {
+        let resets = Quirks { logic_resets_vf: true, ..Quirks::classic() };
+        for opcode in [0x11, 0x12, 0x13] {
+            // VF = 5, V0 = 0x0F, V1 = 0xF0, then OR, AND or XOR
+            let program = [0x6F, 0x05, 0x60, 0x0F, 0x61, 0xF0, 0x80, opcode];
+            assert_eq!(after(Quirks::classic(), &program, 4).registers()[0xF], 5);
+            assert_eq!(after(resets, &program, 4).registers()[0xF], 0);
+        }
+    }
+
+    #[test]
+    fn clip_quirk_stops_sprites_at_the_edges() {
+        // Draws the font's 0 at (62, 30), so it crosses both the right and the bottom edge
+        let program = [0x60, 0x3E, 0x61, 0x1E, 0xA0, 0x00, 0xD0, 0x15];
+        let wrapped = after(Quirks::classic(), &program, 4);
+        let clipped = after(Quirks { clip_sprites: true, ..Quirks::classic() }, &program, 4);
+
+        for chip8 in [&wrapped, &clipped] {
+            assert_eq!(chip8.framebuffer().pixel(62, 30), 1);
+            assert_eq!(chip8.framebuffer().pixel(63, 31), 0);
+        }
+        assert_eq!(wrapped.framebuffer().pixel(0, 30), 1);
+        assert_eq!(wrapped.framebuffer().pixel(62, 0), 1);
+        assert_eq!(clipped.framebuffer().pixel(0, 30), 0);
+        assert_eq!(clipped.framebuffer().pixel(62, 0), 0);
+    }
+
+    #[test]
+    fn display_wait_quirk_draws_on_the_frame_boundary() {
+        let program = [0xD0, 0x15];
+        assert_eq!(after(Quirks::classic(), &program, 1).pc(), 0x202);
+
+        let mut chip8 = after(Quirks { display_wait: true, ..Quirks::classic() }, &program, 1);
+        assert_eq!(chip8.pc(), 0x200);
+        assert_eq!(chip8.framebuffer().pixel(0, 0), 0);
+
+        chip8.run_frame(Keypad::new()).unwrap();
+        assert!(chip8.is_vblank());
+        assert_eq!(chip8.pc(), 0x202);
+        assert_eq!(chip8.framebuffer().pixel(0, 0), 1);
+    }
+
+    fn super_chip(program: &[u8], cycles: usize) -> Chip8 {
+        let mut chip8 = load(Platform::SuperChip, Quirks::super_chip(), program);
+        run(&mut chip8, cycles);
+        chip8
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn switches_resolution() {
+        // HIGH, DRW V0, V0, 1 with I at the font's 0, LOW
+        let program = [0x00, 0xFF, 0xD0, 0x01, 0x00, 0xFE];
+        let chip8 = super_chip(&program, 2);
+        assert!(chip8.framebuffer().is_hires());
+        assert_eq!(chip8.framebuffer().width(), 128);
+        assert_eq!(chip8.framebuffer().pixel(0, 0), 1);
+
+        let chip8 = super_chip(&program, 3);
+        assert!(!chip8.framebuffer().is_hires());
+        assert_eq!(chip8.framebuffer().pixel(0, 0), 0);
+    }
+
+    #[test]
+    fn scroll_instructions_move_the_screen() {
+        // DRW V0, V0, 1 draws the top of the font's 0 at (0, 0), then SCD 2, SCR and SCL
+        let program = [0xD0, 0x01, 0x00, 0xC2, 0x00, 0xFB, 0x00, 0xFC];
+        assert_eq!(super_chip(&program, 2).framebuffer().pixel(0, 2), 1);
+        assert_eq!(super_chip(&program, 3).framebuffer().pixel(4, 2), 1);
+        assert_eq!(super_chip(&program, 3).framebuffer().pixel(0, 2), 0);
+        assert_eq!(super_chip(&program, 4).framebuffer().pixel(0, 2), 1);
+    }
+
+    #[test]
+    fn draws_big_sprites_and_counts_hires_collisions() {
+        let mut program = vec![
+            0x00, 0xFF,     // 200: HIGH
+            0xA2, 0x10,     // 202: LD I, 0x210
+            0xD0, 0x00,     // 204: DRW V0, V0, 0
+            0xD0, 0x00,     // 206: DRW V0, V0, 0
+            0x61, 0x38,     // 208: LD V1, 56
+            0xD0, 0x10,     // 20A: DRW V0, V1, 0
+            0x00, 0xFE,     // 20C: LOW
+            0xD0, 0x00,     // 20E: DRW V0, V0, 0
+        ];
+        program.extend([0xFF; 32]);
+
+        let chip8 = super_chip(&program, 3);
+        assert_eq!(chip8.registers()[0xF], 0);
+        assert_eq!(chip8.framebuffer().pixel(15, 15), 1);
+        assert_eq!(chip8.framebuffer().pixel(16, 15), 0);
+        assert_eq!(chip8.framebuffer().pixel(15, 16), 0);
+
+        // Every row collides, then half the sprite is clipped off the bottom
+        assert_eq!(super_chip(&program, 4).registers()[0xF], 16);
+        assert_eq!(super_chip(&program, 6).registers()[0xF], 8);
+
+        // Lores still draws 16x16 sprites but only reports whether anything collided
+        let mut chip8 = super_chip(&program, 8);
+        assert_eq!(chip8.registers()[0xF], 0);
+        chip8.pc = 0x20E;
+        run(&mut chip8, 1);
+        assert_eq!(chip8.registers()[0xF], 1);
+    }
+
+    #[test]
+    fn big_font_address_only_uses_the_low_digit() {
+        let chip8 = super_chip(&[0x60, 0x13, 0xF0, 0x30], 2);
+        assert_eq!(chip8.i_register() as usize, BIG_SPRITES_ADDR + 3 * 10);
+        assert_eq!(chip8.memory()[chip8.i_register() as usize], BIG_SPRITES[3][0]);
+    }
+
+    #[test]
+    fn rpl_flags_keep_registers() {
+        // V0 = 0x11, V1 = 0x22, V2 = 0x33, LD R, V1, clear V0 and V1, LD V2, R
+        let program = [0x60, 0x11, 0x61, 0x22, 0x62, 0x33, 0xF1, 0x75, 0x60, 0x00, 0x61, 0x00, 0xF2, 0x85];
+        let chip8 = super_chip(&program, 7);
+        assert_eq!(chip8.rpl_flags()[..3], [0x11, 0x22, 0x00]);
+        assert_eq!(chip8.registers()[..3], [0x11, 0x22, 0x00]);
+    }
+
+    #[test]
+    fn exit_stops_the_machine() {
+        let mut chip8 = super_chip(&[0x00, 0xFD], 1);
+        assert!(chip8.has_exited());
+        run(&mut chip8, 10);
+        assert_eq!(chip8.pc(), 0x202);
+    }
+
+    fn xo_chip(program: &[u8], cycles: usize) -> Chip8 {
+        let mut chip8 = load(Platform::XoChip, Quirks::xo_chip(), program);
+        run(&mut chip8, cycles);
+        chip8
+    }
+
+    #[test]
+    fn draws_on_the_selected_planes() {
+        let program = [
+            0xA2, 0x0A,     // 200: LD I, 0x20A
+            0xF2, 0x01,     // 202: PLANE 2
+            0xD0, 0x01,     // 204: DRW V0, V0, 1
+            0xF3, 0x01,     // 206: PLANE 3
+            0xD0, 0x01,     // 208: DRW V0, V0, 1
+            0xC0, 0x30,     // 20A: sprite rows, plane 1 then plane 2
+        ];
+        let chip8 = xo_chip(&program, 3);
+        assert_eq!([0, 1, 2, 3].map(|x| chip8.framebuffer().pixel(x, 0)), [2, 2, 0, 0]);
+
+        // Plane 1 gets 0xC0 and plane 2 the row after it, on top of the first sprite
+        let chip8 = xo_chip(&program, 5);
+        assert_eq!([0, 1, 2, 3].map(|x| chip8.framebuffer().pixel(x, 0)), [3, 3, 2, 2]);
+
+        // Clearing only touches the selected planes
+        let chip8 = xo_chip(&[0xA2, 0x0A, 0xF3, 0x01, 0xD0, 0x01, 0xF1, 0x01, 0x00, 0xE0, 0xC0, 0x30], 5);
+        assert_eq!(chip8.framebuffer().selected_planes(), 1);
+        assert_eq!([0, 1, 2, 3].map(|x| chip8.framebuffer().pixel(x, 0)), [0, 0, 2, 2]);
+    }
+
+    #[test]
+    fn saves_and_loads_register_ranges_in_either_direction() {
+        let mut program = vec![0x61, 0x01, 0x62, 0x02, 0x63, 0x03, 0xA3, 0x00];
+        let store = |chip8: Chip8| chip8.memory()[0x300..0x304].to_vec();
+        assert_eq!(store(xo_chip(&[program.as_slice(), &[0x51, 0x32]].concat(), 5)), [1, 2, 3, 0]);
+        assert_eq!(store(xo_chip(&[program.as_slice(), &[0x53, 0x12]].concat(), 5)), [3, 2, 1, 0]);
+        assert_eq!(store(xo_chip(&[program.as_slice(), &[0x52, 0x22]].concat(), 5)), [2, 0, 0, 0]);
+
+        // Load 0x300.. = [9, 8, 7] into V4..V6 and V6..V4
+        program.splice(0..6, [0x60, 0x09, 0x61, 0x08, 0x62, 0x07]);
+        program.extend([0x50, 0x22]);
+        let chip8 = xo_chip(&[program.as_slice(), &[0x54, 0x63]].concat(), 6);
+        assert_eq!(chip8.registers()[4..7], [9, 8, 7]);
+        let chip8 = xo_chip(&[program.as_slice(), &[0x56, 0x43]].concat(), 6);
+        assert_eq!(chip8.registers()[4..7], [7, 8, 9]);
+        assert_eq!(chip8.i_register(), 0x300);
+    }
+
+    #[test]
+    fn long_load_reaches_all_memory_and_is_skipped_whole() {
+        // LD I, 0xFF00; LD [I], V0; SE V0, 0; LD I, 0x1234; LD V1, 1
+        let program = [0x60, 0x2A, 0xF0, 0x00, 0xFF, 0x00, 0xF0, 0x55, 0x30, 0x2A, 0xF0, 0x00, 0x12, 0x34, 0x61, 0x01];
+        let chip8 = xo_chip(&program, 4);
+        assert_eq!(chip8.memory().len(), 0x10000);
+        assert_eq!(chip8.memory()[0xFF00], 0x2A);
+        assert_eq!(chip8.i_register(), 0xFF01);
+        assert_eq!(chip8.pc(), 0x20E);
+
+        let chip8 = xo_chip(&program, 5);
+        assert_eq!(chip8.registers()[1], 1);
+        assert_eq!(chip8.i_register(), 0xFF01);
+    }
+
+    #[test]
+    fn loads_the_audio_pattern_and_pitch() {
+        let mut program = vec![
+            0xA2, 0x08,     // 200: LD I, 0x208
+            0xF0, 0x02,     // 202: AUDIO
+            0x60, 0xC8,     // 204: LD V0, 200
+            0xF0, 0x3A,     // 206: PITCH V0
+        ];
+        program.extend(0..16);
+
+        let chip8 = xo_chip(&program, 1);
+        assert_eq!(chip8.audio_pattern(), None);
+        assert_eq!(chip8.pitch(), 64);
+
+        let chip8 = xo_chip(&program, 4);
+        assert_eq!(chip8.audio_pattern(), Some(&core::array::from_fn(|i| i as u8)));
+        assert_eq!(chip8.pitch(), 200);
+    }
+
+    #[test]
+    fn sound_timer_counts_down_at_60_hz() {
+        // LD V0, 100; LD ST, V0; JP 0x204
+        let mut chip8 = load(Platform::Chip8, Quirks::classic(), &[0x60, 0x64, 0xF0, 0x18, 0x12, 0x04]);
+        run(&mut chip8, 2);
+        assert_eq!(chip8.sound_timer(), 100);
+        assert!(chip8.is_beeping());
+
+        // One emulated second at the default 700 Hz
+        run(&mut chip8, 700);
+        assert_eq!(chip8.sound_timer(), 40);
+        run(&mut chip8, 700);
+        assert_eq!(chip8.sound_timer(), 0);
+        assert!(!chip8.is_beeping());
+    }
+
+    fn pc_after_skip(vx: u8, opcode: u8, keypad: Keypad) -> usize {
+        // LD V0, vx; SKP V0 or SKNP V0
+        let mut chip8 = load(Platform::Chip8, Quirks::classic(), &[0x60, vx, 0xE0, opcode]);
+        chip8.run_cycle(keypad).unwrap();
+        chip8.run_cycle(keypad).unwrap();
+        chip8.pc()
+    }
+
+    #[test]
+    fn key_skips_test_the_key_in_vx() {
+        let two_keys = Keypad::from_bits(1 << 0x3 | 1 << 0x7);
+        assert_eq!(pc_after_skip(0x7, 0x9E, two_keys), 0x206);
+        assert_eq!(pc_after_skip(0x3, 0x9E, two_keys), 0x206);
+        assert_eq!(pc_after_skip(0x5, 0x9E, two_keys), 0x204);
+        assert_eq!(pc_after_skip(0x7, 0xA1, two_keys), 0x204);
+        assert_eq!(pc_after_skip(0x5, 0xA1, two_keys), 0x206);
+
+        // With nothing pressed SKNP always skips
+        assert_eq!(pc_after_skip(0x0, 0xA1, Keypad::new()), 0x206);
+        assert_eq!(pc_after_skip(0x0, 0x9E, Keypad::new()), 0x204);
+
+        // Only the low digit of VX names the key
+        assert_eq!(pc_after_skip(0x17, 0x9E, two_keys), 0x206);
+        assert_eq!(pc_after_skip(0xF3, 0xA1, two_keys), 0x204);
+    }
+
+    #[test]
+    fn key_wait_completes_when_the_key_is_released() {
+        // LD V1, 60; LD DT, V1; LD V0, K
+        let mut chip8 = load(Platform::Chip8, Quirks::classic(), &[0x61, 0x3C, 0xF1, 0x15, 0xF0, 0x0A]);
+        run(&mut chip8, 12);
+        assert_eq!(chip8.pc(), 0x204);
+
+        let mut held = Keypad::new();
+        held.press(0x5);
+        for _ in 0..100 { chip8.run_cycle(held).unwrap(); }
+        assert_eq!(chip8.pc(), 0x204);
+        assert_eq!(chip8.registers()[0], 0);
+
+        // Other keys pressed meanwhile don't change which key is waited for
+        held.press(0x2);
+        chip8.run_cycle(held).unwrap();
+        held.release(0x5);
+        chip8.run_cycle(held).unwrap();
+        assert_eq!(chip8.pc(), 0x206);
+        assert_eq!(chip8.registers()[0], 0x5);
+
+        // The delay timer kept running while the instruction was blocked: 114 cycles at 700 Hz is 9 ticks
+        assert_eq!(chip8.delay_timer(), 60 - 9);
+    }
+
+    #[test]
+    fn delay_timer_is_independent_of_the_cpu_speed() {
+        for cpu_hertz in [60, 500, 700, 2000] {
+            // LD V0, 120; LD DT, V0; JP 0x204
+            let config = Config { cpu_hertz, ..Config::default() };
+            let mut chip8 = Chip8::new(Rom::new(vec![0x60, 0x78, 0xF0, 0x15, 0x12, 0x04]), &config).unwrap();
+            run(&mut chip8, 2);
+            let start = chip8.delay_timer();
+
+            // One emulated second, however long it takes the host
+            run(&mut chip8, cpu_hertz as usize);
+            assert_eq!(start - chip8.delay_timer(), 60, "at {cpu_hertz} Hz");
+        }
+    }
+
+    #[test]
+    fn inspection_api_follows_execution() {
+        let mut chip8 = machine();
+        assert_eq!(chip8.pc(), 0x200);
+        assert_eq!(chip8.memory()[0x200..0x210], PROGRAM);
+        assert_eq!(chip8.memory()[..5], SPRITES[0]);
+
+        for _ in 0..6 { chip8.step(Keypad::new()).unwrap(); }
+        assert_eq!(chip8.pc(), 0x20E);
+        assert_eq!(chip8.stack(), [0x20C]);
+        assert_eq!(chip8.registers()[0], 60);
+        assert_eq!(chip8.delay_timer(), 60);
+
+        chip8.step(Keypad::new()).unwrap();
+        assert!(chip8.stack().is_empty());
+
+        // 700 Hz crosses a 60 Hz frame boundary every 11 or 12 instructions
+        let mut chip8 = machine();
+        chip8.run_frame(Keypad::new()).unwrap();
+        assert!(chip8.is_vblank());
+        assert_eq!(chip8.delay_timer(), 59);
+        chip8.run_frame(Keypad::new()).unwrap();
+        assert_eq!(chip8.delay_timer(), 58);
+    }
+}
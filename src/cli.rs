@@ -0,0 +1,318 @@
+use chip_8::config::{Config, Quirks};
+use chip_8::RomSettings;
+use chip_8::debugger::{Breakpoint, Watchpoint};
+use chip_8::disasm::Syntax;
+use chip_8::number;
+use chip_8::trace::{TraceFormat, TraceOptions, Trigger};
+use std::path::Path;
+
+pub const USAGE: &str = "\
+Usage: chip-8 [OPTIONS] [ROM]
+       chip-8 disasm [--syntax <SYNTAX>] [--quirks <PRESET>] [--offset <ADDR>] [--symbols <FILE>] ROM
+       chip-8 asm [--offset <ADDR>] [-o <FILE>] SOURCE
+
+Starts the launcher window when no ROM is given. Settings found for the ROM in
+the ROM database are applied first; the options below take precedence over them.
+The database is not bundled: copy programs.json from the CHIP-8 database
+(https://github.com/chip-8/chip-8-database) to ~/.config/chip-8/programs.json.
+Octo sources (.8o) and Octo cartridge GIFs are compiled when they are loaded.
+Addresses are named from ROM.sym when it exists, or from the file given with --symbols.
+
+Options:
+  --cpu-hz <N>        Instructions executed per second (default 700)
+  --offset <ADDR>     Address the ROM is loaded at (default 0x200)
+  --delay-hz <N>      Delay timer rate (default 60)
+  --sound-hz <N>      Sound timer rate (default 60)
+  --quirks <PRESET>   classic, vip, chip48, schip or xochip (default classic)
+  --scale <N>         Window pixels per low-resolution pixel (default 10)
+  --mute              Start with the beeper muted
+  --seed <N>          Seed for the random number generator, for reproducible runs
+  --rewind <SECONDS>  History kept for rewinding with Backspace, 0 disables it (default 10)
+  --headless          Run without a window and print the final screen
+  --frames <N>        Frames to run in headless mode (default 600)
+  --record <FILE>     Record the keypad of every frame to a movie file
+  --replay <FILE>     Play a movie back and check the final screen matches
+  --debug             Start paused with the debugger panel open
+  --break <ADDR[:COND]>
+                      Pause at ADDR, optionally only when COND holds (e.g. 0x2A4:V3==0x10)
+  --watch <SPEC>      Pause when memory is read (r:ADDR[-END]) or written (w:, rw:)
+                      or when a register changes (V3)
+  --symbols <FILE>    Symbol file naming addresses in the ROM (default ROM.sym)
+  --trace <FILE>      Log every instruction with the registers, I and timers to FILE
+  --trace-format <FORMAT>
+                      text or binary (default text)
+  --trace-start <TRIGGER>
+                      Start tracing at an address (0x2A4) or cycle (cycle:5000)
+  --trace-stop <TRIGGER>
+                      Stop tracing at an address or cycle
+  --trace-ring <N>    Keep only the last N instructions and write them when emulation fails
+  -h, --help          Print this message
+
+Disassembler options:
+  --syntax <SYNTAX>   cowgod or octo (default cowgod)
+
+Assembler options:
+  -o, --output <FILE> Where to write the ROM (default SOURCE with a .ch8 extension);
+                      the labels are written beside it to OUTPUT.sym";
+
+pub enum Command {
+    Launcher,
+    Run(Box<RunOptions>),
+    Disasm(DisasmOptions),
+    Asm(AsmOptions),
+    Help
+}
+
+pub struct RunOptions {
+    pub config: Config,
+    pub rom_settings: Option<RomSettings>,  // options given explicitly, applied over the ROM database
+    pub headless: bool,
+    pub frames: u64,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    pub debug: bool,
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub symbols: Option<String>,
+    pub trace: Option<(String, TraceOptions)>,
+}
+
+pub struct DisasmOptions {
+    pub rom_path: String,
+    pub rom_settings: RomSettings,
+    pub syntax: Syntax,
+    pub symbols: Option<String>
+}
+
+pub struct AsmOptions {
+    pub source: String,
+    pub output: String,
+    pub offset: u16
+}
+
+pub fn parse(args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut args = args.peekable();
+    match args.peek().map(String::as_str) {
+        Some("disasm") => parse_disasm(args.skip(1)),
+        Some("asm") => parse_asm(args.skip(1)),
+        _ => parse_run(args)
+    }
+}
+
+fn parse_run(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut config = Config::default();
+    let mut rom_settings = RomSettings::default();
+    let mut rom_path: Option<String> = None;
+    let mut headless = false;
+    let mut frames = 600;
+    let mut record = None;
+    let mut replay = None;
+    let mut debug = false;
+    let mut breakpoints = Vec::new();
+    let mut watchpoints = Vec::new();
+    let mut symbols = None;
+    let mut trace_path = None;
+    let mut trace = TraceOptions::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{name} needs a value"));
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--cpu-hz" => rom_settings.cpu_hertz = Some(parse_number(&arg, &value(&arg)?)?),
+            "--offset" => rom_settings.rom_offset = Some(parse_number(&arg, &value(&arg)?)?),
+            "--delay-hz" => config.delay_timer_hertz = parse_number(&arg, &value(&arg)?)?,
+            "--sound-hz" => config.sound_timer_hertz = parse_number(&arg, &value(&arg)?)?,
+            "--scale" => config.scale = parse_number(&arg, &value(&arg)?)?,
+            "--seed" => config.seed = Some(parse_number(&arg, &value(&arg)?)?),
+            "--rewind" => config.rewind_seconds = parse_number(&arg, &value(&arg)?)?,
+            "--frames" => frames = parse_number(&arg, &value(&arg)?)?,
+            "--quirks" => {
+                let name = value(&arg)?;
+                let (platform, quirks) = Quirks::preset(&name)
+                    .ok_or(format!("unknown quirks preset `{name}`"))?;
+                (rom_settings.platform, rom_settings.quirks) = (Some(platform), Some(quirks));
+            },
+            "--record" => record = Some(value(&arg)?),
+            "--replay" => replay = Some(value(&arg)?),
+            "--break" => breakpoints.push(Breakpoint::parse(&value(&arg)?)?),
+            "--watch" => watchpoints.push(Watchpoint::parse(&value(&arg)?)?),
+            "--symbols" => symbols = Some(value(&arg)?),
+            "--trace" => trace_path = Some(value(&arg)?),
+            "--trace-format" => {
+                let name = value(&arg)?;
+                trace.format = TraceFormat::parse(&name).ok_or(format!("unknown trace format `{name}`"))?;
+            },
+            "--trace-start" => trace.start = Some(Trigger::parse(&value(&arg)?)?),
+            "--trace-stop" => trace.stop = Some(Trigger::parse(&value(&arg)?)?),
+            "--trace-ring" => trace.ring = Some(parse_number(&arg, &value(&arg)?)?),
+            "--debug" => debug = true,
+            "--mute" => config.muted = true,
+            "--headless" => headless = true,
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{arg}`")),
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => return Err(format!("unexpected argument `{arg}`"))
+        }
+    }
+
+    if record.is_some() && replay.is_some() { return Err(String::from("--record and --replay cannot be combined")) }
+    if rom_settings.cpu_hertz == Some(0) { return Err(String::from("--cpu-hz must be greater than 0")) }
+    if config.scale == 0 { return Err(String::from("--scale must be greater than 0")) }
+    if trace.ring == Some(0) { return Err(String::from("--trace-ring must be greater than 0")) }
+    let trace_set = trace.start.is_some() || trace.stop.is_some() || trace.ring.is_some() || trace.format != TraceFormat::Text;
+    if trace_set && trace_path.is_none() { return Err(String::from("the --trace-* options need --trace")) }
+    let trace = trace_path.map(|path| (path, trace));
+
+    match rom_path {
+        Some(rom_path) => {
+            config.rom_path = rom_path;
+            Ok(Command::Run(Box::new(RunOptions { config, rom_settings: Some(rom_settings), headless, frames, record, replay, debug, breakpoints, watchpoints, symbols, trace })))
+        },
+        None if headless => Err(String::from("--headless needs a ROM")),
+        None => Ok(Command::Launcher)
+    }
+}
+
+fn parse_disasm(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut rom_settings = RomSettings::default();
+    let mut rom_path: Option<String> = None;
+    let mut syntax = Syntax::Cowgod;
+    let mut symbols = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{name} needs a value"));
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--offset" => rom_settings.rom_offset = Some(parse_number(&arg, &value(&arg)?)?),
+            "--quirks" => {
+                let name = value(&arg)?;
+                let (platform, quirks) = Quirks::preset(&name)
+                    .ok_or(format!("unknown quirks preset `{name}`"))?;
+                (rom_settings.platform, rom_settings.quirks) = (Some(platform), Some(quirks));
+            },
+            "--syntax" => {
+                let name = value(&arg)?;
+                syntax = Syntax::parse(&name).ok_or(format!("unknown syntax `{name}`"))?;
+            },
+            "--symbols" => symbols = Some(value(&arg)?),
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{arg}`")),
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => return Err(format!("unexpected argument `{arg}`"))
+        }
+    }
+
+    let rom_path = rom_path.ok_or("disasm needs a ROM")?;
+    Ok(Command::Disasm(DisasmOptions { rom_path, rom_settings, syntax, symbols }))
+}
+
+fn parse_asm(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut source: Option<String> = None;
+    let mut output = None;
+    let mut offset = 0x200;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{name} needs a value"));
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--offset" => offset = parse_number(&arg, &value(&arg)?)?,
+            "-o" | "--output" => output = Some(value(&arg)?),
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{arg}`")),
+            _ if source.is_none() => source = Some(arg),
+            _ => return Err(format!("unexpected argument `{arg}`"))
+        }
+    }
+
+    let source = source.ok_or("asm needs a source file")?;
+    let output = output.unwrap_or_else(|| Path::new(&source).with_extension("ch8").display().to_string());
+    Ok(Command::Asm(AsmOptions { source, output, offset }))
+}
+
+fn parse_number<T: TryFrom<u64>>(name: &str, value: &str) -> Result<T, String> {
+    number::parse(value).ok_or(format!("invalid value `{value}` for {name}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip_8::Platform;
+
+    fn parse_args(args: &str) -> Result<Command, String> {
+        parse(args.split_whitespace().map(String::from))
+    }
+
+    fn run_options(args: &str) -> RunOptions {
+        match parse_args(args) {
+            Ok(Command::Run(options)) => *options,
+            _ => panic!("`{args}` does not run a ROM")
+        }
+    }
+
+    fn error(args: &str) -> String { parse_args(args).err().unwrap() }
+
+    #[test]
+    fn builds_a_config_from_run_options() {
+        let options = run_options("--cpu-hz 1000 --offset 0x600 --delay-hz 30 --sound-hz 0b11110 --scale 4 --quirks schip --mute --headless --frames 60 game.ch8");
+        let settings = options.rom_settings.unwrap();
+        assert_eq!(settings.cpu_hertz, Some(1000));
+        assert_eq!(settings.rom_offset, Some(0x600));
+        assert_eq!(settings.platform, Some(Platform::SuperChip));
+        assert_eq!(settings.quirks, Some(Quirks::super_chip()));
+        assert_eq!(options.config.rom_path, "game.ch8");
+        assert_eq!(options.config.delay_timer_hertz, 30);
+        assert_eq!(options.config.sound_timer_hertz, 30);
+        assert_eq!(options.config.scale, 4);
+        assert!(options.config.muted);
+        assert!(options.headless);
+        assert_eq!(options.frames, 60);
+
+        // Options that are not given leave the database and the defaults alone
+        let options = run_options("game.ch8");
+        let settings = options.rom_settings.unwrap();
+        assert_eq!((settings.cpu_hertz, settings.platform, settings.quirks), (None, None, None));
+        assert_eq!(options.config.scale, Config::default().scale);
+        assert!(!options.headless);
+    }
+
+    #[test]
+    fn falls_back_to_the_launcher_without_a_rom() {
+        assert!(matches!(parse_args(""), Ok(Command::Launcher)));
+        assert!(matches!(parse_args("--scale 3"), Ok(Command::Launcher)));
+        assert!(matches!(parse_args("--help game.ch8"), Ok(Command::Help)));
+        assert_eq!(error("--headless"), "--headless needs a ROM");
+    }
+
+    #[test]
+    fn rejects_bad_run_options() {
+        assert_eq!(error("--cpu-hz"), "--cpu-hz needs a value");
+        assert_eq!(error("--cpu-hz fast game.ch8"), "invalid value `fast` for --cpu-hz");
+        assert_eq!(error("--cpu-hz 0 game.ch8"), "--cpu-hz must be greater than 0");
+        assert_eq!(error("--delay-hz 256 game.ch8"), "invalid value `256` for --delay-hz");
+        assert_eq!(error("--offset 0x10000 game.ch8"), "invalid value `0x10000` for --offset");
+        assert_eq!(error("--scale 0 game.ch8"), "--scale must be greater than 0");
+        assert_eq!(error("--quirks amiga game.ch8"), "unknown quirks preset `amiga`");
+        assert_eq!(error("--fast game.ch8"), "unknown option `--fast`");
+        assert_eq!(error("game.ch8 other.ch8"), "unexpected argument `other.ch8`");
+        assert_eq!(error("--record a --replay b game.ch8"), "--record and --replay cannot be combined");
+        assert_eq!(error("--trace-ring 10 game.ch8"), "the --trace-* options need --trace");
+    }
+
+    #[test]
+    fn parses_the_tool_commands() {
+        let Ok(Command::Disasm(options)) = parse_args("disasm --syntax octo --quirks xochip --offset 0x300 game.ch8") else {
+            panic!("not a disasm command")
+        };
+        assert_eq!(options.rom_path, "game.ch8");
+        assert_eq!(options.syntax, Syntax::Octo);
+        assert_eq!(options.rom_settings.platform, Some(Platform::XoChip));
+        assert_eq!(options.rom_settings.rom_offset, Some(0x300));
+        assert_eq!(error("disasm"), "disasm needs a ROM");
+
+        let Ok(Command::Asm(options)) = parse_args("asm game.8o") else { panic!("not an asm command") };
+        assert_eq!((options.source.as_str(), options.output.as_str(), options.offset), ("game.8o", "game.ch8", 0x200));
+        let Ok(Command::Asm(options)) = parse_args("asm -o out.bin --offset 512 game.8o") else { panic!("not an asm command") };
+        assert_eq!((options.output.as_str(), options.offset), ("out.bin", 512));
+        assert_eq!(error("asm"), "asm needs a source file");
+    }
+}
@@ -1,7 +1,184 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub rom_path: String,
     pub cpu_hertz: u32,
     pub delay_timer_hertz: u8,
     pub sound_timer_hertz: u8,
     pub rom_offset: u16,
-}
\ No newline at end of file
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub palette: [u32; 4],
+    pub keys: KeyBindings,
+    pub beep_frequency: u32,
+    pub volume: f32,
+    pub muted: bool,
+    pub scale: u32,
+    pub rewind_seconds: u32,
+    pub seed: Option<u64>,            // random seed for CXNN, picked at startup when unset
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            rom_path: String::new(),
+            cpu_hertz: 700,
+            delay_timer_hertz: 60,
+            sound_timer_hertz: 60,
+            rom_offset: 512,
+            platform: Platform::Chip8,
+            quirks: Quirks::classic(),
+            palette: DEFAULT_PALETTE,
+            keys: KeyBindings::default(),
+            beep_frequency: 440,
+            volume: 0.5,
+            muted: false,
+            scale: 10,
+            rewind_seconds: 10,
+            seed: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Platform {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl Platform {
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Platform::XoChip => 0x10000,
+            _ => 0x1000
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Quirks {
+    pub shift_uses_vy: bool,            // 8XY6/8XYE shift VY into VX instead of shifting VX in place
+    pub load_store_increments_i: bool,  // FX55/FX65 leave I pointing past the last register
+    pub jump_uses_vx: bool,             // BXNN jumps to XNN + VX instead of NNN + V0
+    pub logic_resets_vf: bool,          // 8XY1/8XY2/8XY3 set VF to 0
+    pub clip_sprites: bool,             // DXYN clips sprites at the screen edge instead of wrapping them
+    pub display_wait: bool,             // DXYN waits for the next 60 Hz frame before drawing
+    #[serde(default)]
+    pub increment_i_by_x: bool,         // FX55/FX65 advance I by X rather than X + 1, as CHIP-48 did
+}
+
+impl Quirks {
+    pub const PRESETS: [(&'static str, Platform, Quirks); 5] = [
+        ("CLASSIC", Platform::Chip8, Quirks::classic()),
+        ("COSMAC VIP", Platform::Chip8, Quirks::cosmac_vip()),
+        ("CHIP-48", Platform::Chip8, Quirks::chip48()),
+        ("SUPER-CHIP", Platform::SuperChip, Quirks::super_chip()),
+        ("XO-CHIP", Platform::XoChip, Quirks::xo_chip()),
+    ];
+
+    // Looks a preset up by name, ignoring case and punctuation ("xo-chip", "XOCHIP", ...)
+    pub fn preset(name: &str) -> Option<(Platform, Quirks)> {
+        let name = name.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_ascii_lowercase();
+        let name = match name.as_str() {
+            "vip" => "cosmacvip",
+            "schip" => "superchip",
+            name => name
+        };
+
+        Quirks::PRESETS.iter()
+            .find(|(preset, _, _)| preset.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().eq_ignore_ascii_case(name))
+            .map(|(_, platform, quirks)| (*platform, *quirks))
+    }
+
+    // How this emulator always ran programs before quirks could be chosen: shifts work on VX,
+    // FX55/FX65 leave I alone, BNNN adds V0 and sprites wrap around the screen
+    pub const fn classic() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+            display_wait: false,
+            increment_i_by_x: false,
+        }
+    }
+
+    pub const fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            clip_sprites: true,
+            display_wait: true,
+            increment_i_by_x: false,
+        }
+    }
+
+    pub const fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: true,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+            display_wait: false,
+            increment_i_by_x: false,
+        }
+    }
+
+    pub const fn modern_chip8() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: true,
+            display_wait: false,
+            increment_i_by_x: false,
+        }
+    }
+
+    pub const fn super_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+            display_wait: false,
+            increment_i_by_x: false,
+        }
+    }
+
+    pub const fn xo_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+            display_wait: false,
+            increment_i_by_x: false,
+        }
+    }
+}
+
+// Extra host keys (arrows, Space and Enter) mapped onto CHIP-8 keys for a specific ROM
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub up: Option<u8>,
+    pub down: Option<u8>,
+    pub left: Option<u8>,
+    pub right: Option<u8>,
+    pub a: Option<u8>,
+    pub b: Option<u8>,
+}
+
+// Background, plane 1, plane 2 and both planes, as 0xRRGGBB
+pub const DEFAULT_PALETTE: [u32; 4] = [0x000000, 0xFFFFFF, 0xFF6600, 0x662200];
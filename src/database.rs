@@ -0,0 +1,359 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::config::{Config, KeyBindings, Platform, Quirks};
+use crate::settings::config_dir;
+
+// Platform ids of the community CHIP-8 database and their default quirks
+const PLATFORMS: [(&str, Platform, Quirks); 7] = [
+    ("originalChip8", Platform::Chip8, Quirks::cosmac_vip()),
+    ("hybridVIP", Platform::Chip8, Quirks::cosmac_vip()),
+    ("modernChip8", Platform::Chip8, Quirks::modern_chip8()),
+    ("chip48", Platform::Chip8, Quirks::chip48()),
+    ("superchip1", Platform::SuperChip, Quirks::super_chip()),
+    ("superchip", Platform::SuperChip, Quirks::super_chip()),
+    ("xochip", Platform::XoChip, Quirks::xo_chip()),
+];
+
+// The parts of a Config that depend on the ROM being played
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RomSettings {
+    pub platform: Option<Platform>,
+    pub quirks: Option<Quirks>,
+    pub cpu_hertz: Option<u32>,
+    pub rom_offset: Option<u16>,
+    pub palette: Option<[u32; 4]>,
+    pub keys: Option<KeyBindings>,
+}
+
+impl RomSettings {
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(platform) = self.platform { config.platform = platform; }
+        if let Some(quirks) = self.quirks { config.quirks = quirks; }
+        if let Some(cpu_hertz) = self.cpu_hertz { config.cpu_hertz = cpu_hertz; }
+        if let Some(rom_offset) = self.rom_offset { config.rom_offset = rom_offset; }
+        if let Some(palette) = self.palette { config.palette = palette; }
+        if let Some(keys) = self.keys { config.keys = keys; }
+    }
+
+    // Settings from `other` take precedence over ours
+    pub fn merge(&self, other: &RomSettings) -> RomSettings {
+        RomSettings {
+            platform: other.platform.or(self.platform),
+            quirks: other.quirks.or(self.quirks),
+            cpu_hertz: other.cpu_hertz.or(self.cpu_hertz),
+            rom_offset: other.rom_offset.or(self.rom_offset),
+            palette: other.palette.or(self.palette),
+            keys: other.keys.or(self.keys),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool { *self == RomSettings::default() }
+}
+
+pub struct RomEntry {
+    pub title: String,
+    pub settings: RomSettings,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Overrides {
+    roms: BTreeMap<String, RomSettings>,
+}
+
+// ROM settings keyed by the SHA-1 of the program. Entries come from the programs.json of
+// the community CHIP-8 database (https://github.com/chip-8/chip-8-database), which is not
+// shipped with the emulator and has to be copied into the config directory; the user's
+// own changes live in overrides.toml beside it.
+#[derive(Default)]
+pub struct RomDatabase {
+    entries: HashMap<String, RomEntry>,
+    overrides: Overrides,
+}
+
+impl RomDatabase {
+    pub fn programs_path() -> Option<PathBuf> { config_dir().map(|dir| dir.join("programs.json")) }
+
+    pub fn overrides_path() -> Option<PathBuf> { config_dir().map(|dir| dir.join("overrides.toml")) }
+
+    // Missing files leave the database empty; broken ones are skipped and returned as warnings
+    pub fn load() -> (RomDatabase, Vec<String>) {
+        let mut database = RomDatabase::default();
+        let mut warnings = Vec::new();
+
+        match read_config_file(RomDatabase::programs_path()) {
+            Ok(Some(text)) => match RomDatabase::parse_programs(&text) {
+                Ok(entries) => database.entries = entries,
+                Err(e) => warnings.push(format!("Ignoring the ROM database: {e}"))
+            },
+            Ok(None) => (),
+            Err(e) => warnings.push(format!("Ignoring the ROM database: {e}"))
+        }
+
+        match read_config_file(RomDatabase::overrides_path()) {
+            Ok(Some(text)) => match toml::from_str(&text) {
+                Ok(overrides) => database.overrides = overrides,
+                Err(e) => warnings.push(format!("Ignoring the ROM overrides: {e}"))
+            },
+            Ok(None) => (),
+            Err(e) => warnings.push(format!("Ignoring the ROM overrides: {e}"))
+        }
+
+        (database, warnings)
+    }
+
+    pub fn parse_programs(text: &str) -> Result<HashMap<String, RomEntry>, String> {
+        let programs: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let programs = programs.as_array().ok_or("expected an array of programs")?;
+        let mut entries = HashMap::new();
+
+        for program in programs {
+            let title = program.get("title").and_then(Value::as_str).unwrap_or_default();
+            let Some(roms) = program.get("roms").and_then(Value::as_object) else { continue };
+
+            for (hash, rom) in roms {
+                entries.insert(hash.to_ascii_lowercase(), RomEntry {
+                    title: String::from(title),
+                    settings: rom_settings(rom)
+                });
+            }
+        }
+
+        Ok(entries)
+    }
+
+    pub fn entry(&self, hash: &str) -> Option<&RomEntry> { self.entries.get(hash) }
+
+    // Database settings with the user's overrides layered on top
+    pub fn lookup(&self, hash: &str) -> RomSettings {
+        let settings = self.entry(hash).map(|entry| entry.settings.clone()).unwrap_or_default();
+        match self.overrides.roms.get(hash) {
+            Some(overrides) => settings.merge(overrides),
+            None => settings
+        }
+    }
+
+    // Stores whatever `config` changes with respect to the database as the ROM's overrides
+    pub fn remember(&mut self, hash: &str, config: &Config) {
+        let mut expected = Config::default();
+        if let Some(entry) = self.entry(hash) { entry.settings.apply(&mut expected); }
+
+        let overrides = RomSettings {
+            platform: Some(config.platform).filter(|platform| *platform != expected.platform),
+            quirks: Some(config.quirks).filter(|quirks| *quirks != expected.quirks),
+            cpu_hertz: Some(config.cpu_hertz).filter(|cpu_hertz| *cpu_hertz != expected.cpu_hertz),
+            rom_offset: Some(config.rom_offset).filter(|rom_offset| *rom_offset != expected.rom_offset),
+            ..RomSettings::default()
+        };
+
+        if overrides.is_empty() { self.overrides.roms.remove(hash); }
+        else { self.overrides.roms.insert(String::from(hash), overrides); }
+    }
+
+    pub fn save_overrides(&self) -> io::Result<()> {
+        let path = RomDatabase::overrides_path().ok_or(io::Error::new(io::ErrorKind::NotFound, "no config directory"))?;
+        let text = toml::to_string(&self.overrides).map_err(io::Error::other)?;
+
+        fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+        fs::write(path, text)
+    }
+}
+
+// The text of a file in the config directory, or None when there is no such file
+fn read_config_file(path: Option<PathBuf>) -> io::Result<Option<String>> {
+    let Some(path) = path else { return Ok(None) };
+    match fs::read_to_string(&path) {
+        Ok(text) => Ok(Some(text)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+    }
+}
+
+fn rom_settings(rom: &Value) -> RomSettings {
+    let mut settings = RomSettings::default();
+
+    // The first platform listed that this emulator can run
+    let platform = rom.get("platforms").and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default().iter()
+        .filter_map(Value::as_str)
+        .find_map(|id| PLATFORMS.iter().find(|(name, _, _)| *name == id));
+    if let Some((id, platform, quirks)) = platform {
+        let mut quirks = *quirks;
+        if let Some(overrides) = rom.get("quirkyPlatforms").and_then(|quirky| quirky.get(id)) {
+            apply_quirks(&mut quirks, overrides, &QUIRK_FLAGS);
+        }
+        settings.platform = Some(*platform);
+        settings.quirks = Some(quirks);
+    }
+
+    settings.cpu_hertz = rom.get("tickrate").and_then(tickrate_hertz);
+    settings.rom_offset = rom.get("startAddress").and_then(Value::as_u64).and_then(|address| u16::try_from(address).ok());
+
+    if let Some(pixels) = rom.get("colors").and_then(|colors| colors.get("pixels")).and_then(Value::as_array) {
+        let mut palette = Config::default().palette;
+        for (color, pixel) in palette.iter_mut().zip(pixels) {
+            if let Some(rgb) = pixel.as_str().and_then(parse_color) { *color = rgb; }
+        }
+        settings.palette = Some(palette);
+    }
+
+    if let Some(keys) = rom.get("keys") {
+        let key = |name: &str| keys.get(name).and_then(Value::as_u64).filter(|key| *key < 16).map(|key| key as u8);
+        settings.keys = Some(KeyBindings {
+            up: key("up"),
+            down: key("down"),
+            left: key("left"),
+            right: key("right"),
+            a: key("a"),
+            b: key("b"),
+        });
+    }
+
+    settings
+}
+
+// A JSON flag, the quirk it sets and whether it is inverted. Flags named after the non-VIP
+// behaviour, like the database's `shift`, are inverted.
+pub(crate) type QuirkFlag = (&'static str, fn(&mut Quirks) -> &mut bool, bool);
+
+const QUIRK_FLAGS: [QuirkFlag; 7] = [
+    ("shift", |quirks| &mut quirks.shift_uses_vy, true),
+    ("memoryLeaveIUnchanged", |quirks| &mut quirks.load_store_increments_i, true),
+    ("memoryIncrementByX", |quirks| &mut quirks.increment_i_by_x, false),
+    ("jump", |quirks| &mut quirks.jump_uses_vx, false),
+    ("logic", |quirks| &mut quirks.logic_resets_vf, false),
+    ("wrap", |quirks| &mut quirks.clip_sprites, true),
+    ("vblank", |quirks| &mut quirks.display_wait, false)
+];
+
+pub(crate) fn apply_quirks(quirks: &mut Quirks, flags: &Value, names: &[QuirkFlag]) {
+    for (name, quirk, inverted) in names {
+        if let Some(value) = flags.get(name).and_then(Value::as_bool) { *quirk(quirks) = value != *inverted; }
+    }
+}
+
+// Octo and the database give the speed as instructions per 60 Hz frame
+pub(crate) fn tickrate_hertz(tickrate: &Value) -> Option<u32> {
+    tickrate.as_u64()
+        .filter(|tickrate| *tickrate > 0)
+        .and_then(|tickrate| tickrate.checked_mul(60))
+        .and_then(|hertz| u32::try_from(hertz).ok())
+}
+
+// "#RRGGBB" or "RRGGBB"
+pub(crate) fn parse_color(text: &str) -> Option<u32> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    if hex.len() != 6 { return None }
+    u32::from_str_radix(hex, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn parse_rom(rom: &str) -> RomSettings {
+        let text = format!(r#"[{{"title": "Test", "roms": {{"{}": {rom}}}}}]"#, HASH.to_ascii_uppercase());
+        let mut entries = RomDatabase::parse_programs(&text).unwrap();
+        let entry = entries.remove(HASH).unwrap();
+        assert_eq!(entry.title, "Test");
+        entry.settings
+    }
+
+    #[test]
+    fn reads_platform_quirks_speed_and_keys() {
+        let settings = parse_rom(r#"{
+            "platforms": ["megachip8", "chip48"],
+            "quirkyPlatforms": {"chip48": {"shift": false, "memoryIncrementByX": true}},
+            "tickrate": 30,
+            "startAddress": 768,
+            "keys": {"up": 5, "a": 16}
+        }"#);
+
+        assert_eq!(settings.platform, Some(Platform::Chip8));
+        let quirks = settings.quirks.unwrap();
+        assert!(quirks.shift_uses_vy);
+        assert!(quirks.increment_i_by_x);
+        assert!(quirks.jump_uses_vx);
+        assert_eq!(settings.cpu_hertz, Some(1800));
+        assert_eq!(settings.rom_offset, Some(0x300));
+        let keys = settings.keys.unwrap();
+        assert_eq!((keys.up, keys.a), (Some(5), None));
+    }
+
+    #[test]
+    fn ignores_out_of_range_numbers() {
+        let settings = parse_rom(r#"{"tickrate": 18446744073709551615, "startAddress": 70000}"#);
+        assert_eq!(settings.cpu_hertz, None);
+        assert_eq!(settings.rom_offset, None);
+    }
+
+    #[test]
+    fn rejects_deeply_nested_documents() {
+        let text = "[".repeat(100_000);
+        assert!(RomDatabase::parse_programs(&text).is_err());
+    }
+
+    fn database(rom: &str) -> RomDatabase {
+        let text = format!(r#"[{{"title": "Test", "roms": {{"{HASH}": {rom}}}}}]"#);
+        RomDatabase { entries: RomDatabase::parse_programs(&text).unwrap(), overrides: Overrides::default() }
+    }
+
+    #[test]
+    fn reads_colours() {
+        let settings = parse_rom(r##"{"colors": {"pixels": ["#102030", "nonsense", "#ABCDEF"]}}"##);
+        let default = Config::default().palette;
+        assert_eq!(settings.palette, Some([0x102030, default[1], 0xABCDEF, default[3]]));
+        assert!(RomDatabase::parse_programs(r#"{"title": "Not a list"}"#).is_err());
+    }
+
+    #[test]
+    fn remembers_only_what_differs_from_the_database() {
+        let mut database = database(r#"{"platforms": ["superchip"], "tickrate": 20}"#);
+        let mut config = Config::default();
+        database.lookup(HASH).apply(&mut config);
+        assert_eq!((config.platform, config.cpu_hertz), (Platform::SuperChip, 1200));
+
+        database.remember(HASH, &config);
+        assert!(database.overrides.roms.is_empty());
+
+        config.cpu_hertz = 500;
+        database.remember(HASH, &config);
+        let settings = database.lookup(HASH);
+        assert_eq!(database.overrides.roms[HASH], RomSettings { cpu_hertz: Some(500), ..RomSettings::default() });
+        assert_eq!((settings.platform, settings.cpu_hertz), (Some(Platform::SuperChip), Some(500)));
+
+        // ROMs without an entry are remembered against the defaults
+        let other = "ffffffffffffffffffffffffffffffffffffffff";
+        database.remember(other, &Config::default());
+        assert!(database.lookup(other).is_empty());
+        database.remember(other, &Config { rom_offset: 0x600, ..Config::default() });
+        assert_eq!(database.lookup(other).rom_offset, Some(0x600));
+    }
+
+    #[test]
+    fn merged_settings_prefer_the_other_side() {
+        let ours = RomSettings { cpu_hertz: Some(100), rom_offset: Some(0x300), ..RomSettings::default() };
+        let theirs = RomSettings { cpu_hertz: Some(200), ..RomSettings::default() };
+        let merged = ours.merge(&theirs);
+        assert_eq!((merged.cpu_hertz, merged.rom_offset), (Some(200), Some(0x300)));
+    }
+
+    #[test]
+    fn missing_config_files_are_not_an_error() {
+        let path = std::env::temp_dir().join(format!("chip-8-database-{}.json", std::process::id()));
+        assert!(read_config_file(Some(path.clone())).unwrap().is_none());
+        assert!(read_config_file(None).unwrap().is_none());
+
+        fs::write(&path, "[]").unwrap();
+        let text = read_config_file(Some(path.clone()));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(text.unwrap().as_deref(), Some("[]"));
+        assert!(read_config_file(Some(std::env::temp_dir())).is_err());
+    }
+}
@@ -0,0 +1,532 @@
+use std::fmt;
+use crate::chip::{Access, Chip8};
+use crate::error::Chip8Error;
+use crate::keypad::Keypad;
+use crate::number;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    // Longest operators first so "<=" is not read as "<"
+    const OPERATORS: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Equal),
+        ("!=", Comparison::NotEqual),
+        ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+    ];
+
+    fn operator(&self) -> &'static str {
+        Comparison::OPERATORS.iter().find(|(_, comparison)| comparison == self).map_or("", |(operator, _)| operator)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RegisterCondition {
+    pub register: usize,
+    pub comparison: Comparison,
+    pub value: u8,
+}
+
+impl RegisterCondition {
+    pub fn holds(&self, registers: &[u8; 16]) -> bool {
+        let register = registers[self.register];
+        match self.comparison {
+            Comparison::Equal => register == self.value,
+            Comparison::NotEqual => register != self.value,
+            Comparison::Less => register < self.value,
+            Comparison::LessOrEqual => register <= self.value,
+            Comparison::Greater => register > self.value,
+            Comparison::GreaterOrEqual => register >= self.value,
+        }
+    }
+
+    // "V3==0x10", "va < 5", ...
+    pub fn parse(text: &str) -> Option<RegisterCondition> {
+        let text = text.trim();
+        let register = text.strip_prefix(['V', 'v'])?.get(..1)?;
+        let register = usize::from_str_radix(register, 16).ok()?;
+        let rest = text[2..].trim_start();
+
+        let (operator, comparison) = Comparison::OPERATORS.iter().find(|(operator, _)| rest.starts_with(operator))?;
+        let value = number::parse(rest[operator.len()..].trim())?;
+
+        Some(RegisterCondition { register, comparison: *comparison, value })
+    }
+}
+
+impl fmt::Display for RegisterCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "V{:X} {} {:#04X}", self.register, self.comparison.operator(), self.value)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Breakpoint {
+    pub addr: usize,
+    pub condition: Option<RegisterCondition>,
+}
+
+impl Breakpoint {
+    // "0x2A4" or "0x2A4:V3==10"
+    pub fn parse(text: &str) -> Result<Breakpoint, String> {
+        let (addr, condition) = match text.split_once(':') {
+            Some((addr, condition)) => (addr, Some(condition)),
+            None => (text, None)
+        };
+        let addr = number::parse(addr.trim()).ok_or(format!("invalid breakpoint address `{addr}`"))?;
+        let condition = match condition {
+            Some(condition) => Some(RegisterCondition::parse(condition).ok_or(format!("invalid breakpoint condition `{condition}`"))?),
+            None => None
+        };
+
+        Ok(Breakpoint { addr, condition })
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.condition {
+            Some(condition) => write!(f, "{:#05X} if {condition}", self.addr),
+            None => write!(f, "{:#05X}", self.addr)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Watchpoint {
+    Memory { start: usize, end: usize, read: bool, write: bool },  // `end` is inclusive; any write counts, even of the same value
+    Register(usize),                                                // VX changes value; writing the value it holds does not count
+}
+
+impl Watchpoint {
+    // "w:0x300-0x30F", "r:0x300", "rw:0x300-0x30F" or "V3"
+    pub fn parse(text: &str) -> Result<Watchpoint, String> {
+        let invalid = || format!("invalid watchpoint `{text}`");
+        let text = text.trim();
+
+        if let Some(register) = text.strip_prefix(['V', 'v']) {
+            let register = usize::from_str_radix(register, 16).ok().filter(|x| *x < 16).ok_or_else(invalid)?;
+            return Ok(Watchpoint::Register(register))
+        }
+
+        let (kind, range) = text.split_once(':').ok_or_else(invalid)?;
+        let (read, write) = match kind.to_ascii_lowercase().as_str() {
+            "r" => (true, false),
+            "w" => (false, true),
+            "rw" | "wr" => (true, true),
+            _ => return Err(invalid())
+        };
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let start = number::parse(start.trim()).ok_or_else(invalid)?;
+        let end = number::parse(end.trim()).filter(|end| *end >= start).ok_or_else(invalid)?;
+
+        Ok(Watchpoint::Memory { start, end, read, write })
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Watchpoint::Memory { start, end, read, write } => {
+                let kind = match (read, write) { (true, true) => "rw", (true, false) => "r", _ => "w" };
+                if start == end { write!(f, "{kind}:{start:#05X}") } else { write!(f, "{kind}:{start:#05X}-{end:#05X}") }
+            },
+            Watchpoint::Register(register) => write!(f, "V{register:X}")
+        }
+    }
+}
+
+// Where a resumed machine stops by itself
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Target {
+    Address(usize),
+    Return { addr: usize, depth: usize },   // back from the CALL being stepped over
+    StackBelow(usize),                      // out of the current subroutine
+}
+
+// Drives a Chip8 one cycle at a time, pausing it on breakpoints and after steps
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    paused: bool,
+    target: Option<Target>,
+    reason: String,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger { Debugger::default() }
+
+    pub fn is_paused(&self) -> bool { self.paused }
+
+    // Why the machine last stopped
+    pub fn reason(&self) -> &str { &self.reason }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] { &self.breakpoints }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) { self.breakpoints.push(breakpoint); }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] { &self.watchpoints }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) { self.watchpoints.push(watchpoint); }
+
+    pub fn toggle_breakpoint(&mut self, addr: usize) {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.addr != addr);
+        if self.breakpoints.len() == count { self.add_breakpoint(Breakpoint { addr, condition: None }); }
+    }
+
+    pub fn pause(&mut self, reason: &str) {
+        self.paused = true;
+        self.target = None;
+        self.reason = String::from(reason);
+    }
+
+    pub fn resume(&mut self) { self.paused = false; }
+
+    // Runs one cycle unless paused
+    pub fn run_cycle(&mut self, chip8: &mut Chip8, keypad: Keypad) -> Result<(), Chip8Error> {
+        if self.paused { return Ok(()) }
+
+        let pc = chip8.pc();
+        let registers = *chip8.registers();
+        let opcode = opcode_at(chip8, pc).unwrap_or_default();
+        chip8.run_cycle(keypad)?;
+
+        self.check_watchpoints(chip8, pc, opcode, &registers);
+        // Cycles that leave the PC in place (FX0A, the display wait) have not finished their instruction
+        if !self.paused && chip8.pc() != pc { self.check(chip8); }
+        Ok(())
+    }
+
+    // Executes instructions up to and including the next 60 Hz frame boundary, or until paused
+    pub fn run_frame(&mut self, chip8: &mut Chip8, keypad: Keypad) -> Result<(), Chip8Error> {
+        loop {
+            self.run_cycle(chip8, keypad)?;
+            if self.paused || chip8.is_vblank() || chip8.has_exited() { return Ok(()) }
+        }
+    }
+
+    // Completes the instruction at PC, giving up at the end of the frame if it keeps waiting
+    pub fn step(&mut self, chip8: &mut Chip8, keypad: Keypad) -> Result<(), Chip8Error> {
+        let pc = chip8.pc();
+        self.pause("stepped");
+        loop {
+            chip8.run_cycle(keypad)?;
+            if chip8.pc() != pc || chip8.is_vblank() || chip8.has_exited() { return Ok(()) }
+        }
+    }
+
+    // Runs a CALL until it returns; anything else is a plain step
+    pub fn step_over(&mut self, chip8: &mut Chip8, keypad: Keypad) -> Result<(), Chip8Error> {
+        let pc = chip8.pc();
+        if opcode_at(chip8, pc).is_some_and(|opcode| opcode & 0xF000 == 0x2000) {
+            self.run_until(Target::Return { addr: pc + 2, depth: chip8.stack().len() });
+            Ok(())
+        } else {
+            self.step(chip8, keypad)
+        }
+    }
+
+    // Runs until the current subroutine returns
+    pub fn step_out(&mut self, chip8: &Chip8) {
+        let depth = chip8.stack().len();
+        if depth > 0 { self.run_until(Target::StackBelow(depth)); }
+    }
+
+    pub fn run_to(&mut self, addr: usize) { self.run_until(Target::Address(addr)); }
+
+    fn run_until(&mut self, target: Target) {
+        self.target = Some(target);
+        self.paused = false;
+    }
+
+    // Reports the first watchpoint the instruction at `pc` triggered
+    fn check_watchpoints(&mut self, chip8: &Chip8, pc: usize, opcode: u16, registers: &[u8; 16]) {
+        for watchpoint in &self.watchpoints {
+            let hit = match *watchpoint {
+                Watchpoint::Register(x) => (registers[x] != chip8.registers()[x])
+                    .then(|| format!("V{x:X} changed {:02X} -> {:02X}", registers[x], chip8.registers()[x])),
+                Watchpoint::Memory { start, end, read, write } => chip8.memory_accesses().iter()
+                    .filter(|access| if access.access == Access::Read { read } else { write })
+                    .find(|access| access.addr <= end && access.addr + access.length > start)
+                    .map(|access| {
+                        let kind = if access.access == Access::Read { "read from" } else { "write to" };
+                        format!("{kind} {:#05X}", access.addr.max(start))
+                    })
+            };
+
+            if let Some(hit) = hit {
+                return self.pause(&format!("{hit} by {opcode:04X} at {pc:#05X}"))
+            }
+        }
+    }
+
+    fn check(&mut self, chip8: &Chip8) {
+        let (pc, depth) = (chip8.pc(), chip8.stack().len());
+        let reached = match self.target {
+            Some(Target::Address(addr)) => pc == addr,
+            Some(Target::Return { addr, depth: call_depth }) => pc == addr && depth == call_depth,
+            Some(Target::StackBelow(call_depth)) => depth < call_depth,
+            None => false
+        };
+        if reached { return self.pause(&format!("reached {pc:#05X}")) }
+
+        let hit = self.breakpoints.iter()
+            .find(|breakpoint| breakpoint.addr == pc && breakpoint.condition.is_none_or(|condition| condition.holds(chip8.registers())));
+        if let Some(breakpoint) = hit {
+            let reason = format!("breakpoint at {breakpoint}");
+            self.pause(&reason);
+        }
+    }
+}
+
+// Registers, timers and stack in a few lines of text
+pub fn state_lines(chip8: &Chip8) -> Vec<String> {
+    let symbols = chip8.symbols();
+    let mut lines = vec![format!("PC {}  I {:#05X}  DT {:02X}  ST {:02X}", symbols.annotate(chip8.pc()), chip8.i_register(), chip8.delay_timer(), chip8.sound_timer())];
+    for (row, registers) in chip8.registers().chunks(4).enumerate() {
+        lines.push(registers.iter().enumerate()
+            .map(|(i, value)| format!("V{:X} {value:02X}", row * 4 + i))
+            .collect::<Vec<_>>().join("  "));
+    }
+    let stack = chip8.stack().iter().map(|addr| symbols.annotate(*addr)).collect::<Vec<_>>().join(" ");
+    lines.push(format!("Stack {}", if stack.is_empty() { "-" } else { &stack }));
+    lines
+}
+
+// An error message with the symbol the faulting instruction belongs to, when there is one
+pub fn describe_error(chip8: &Chip8, e: &Chip8Error) -> String {
+    match e.pc().and_then(|pc| chip8.symbols().describe(pc)) {
+        Some(symbol) => format!("{e} (in {symbol})"),
+        None => e.to_string()
+    }
+}
+
+fn opcode_at(chip8: &Chip8, addr: usize) -> Option<u16> {
+    chip8.memory().get(addr..addr + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::rom::Rom;
+
+    const PROGRAM: [u8; 24] = [
+        0x60, 0x00,     // 200: LD V0, 0
+        0x22, 0x0A,     // 202: CALL 0x20A
+        0x70, 0x01,     // 204: ADD V0, 1
+        0x12, 0x02,     // 206: JP 0x202
+        0x00, 0x00,     // 208:
+        0x61, 0x05,     // 20A: LD V1, 5
+        0x22, 0x12,     // 20C: CALL 0x212
+        0x00, 0xEE,     // 20E: RET
+        0x00, 0x00,     // 210:
+        0x62, 0x07,     // 212: LD V2, 7
+        0x00, 0xEE,     // 214: RET
+        0x00, 0x00,     // 216:
+    ];
+
+    fn machine() -> Chip8 { Chip8::new(Rom::new(PROGRAM.to_vec()), &Config::default()).unwrap() }
+
+    // Runs frames until the debugger pauses
+    fn run_to_pause(debugger: &mut Debugger, chip8: &mut Chip8) {
+        for _ in 0..10 {
+            debugger.run_frame(chip8, Keypad::new()).unwrap();
+            if debugger.is_paused() { return }
+        }
+        panic!("the debugger never paused");
+    }
+
+    #[test]
+    fn pauses_at_breakpoints() {
+        let (mut debugger, mut chip8) = (Debugger::new(), machine());
+        debugger.add_breakpoint(Breakpoint::parse("0x204").unwrap());
+        run_to_pause(&mut debugger, &mut chip8);
+        assert_eq!(chip8.pc(), 0x204);
+        assert_eq!(debugger.reason(), "breakpoint at 0x204");
+
+        // Paused machines don't run
+        debugger.run_frame(&mut chip8, Keypad::new()).unwrap();
+        assert_eq!(chip8.pc(), 0x204);
+
+        debugger.toggle_breakpoint(0x204);
+        debugger.add_breakpoint(Breakpoint::parse("0x204:V0==3").unwrap());
+        debugger.resume();
+        run_to_pause(&mut debugger, &mut chip8);
+        assert_eq!((chip8.pc(), chip8.registers()[0]), (0x204, 3));
+        assert_eq!(debugger.reason(), "breakpoint at 0x204 if V0 == 0x03");
+
+        debugger.toggle_breakpoint(0x204);
+        assert!(debugger.breakpoints().is_empty());
+        debugger.resume();
+        for _ in 0..5 { debugger.run_frame(&mut chip8, Keypad::new()).unwrap(); }
+        assert!(!debugger.is_paused());
+    }
+
+    #[test]
+    fn steps_one_instruction() {
+        let (mut debugger, mut chip8) = (Debugger::new(), machine());
+        debugger.pause("started");
+        assert_eq!(debugger.reason(), "started");
+
+        debugger.step(&mut chip8, Keypad::new()).unwrap();
+        assert_eq!(chip8.pc(), 0x202);
+        debugger.step(&mut chip8, Keypad::new()).unwrap();
+        assert_eq!(chip8.pc(), 0x20A);
+        assert_eq!(chip8.stack(), [0x204]);
+        assert!(debugger.is_paused());
+        assert_eq!(debugger.reason(), "stepped");
+    }
+
+    #[test]
+    fn steps_over_calls() {
+        let (mut debugger, mut chip8) = (Debugger::new(), machine());
+        debugger.step(&mut chip8, Keypad::new()).unwrap();
+
+        debugger.step_over(&mut chip8, Keypad::new()).unwrap();
+        assert!(!debugger.is_paused());
+        run_to_pause(&mut debugger, &mut chip8);
+        assert_eq!(chip8.pc(), 0x204);
+        assert!(chip8.stack().is_empty());
+        assert_eq!(chip8.registers()[1..3], [5, 7]);
+        assert_eq!(debugger.reason(), "reached 0x204");
+
+        // Anything but a CALL is a single step
+        debugger.step_over(&mut chip8, Keypad::new()).unwrap();
+        assert_eq!(chip8.pc(), 0x206);
+        assert_eq!(debugger.reason(), "stepped");
+    }
+
+    #[test]
+    fn steps_out_of_subroutines() {
+        let (mut debugger, mut chip8) = (Debugger::new(), machine());
+        for _ in 0..4 { debugger.step(&mut chip8, Keypad::new()).unwrap(); }
+        assert_eq!(chip8.pc(), 0x212);
+        assert_eq!(chip8.stack().len(), 2);
+
+        debugger.step_out(&chip8);
+        run_to_pause(&mut debugger, &mut chip8);
+        assert_eq!(chip8.pc(), 0x20E);
+        assert_eq!(chip8.stack().len(), 1);
+
+        debugger.step_out(&chip8);
+        run_to_pause(&mut debugger, &mut chip8);
+        assert_eq!(chip8.pc(), 0x204);
+        assert_eq!(debugger.reason(), "reached 0x204");
+
+        // With nothing to return from the machine stays paused
+        debugger.step_out(&chip8);
+        assert!(debugger.is_paused());
+    }
+
+    #[test]
+    fn runs_to_an_address() {
+        let (mut debugger, mut chip8) = (Debugger::new(), machine());
+        debugger.pause("started");
+        debugger.run_to(0x214);
+        run_to_pause(&mut debugger, &mut chip8);
+        assert_eq!(chip8.pc(), 0x214);
+        assert_eq!(chip8.registers()[2], 7);
+    }
+
+    #[test]
+    fn parses_breakpoints() {
+        let condition = RegisterCondition { register: 3, comparison: Comparison::Equal, value: 0x10 };
+        assert_eq!(Breakpoint::parse("0x2A4:V3==0x10"), Ok(Breakpoint { addr: 0x2A4, condition: Some(condition) }));
+        assert_eq!(Breakpoint::parse("#2a4"), Ok(Breakpoint { addr: 0x2A4, condition: None }));
+        assert_eq!(Breakpoint::parse("676 : va >= 5").unwrap().condition.unwrap().comparison, Comparison::GreaterOrEqual);
+        assert_eq!(Breakpoint::parse("0x2A4:V3==0x10").unwrap().to_string(), "0x2A4 if V3 == 0x10");
+
+        assert_eq!(Breakpoint::parse("start"), Err(String::from("invalid breakpoint address `start`")));
+        assert_eq!(Breakpoint::parse("0x200:V3=1"), Err(String::from("invalid breakpoint condition `V3=1`")));
+        assert!(Breakpoint::parse("0x200:VG==1").is_err());
+        assert!(Breakpoint::parse("0x200:V3==256").is_err());
+
+        let less = RegisterCondition::parse("v1<5").unwrap();
+        let mut registers = [0; 16];
+        registers[1] = 4;
+        assert!(less.holds(&registers));
+        registers[1] = 5;
+        assert!(!less.holds(&registers));
+        assert!(RegisterCondition::parse("v1<=5").unwrap().holds(&registers));
+    }
+
+    #[test]
+    fn parses_watchpoints() {
+        assert_eq!(Watchpoint::parse("w:0x300-0x30F"), Ok(Watchpoint::Memory { start: 0x300, end: 0x30F, read: false, write: true }));
+        assert_eq!(Watchpoint::parse("r:0x300"), Ok(Watchpoint::Memory { start: 0x300, end: 0x300, read: true, write: false }));
+        assert_eq!(Watchpoint::parse("RW:768-769"), Ok(Watchpoint::Memory { start: 0x300, end: 0x301, read: true, write: true }));
+        assert_eq!(Watchpoint::parse("vf"), Ok(Watchpoint::Register(0xF)));
+
+        for text in ["V10", "x:0x300", "w:0x30F-0x300", "w", "w:"] {
+            assert_eq!(Watchpoint::parse(text), Err(format!("invalid watchpoint `{text}`")));
+        }
+        for text in ["w:0x300-0x30F", "r:0x300", "rw:0x300-0x301", "VF"] {
+            assert_eq!(Watchpoint::parse(text).unwrap().to_string(), text);
+        }
+    }
+
+    const STORES: [u8; 18] = [
+        0xA3, 0x00,     // 200: LD I, 0x300
+        0x60, 0x7B,     // 202: LD V0, 123
+        0xF0, 0x33,     // 204: LD B, V0
+        0xF1, 0x55,     // 206: LD [I], V1
+        0xF1, 0x55,     // 208: LD [I], V1
+        0x60, 0x7B,     // 20A: LD V0, 123
+        0x60, 0x7C,     // 20C: LD V0, 124
+        0xF0, 0x65,     // 20E: LD V0, [I]
+        0x12, 0x10,     // 210: JP 0x210
+    ];
+
+    // The PC and reason of every pause until the program loops
+    fn watch(spec: &str) -> Vec<(usize, String)> {
+        let mut chip8 = Chip8::new(Rom::new(STORES.to_vec()), &Config::default()).unwrap();
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(Watchpoint::parse(spec).unwrap());
+
+        let mut pauses = Vec::new();
+        while chip8.pc() != 0x210 {
+            debugger.run_cycle(&mut chip8, Keypad::new()).unwrap();
+            if debugger.is_paused() {
+                pauses.push((chip8.pc(), String::from(debugger.reason())));
+                debugger.resume();
+            }
+        }
+        pauses
+    }
+
+    #[test]
+    fn memory_watchpoints_report_the_instruction() {
+        assert_eq!(watch("w:0x302"), [(0x206, String::from("write to 0x302 by F033 at 0x204"))]);
+        // Storing the value memory already holds is still a write
+        assert_eq!(watch("w:0x301-0x310"), [
+            (0x206, String::from("write to 0x301 by F033 at 0x204")),
+            (0x208, String::from("write to 0x301 by F155 at 0x206")),
+            (0x20A, String::from("write to 0x301 by F155 at 0x208")),
+        ]);
+        assert_eq!(watch("r:0x300"), [(0x210, String::from("read from 0x300 by F065 at 0x20E"))]);
+        assert_eq!(watch("r:0x303-0x400"), []);
+    }
+
+    #[test]
+    fn register_watchpoints_only_see_changes() {
+        assert_eq!(watch("V0"), [
+            (0x204, String::from("V0 changed 00 -> 7B by 607B at 0x202")),
+            (0x20E, String::from("V0 changed 7B -> 7C by 607C at 0x20C")),
+            (0x210, String::from("V0 changed 7C -> 7B by F065 at 0x20E")),
+        ]);
+        assert_eq!(watch("V1"), []);
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::BTreeSet;
+use crate::config::Platform;
+use crate::symbols::SymbolTable;
+use crate::instruction::{self, ArithmeticLogic, Audio, Display, IRegister, Instruction, Register, Skip, Subroutine, TimerInstruction};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Syntax {
+    Cowgod,     // CLS, LD V0, 0x12, DRW V0, V1, 5 ...
+    Octo        // clear, v0 := 0x12, sprite v0 v1 5 ...
+}
+
+impl Syntax {
+    pub fn parse(name: &str) -> Option<Syntax> {
+        match name.to_ascii_lowercase().as_str() {
+            "cowgod" => Some(Syntax::Cowgod),
+            "octo" => Some(Syntax::Octo),
+            _ => None
+        }
+    }
+
+    fn comment(&self) -> char {
+        match self {
+            Syntax::Cowgod => ';',
+            Syntax::Octo => '#'
+        }
+    }
+}
+
+// What the byte at an address turned out to be while tracing the program
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Byte {
+    Data,
+    Code(usize),    // first byte of an instruction this long
+    Operand
+}
+
+// The instruction at `addr` and its length in bytes, or None when the word there is not a
+// canonical opcode on `platform`
+pub fn instruction_at(memory: &[u8], addr: usize, platform: Platform) -> Option<(Instruction, usize)> {
+    let opcode = word_at(memory, addr)?;
+    let instruction = instruction::decode(opcode, platform).filter(|i| instruction::encode(*i) == opcode)?;
+    match instruction {
+        Instruction::IRegister(IRegister::SetLong) => word_at(memory, addr + 2).map(|_| (instruction, 4)),
+        _ => Some((instruction, 2))
+    }
+}
+
+// One instruction as text, with addresses named by `symbols` where they can be
+pub fn mnemonic(memory: &[u8], addr: usize, platform: Platform, syntax: Syntax, symbols: &SymbolTable) -> Option<String> {
+    let (instruction, _) = instruction_at(memory, addr, platform)?;
+    let long = word_at(memory, addr + 2).unwrap_or(0);
+    let name = |addr: usize| symbols.name(addr).map(str::to_string).unwrap_or_else(|| hex_address(addr));
+    Some(render(instruction, long, syntax, &name))
+}
+
+// Disassembles a program loaded at `offset`. Code is found by following jumps, calls and
+// skips from the entry point; every byte that is never reached is listed as data. Labels
+// take their names from `symbols` when it has one for the address.
+pub fn disassemble(program: &[u8], offset: usize, platform: Platform, syntax: Syntax, symbols: &SymbolTable) -> String {
+    let mut memory = vec![0; offset];
+    memory.extend_from_slice(program);
+    let end = memory.len();
+
+    let mut bytes = vec![Byte::Data; end];
+    let mut targets = BTreeSet::new();
+    let mut pending = vec![offset];
+    while let Some(mut addr) = pending.pop() {
+        while addr >= offset && addr < end && bytes[addr] == Byte::Data {
+            let Some((instruction, length)) = instruction_at(&memory, addr, platform) else { break };
+            if bytes[addr + 1..addr + length].iter().any(|byte| *byte != Byte::Data) { break }
+            bytes[addr] = Byte::Code(length);
+            bytes[addr + 1..addr + length].fill(Byte::Operand);
+
+            let next = addr + length;
+            match instruction {
+                Instruction::Jump(target) | Instruction::JumpOffset(target, _) => {
+                    targets.insert(target);
+                    pending.push(target);
+                    break;
+                },
+                Instruction::Subroutine(Subroutine::Call(target)) => {
+                    targets.insert(target);
+                    pending.push(target);
+                },
+                Instruction::IRegister(IRegister::Set(target)) => { targets.insert(target as usize); },
+                Instruction::IRegister(IRegister::SetLong) => { targets.extend(word_at(&memory, addr + 2).map(usize::from)); },
+                Instruction::Skip(..) => pending.push(next + skip_length(&memory, next, platform)),
+                Instruction::Subroutine(Subroutine::Return) | Instruction::Exit => break,
+                _ => ()
+            }
+            addr = next;
+        }
+    }
+
+    // Only addresses that start a line inside the program can carry a label
+    let labels: BTreeSet<usize> = targets.into_iter()
+        .chain(symbols.iter().map(|(addr, _)| addr))
+        .filter(|addr| *addr >= offset && *addr < end && bytes[*addr] != Byte::Operand)
+        .collect();
+    let name = |addr: usize| match symbols.name(addr) {
+        Some(name) if labels.contains(&addr) => name.to_string(),
+        _ if labels.contains(&addr) => format!("L{addr:03X}"),
+        _ => hex_address(addr)
+    };
+
+    let mut lines = Vec::new();
+    let mut addr = offset;
+    while addr < end {
+        if labels.contains(&addr) {
+            lines.push(match syntax {
+                Syntax::Cowgod => format!("{}:", name(addr)),
+                Syntax::Octo => format!(": {}", name(addr))
+            });
+        }
+
+        let (text, length) = match bytes[addr] {
+            Byte::Code(length) => {
+                let (instruction, _) = instruction_at(&memory, addr, platform).unwrap();
+                let long = word_at(&memory, addr + 2).unwrap_or(0);
+                (render(instruction, long, syntax, &name), length)
+            },
+            _ => {
+                // Data runs until the next instruction or label, eight bytes per line
+                let length = (addr..end).take(8)
+                    .enumerate()
+                    .take_while(|(i, at)| *i == 0 || (bytes[*at] == Byte::Data && !labels.contains(at)))
+                    .count();
+                let values: Vec<String> = memory[addr..addr + length].iter().map(|byte| format!("0x{byte:02X}")).collect();
+                let text = match syntax {
+                    Syntax::Cowgod => format!("db {}", values.join(", ")),
+                    Syntax::Octo => values.join(" ")
+                };
+                (text, length)
+            }
+        };
+        let hex: String = memory[addr..addr + length].iter().map(|byte| format!("{byte:02X}")).collect();
+        lines.push(format!("    {text:<28} {} {addr:03X}  {hex}", syntax.comment()));
+        addr += length;
+    }
+
+    lines.join("\n")
+}
+
+fn hex_address(addr: usize) -> String { format!("0x{addr:03X}") }
+
+fn word_at(memory: &[u8], addr: usize) -> Option<u16> {
+    Some(((*memory.get(addr)? as u16) << 8) | *memory.get(addr + 1)? as u16)
+}
+
+// Skips hop over XO-CHIP's four byte F000 NNNN as a whole
+fn skip_length(memory: &[u8], addr: usize, platform: Platform) -> usize {
+    if platform == Platform::XoChip && word_at(memory, addr) == Some(0xF000) { 4 } else { 2 }
+}
+
+fn render(instruction: Instruction, long: u16, syntax: Syntax, name: &dyn Fn(usize) -> String) -> String {
+    match syntax {
+        Syntax::Cowgod => cowgod(instruction, long, name),
+        Syntax::Octo => octo(instruction, long, name)
+    }
+}
+
+fn cowgod(instruction: Instruction, long: u16, name: &dyn Fn(usize) -> String) -> String {
+    match instruction {
+        Instruction::Display(typ) => match typ {
+            Display::Clear => String::from("CLS"),
+            Display::DrawSprite(x, y, n) => format!("DRW V{x:X}, V{y:X}, {n}"),
+            Display::ScrollDown(n) => format!("SCD {n}"),
+            Display::ScrollUp(n) => format!("SCU {n}"),
+            Display::ScrollLeft => String::from("SCL"),
+            Display::ScrollRight => String::from("SCR"),
+            Display::LowResolution => String::from("LOW"),
+            Display::HighResolution => String::from("HIGH"),
+            Display::SelectPlanes(mask) => format!("PLANE {mask}")
+        },
+        Instruction::Subroutine(Subroutine::Return) => String::from("RET"),
+        Instruction::Subroutine(Subroutine::Call(addr)) => format!("CALL {}", name(addr)),
+        Instruction::Skip(typ, x, value) => match typ {
+            Skip::Equal => format!("SE V{x:X}, 0x{value:02X}"),
+            Skip::NotEqual => format!("SNE V{x:X}, 0x{value:02X}"),
+            Skip::EqualRegister => format!("SE V{x:X}, V{value:X}"),
+            Skip::NotEqualRegister => format!("SNE V{x:X}, V{value:X}"),
+            Skip::KeyPressed => format!("SKP V{x:X}"),
+            Skip::KeyNotPressed => format!("SKNP V{x:X}")
+        },
+        Instruction::ArithmeticLogic(typ, x, y) => {
+            let operation = match typ {
+                ArithmeticLogic::BitwiseOr => "OR",
+                ArithmeticLogic::BitwiseAnd => "AND",
+                ArithmeticLogic::BitwiseXor => "XOR",
+                ArithmeticLogic::AddsWithCarry => "ADD",
+                ArithmeticLogic::SubtractWithBorrow => "SUB",
+                ArithmeticLogic::ShiftRight => "SHR",
+                ArithmeticLogic::SubtractYWithBorrow => "SUBN",
+                ArithmeticLogic::ShiftLeft => "SHL"
+            };
+            format!("{operation} V{x:X}, V{y:X}")
+        },
+        Instruction::Jump(addr) => format!("JP {}", name(addr)),
+        Instruction::JumpOffset(addr, _) => format!("JP V0, {}", name(addr)),
+        Instruction::Register(typ, x, value) => match typ {
+            Register::Set => format!("LD V{x:X}, 0x{value:02X}"),
+            Register::Add => format!("ADD V{x:X}, 0x{value:02X}"),
+            Register::Copy => format!("LD V{x:X}, V{value:X}"),
+            Register::SetToDelayTimer => format!("LD V{x:X}, DT"),
+            Register::StoreInMemory => format!("LD [I], V{x:X}"),
+            Register::ReadFromMemory => format!("LD V{x:X}, [I]"),
+            Register::StoreFlags => format!("LD R, V{x:X}"),
+            Register::ReadFlags => format!("LD V{x:X}, R"),
+            Register::StoreRange => format!("LD [I], V{x:X}-V{value:X}"),
+            Register::ReadRange => format!("LD V{x:X}-V{value:X}, [I]")
+        },
+        Instruction::IRegister(typ) => match typ {
+            IRegister::Set(addr) => format!("LD I, {}", name(addr as usize)),
+            IRegister::AddRegister(x) => format!("ADD I, V{x:X}"),
+            IRegister::SetToLocationSprite(x) => format!("LD F, V{x:X}"),
+            IRegister::SetToLocationBigSprite(x) => format!("LD HF, V{x:X}"),
+            IRegister::SetLong => format!("LD I, LONG {}", name(long as usize))
+        },
+        Instruction::Timer(TimerInstruction::SetDelay, x) => format!("LD DT, V{x:X}"),
+        Instruction::Timer(TimerInstruction::SetSound, x) => format!("LD ST, V{x:X}"),
+        Instruction::RandomByte(x, value) => format!("RND V{x:X}, 0x{value:02X}"),
+        Instruction::StoreBCD(x) => format!("LD B, V{x:X}"),
+        Instruction::WaitKeyPress(x) => format!("LD V{x:X}, K"),
+        Instruction::Audio(Audio::LoadPattern) => String::from("AUDIO"),
+        Instruction::Audio(Audio::SetPitch(x)) => format!("LD PITCH, V{x:X}"),
+        Instruction::Exit => String::from("EXIT")
+    }
+}
+
+// Octo's `if ... then` runs the next instruction when the condition holds, so each skip
+// is written with the opposite condition
+fn octo(instruction: Instruction, long: u16, name: &dyn Fn(usize) -> String) -> String {
+    match instruction {
+        Instruction::Display(typ) => match typ {
+            Display::Clear => String::from("clear"),
+            Display::DrawSprite(x, y, n) => format!("sprite v{x:x} v{y:x} {n}"),
+            Display::ScrollDown(n) => format!("scroll-down {n}"),
+            Display::ScrollUp(n) => format!("scroll-up {n}"),
+            Display::ScrollLeft => String::from("scroll-left"),
+            Display::ScrollRight => String::from("scroll-right"),
+            Display::LowResolution => String::from("lores"),
+            Display::HighResolution => String::from("hires"),
+            Display::SelectPlanes(mask) => format!("plane {mask}")
+        },
+        Instruction::Subroutine(Subroutine::Return) => String::from("return"),
+        Instruction::Subroutine(Subroutine::Call(addr)) => match name(addr) {
+            label if label.starts_with("0x") => format!(":call {label}"),
+            label => label
+        },
+        Instruction::Skip(typ, x, value) => match typ {
+            Skip::Equal => format!("if v{x:x} != 0x{value:02X} then"),
+            Skip::NotEqual => format!("if v{x:x} == 0x{value:02X} then"),
+            Skip::EqualRegister => format!("if v{x:x} != v{value:x} then"),
+            Skip::NotEqualRegister => format!("if v{x:x} == v{value:x} then"),
+            Skip::KeyPressed => format!("if v{x:x} -key then"),
+            Skip::KeyNotPressed => format!("if v{x:x} key then")
+        },
+        Instruction::ArithmeticLogic(typ, x, y) => {
+            let operator = match typ {
+                ArithmeticLogic::BitwiseOr => "|=",
+                ArithmeticLogic::BitwiseAnd => "&=",
+                ArithmeticLogic::BitwiseXor => "^=",
+                ArithmeticLogic::AddsWithCarry => "+=",
+                ArithmeticLogic::SubtractWithBorrow => "-=",
+                ArithmeticLogic::ShiftRight => ">>=",
+                ArithmeticLogic::SubtractYWithBorrow => "=-",
+                ArithmeticLogic::ShiftLeft => "<<="
+            };
+            format!("v{x:x} {operator} v{y:x}")
+        },
+        Instruction::Jump(addr) => format!("jump {}", name(addr)),
+        Instruction::JumpOffset(addr, _) => format!("jump0 {}", name(addr)),
+        Instruction::Register(typ, x, value) => match typ {
+            Register::Set => format!("v{x:x} := 0x{value:02X}"),
+            Register::Add => format!("v{x:x} += 0x{value:02X}"),
+            Register::Copy => format!("v{x:x} := v{value:x}"),
+            Register::SetToDelayTimer => format!("v{x:x} := delay"),
+            Register::StoreInMemory => format!("save v{x:x}"),
+            Register::ReadFromMemory => format!("load v{x:x}"),
+            Register::StoreFlags => format!("saveflags v{x:x}"),
+            Register::ReadFlags => format!("loadflags v{x:x}"),
+            Register::StoreRange => format!("save v{x:x} - v{value:x}"),
+            Register::ReadRange => format!("load v{x:x} - v{value:x}")
+        },
+        Instruction::IRegister(typ) => match typ {
+            IRegister::Set(addr) => format!("i := {}", name(addr as usize)),
+            IRegister::AddRegister(x) => format!("i += v{x:x}"),
+            IRegister::SetToLocationSprite(x) => format!("i := hex v{x:x}"),
+            IRegister::SetToLocationBigSprite(x) => format!("i := bighex v{x:x}"),
+            IRegister::SetLong => format!("i := long {}", name(long as usize))
+        },
+        Instruction::Timer(TimerInstruction::SetDelay, x) => format!("delay := v{x:x}"),
+        Instruction::Timer(TimerInstruction::SetSound, x) => format!("buzzer := v{x:x}"),
+        Instruction::RandomByte(x, value) => format!("v{x:x} := random 0x{value:02X}"),
+        Instruction::StoreBCD(x) => format!("bcd v{x:x}"),
+        Instruction::WaitKeyPress(x) => format!("v{x:x} := key"),
+        Instruction::Audio(Audio::LoadPattern) => String::from("audio"),
+        Instruction::Audio(Audio::SetPitch(x)) => format!("pitch := v{x:x}"),
+        Instruction::Exit => String::from("exit")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    // One of every instruction, laid out so tracing from 0x200 reaches all of them
+    const EVERY_INSTRUCTION: &str = "
+start:  CLS
+        DRW V1, V2, 5
+        SCD 3
+        SCU 2
+        SCL
+        SCR
+        LOW
+        HIGH
+        PLANE 3
+        SE V1, 0x12
+        SNE V2, 0x34
+        SE V3, V4
+        SNE V5, V6
+        SKP V7
+        SKNP V8
+        OR V1, V2
+        AND V3, V4
+        XOR V5, V6
+        ADD V7, V8
+        SUB V9, VA
+        SHR VB, VC
+        SUBN VD, VE
+        SHL VF, V0
+        LD V1, 0x56
+        ADD V2, 0x78
+        LD V3, V4
+        LD V5, DT
+        LD [I], V6
+        LD V7, [I]
+        LD R, V8
+        LD V9, R
+        LD [I], V1-V4
+        LD V5-V2, [I]
+        LD I, data
+        ADD I, VA
+        LD F, VB
+        LD HF, VC
+        SE V0, 0
+        LD I, LONG data
+        LD DT, VD
+        LD ST, VE
+        RND V0, 0x9A
+        LD B, V1
+        LD V2, K
+        AUDIO
+        LD PITCH, V3
+        CALL sub
+        JP V0, next
+next:   JP end
+sub:    RET
+end:    EXIT
+data:   db 0x01, 0x02, 0x03
+";
+
+    // The variant an instruction is, without its operands
+    fn kind(instruction: Instruction) -> String {
+        format!("{instruction:?}").chars().filter(|c| !c.is_ascii_digit()).collect()
+    }
+
+    #[test]
+    fn round_trips_every_instruction() {
+        let assembly = asm::assemble(EVERY_INSTRUCTION, "every.asm", 0x200).unwrap();
+        let listing = disassemble(&assembly.program, 0x200, Platform::XoChip, Syntax::Cowgod, &SymbolTable::new());
+        assert_eq!(listing.matches("db ").count(), 1, "{listing}");
+
+        let reassembled = asm::assemble(&listing, "listing.asm", 0x200).unwrap();
+        assert_eq!(reassembled.program, assembly.program, "{listing}");
+
+        let mut kinds = BTreeSet::new();
+        let mut addr = 0x200;
+        let mut memory = vec![0; 0x200];
+        memory.extend_from_slice(&assembly.program);
+        while let Some((instruction, length)) = instruction_at(&memory, addr, Platform::XoChip) {
+            kinds.insert(kind(instruction));
+            addr += length;
+        }
+        assert_eq!(addr, memory.len() - 3);
+        assert_eq!(kinds.len(), 50, "{kinds:?}");
+    }
+
+    #[test]
+    fn labels_jump_targets_and_uses_symbols() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x206, "done");
+        let listing = disassemble(&[0x22, 0x04, 0x12, 0x06, 0x00, 0xEE, 0x00, 0xFD], 0x200, Platform::SuperChip, Syntax::Cowgod, &symbols);
+        let lines: Vec<&str> = listing.lines().map(|line| line.split(';').next().unwrap().trim()).collect();
+        assert_eq!(lines, ["CALL L204", "JP done", "L204:", "RET", "done:", "EXIT"]);
+    }
+
+    #[test]
+    fn unreachable_bytes_and_unknown_opcodes_are_data() {
+        let listing = disassemble(&[0x12, 0x04, 0xAB, 0xCD, 0xFF, 0xFF], 0x200, Platform::Chip8, Syntax::Cowgod, &SymbolTable::new());
+        let lines: Vec<&str> = listing.lines().map(|line| line.split(';').next().unwrap().trim()).collect();
+        assert_eq!(lines, ["JP L204", "db 0xAB, 0xCD", "L204:", "db 0xFF, 0xFF"]);
+    }
+
+    #[test]
+    fn octo_inverts_skips() {
+        let memory = [0x31, 0x12, 0x91, 0x20, 0xE3, 0x9E];
+        let text = |addr| mnemonic(&memory, addr, Platform::Chip8, Syntax::Octo, &SymbolTable::new()).unwrap();
+        assert_eq!(text(0), "if v1 != 0x12 then");
+        assert_eq!(text(2), "if v1 == v2 then");
+        assert_eq!(text(4), "if v3 -key then");
+    }
+}
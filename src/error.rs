@@ -0,0 +1,65 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Chip8Error {
+    UnknownOpcode { pc: usize, opcode: u16 },
+    StackOverflow { pc: usize },
+    StackUnderflow { pc: usize },
+    MemoryOutOfBounds { pc: usize, addr: usize },
+    PcOutOfBounds { pc: usize },
+    RomTooLarge { size: usize, max: usize },
+    BadSaveState { reason: &'static str },
+    UnsupportedSaveState { version: u16 },
+    SaveStateRomMismatch,
+    BadMovie { reason: &'static str },
+    BadSource { line: usize, message: String },
+    BadCartridge { reason: String },
+    SymbolsRomMismatch,
+    Io(io::Error)
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8Error::UnknownOpcode { pc, opcode } => write!(f, "unknown opcode {opcode:04X} at {pc:#05X}"),
+            Chip8Error::StackOverflow { pc } => write!(f, "stack overflow at {pc:#05X}"),
+            Chip8Error::StackUnderflow { pc } => write!(f, "return with an empty stack at {pc:#05X}"),
+            Chip8Error::MemoryOutOfBounds { pc, addr } => write!(f, "memory access out of bounds ({addr:#05X}) at {pc:#05X}"),
+            Chip8Error::PcOutOfBounds { pc } => write!(f, "program counter ran off the end of memory ({pc:#05X})"),
+            Chip8Error::RomTooLarge { size, max } => write!(f, "ROM is {size} bytes but only {max} bytes fit in memory"),
+            Chip8Error::BadSaveState { reason } => write!(f, "invalid save state: {reason}"),
+            Chip8Error::UnsupportedSaveState { version } => write!(f, "save state version {version} is not supported"),
+            Chip8Error::SaveStateRomMismatch => write!(f, "save state was taken with a different ROM"),
+            Chip8Error::BadMovie { reason } => write!(f, "invalid movie: {reason}"),
+            Chip8Error::BadSource { line, message } => write!(f, "line {line}: {message}"),
+            Chip8Error::BadCartridge { reason } => write!(f, "invalid Octo cartridge: {reason}"),
+            Chip8Error::SymbolsRomMismatch => write!(f, "symbols were written for a different ROM"),
+            Chip8Error::Io(e) => write!(f, "{e}")
+        }
+    }
+}
+
+impl Chip8Error {
+    // Where the program was when emulation failed
+    pub fn pc(&self) -> Option<usize> {
+        match self {
+            Chip8Error::UnknownOpcode { pc, .. } | Chip8Error::StackOverflow { pc } | Chip8Error::StackUnderflow { pc }
+                | Chip8Error::MemoryOutOfBounds { pc, .. } | Chip8Error::PcOutOfBounds { pc } => Some(*pc),
+            _ => None
+        }
+    }
+}
+
+impl std::error::Error for Chip8Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Chip8Error::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Chip8Error {
+    fn from(e: io::Error) -> Chip8Error { Chip8Error::Io(e) }
+}
@@ -0,0 +1,230 @@
+use crate::error::Chip8Error;
+use crate::savestate::{Snapshot, StateReader, StateWriter};
+
+pub struct Framebuffer {
+    planes: [[u128; 64]; 2],
+    selected: u8,
+    hires: bool
+}
+
+impl Framebuffer {
+    pub fn new() -> Framebuffer {
+        Framebuffer {
+            planes: [[0; 64]; 2],
+            selected: 1,
+            hires: false
+        }
+    }
+
+    pub fn width(&self) -> usize { if self.hires { 128 } else { 64 } }
+
+    pub fn height(&self) -> usize { if self.hires { 64 } else { 32 } }
+
+    pub fn is_hires(&self) -> bool { self.hires }
+
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.planes = [[0; 64]; 2];
+    }
+
+    pub fn selected_planes(&self) -> u8 { self.selected }
+
+    pub fn select_planes(&mut self, mask: u8) { self.selected = mask & 0b11; }
+
+    // Indices of the selected planes, in the order sprite data is read for them
+    pub fn selected_plane_indices(&self) -> impl Iterator<Item = usize> {
+        let selected = self.selected;
+        (0..2).filter(move |plane| selected & (1 << plane) != 0)
+    }
+
+    pub fn clear(&mut self) {
+        for plane in self.selected_plane_indices() {
+            self.planes[plane] = [0; 64];
+        }
+    }
+
+    // Palette index of a pixel: bit 0 is plane 1, bit 1 is plane 2
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        let mask = Framebuffer::mask(x);
+        let mut color = 0;
+        for (plane, rows) in self.planes.iter().enumerate() {
+            if rows[y] & mask != 0 { color |= 1 << plane; }
+        }
+        color
+    }
+
+    // Each entry of `sprite` is one row, with its leftmost pixel in bit `width - 1`.
+    // Returns the number of rows in which a lit pixel was turned off.
+    pub fn draw_sprite(&mut self, plane: usize, x: usize, y: usize, sprite: &[u16], width: usize, clip: bool) -> usize {
+        let (screen_width, screen_height) = (self.width(), self.height());
+        let (x, y) = (x % screen_width, y % screen_height);
+        let rows = &mut self.planes[plane];
+        let mut collisions = 0;
+
+        for (row, bits) in sprite.iter().enumerate() {
+            let mut py = y + row;
+            if py >= screen_height {
+                if clip { break }
+                py %= screen_height;
+            }
+
+            let mut collision = false;
+            for col in 0..width {
+                if bits & (1 << (width - 1 - col)) == 0 { continue }
+
+                let mut px = x + col;
+                if px >= screen_width {
+                    if clip { break }
+                    px %= screen_width;
+                }
+
+                let mask = Framebuffer::mask(px);
+                collision |= rows[py] & mask != 0;
+                rows[py] ^= mask;
+            }
+            if collision { collisions += 1; }
+        }
+
+        collisions
+    }
+
+    pub fn scroll_down(&mut self, n: usize) {
+        let height = self.height();
+        let n = n.min(height);
+        for plane in self.selected_plane_indices() {
+            let rows = &mut self.planes[plane];
+            rows.copy_within(0..height - n, n);
+            rows[..n].fill(0);
+        }
+    }
+
+    pub fn scroll_up(&mut self, n: usize) {
+        let height = self.height();
+        let n = n.min(height);
+        for plane in self.selected_plane_indices() {
+            let rows = &mut self.planes[plane];
+            rows.copy_within(n..height, 0);
+            rows[height - n..height].fill(0);
+        }
+    }
+
+    pub fn scroll_left(&mut self, n: usize) {
+        let width_mask = self.width_mask();
+        for plane in self.selected_plane_indices() {
+            for row in self.planes[plane].iter_mut() {
+                *row = (*row << n) & width_mask;
+            }
+        }
+    }
+
+    pub fn scroll_right(&mut self, n: usize) {
+        let width_mask = self.width_mask();
+        for plane in self.selected_plane_indices() {
+            for row in self.planes[plane].iter_mut() {
+                *row = (*row >> n) & width_mask;
+            }
+        }
+    }
+
+    fn width_mask(&self) -> u128 {
+        if self.hires { u128::MAX } else { (u64::MAX as u128) << 64 }
+    }
+
+    // Pixel x lives in bit 127 - x so lores and hires rows share the same left edge
+    fn mask(x: usize) -> u128 { 1 << (127 - x) }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Framebuffer { Framebuffer::new() }
+}
+
+impl Snapshot for Framebuffer {
+    fn save(&self, writer: &mut StateWriter) {
+        for row in self.planes.iter().flatten() { writer.u128(*row); }
+        writer.u8(self.selected);
+        writer.bool(self.hires);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), Chip8Error> {
+        for row in self.planes.iter_mut().flatten() { *row = reader.u128()?; }
+        self.selected = reader.u8()? & 0b11;
+        self.hires = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_pixels(hires: bool, pixels: &[(usize, usize)]) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.set_hires(hires);
+        for &(x, y) in pixels { framebuffer.draw_sprite(0, x, y, &[0x80], 8, true); }
+        framebuffer
+    }
+
+    fn lit(framebuffer: &Framebuffer) -> Vec<(usize, usize)> {
+        let mut pixels = Vec::new();
+        for y in 0..framebuffer.height() {
+            for x in 0..framebuffer.width() {
+                if framebuffer.pixel(x, y) != 0 { pixels.push((x, y)); }
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn switching_resolution_clears_the_screen() {
+        let mut framebuffer = with_pixels(false, &[(63, 31)]);
+        assert_eq!((framebuffer.width(), framebuffer.height()), (64, 32));
+
+        framebuffer.set_hires(true);
+        assert!(framebuffer.is_hires());
+        assert_eq!((framebuffer.width(), framebuffer.height()), (128, 64));
+        assert!(lit(&framebuffer).is_empty());
+    }
+
+    #[test]
+    fn scrolls_vertically_within_the_screen() {
+        for (hires, bottom) in [(false, 31), (true, 63)] {
+            let mut framebuffer = with_pixels(hires, &[(5, 0), (6, bottom)]);
+            framebuffer.scroll_down(3);
+            assert_eq!(lit(&framebuffer), [(5, 3)]);
+            framebuffer.scroll_up(4);
+            assert!(lit(&framebuffer).is_empty());
+
+            let mut framebuffer = with_pixels(hires, &[(5, 0), (6, bottom)]);
+            framebuffer.scroll_up(1);
+            assert_eq!(lit(&framebuffer), [(6, bottom - 1)]);
+            framebuffer.scroll_down(bottom + 1);
+            assert!(lit(&framebuffer).is_empty());
+        }
+    }
+
+    #[test]
+    fn scrolls_horizontally_within_the_screen() {
+        let mut lores = with_pixels(false, &[(2, 0), (61, 1)]);
+        lores.scroll_right(4);
+        assert_eq!(lit(&lores), [(6, 0)]);
+        lores.scroll_left(4);
+        assert_eq!(lit(&lores), [(2, 0)]);
+        lores.scroll_left(4);
+        assert!(lit(&lores).is_empty());
+
+        let mut hires = with_pixels(true, &[(2, 0), (61, 1), (125, 2)]);
+        hires.scroll_right(4);
+        assert_eq!(lit(&hires), [(6, 0), (65, 1)]);
+        hires.scroll_left(4);
+        assert_eq!(lit(&hires), [(2, 0), (61, 1)]);
+    }
+
+    #[test]
+    fn counts_rows_with_collisions() {
+        let mut framebuffer = Framebuffer::new();
+        assert_eq!(framebuffer.draw_sprite(0, 0, 0, &[0xF0, 0x0F, 0xF0], 8, false), 0);
+        assert_eq!(framebuffer.draw_sprite(0, 0, 0, &[0x80, 0x80, 0x80], 8, false), 2);
+        assert_eq!(framebuffer.pixel(0, 0), 0);
+        assert_eq!(framebuffer.pixel(0, 1), 1);
+    }
+}
@@ -0,0 +1,247 @@
+// Just enough of a GIF decoder to read the palette indices of the first image
+
+// Far more than an Octo cartridge needs, and small enough to allocate without a second thought
+const MAX_PIXELS: usize = 4096 * 4096;
+
+pub fn is_gif(bytes: &[u8]) -> bool { bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") }
+
+// Palette indices of the first image, row by row across the whole logical screen
+pub fn first_frame(bytes: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if !is_gif(bytes) { return Err("not a GIF image") }
+    let mut reader = Reader { bytes, at: 6 };
+
+    let width = reader.u16()? as usize;
+    let height = reader.u16()? as usize;
+    if width * height > MAX_PIXELS { return Err("GIF is too large") }
+    let flags = reader.u8()?;
+    let background = reader.u8()?;
+    reader.u8()?;
+    if flags & 0x80 != 0 { reader.skip(3 << ((flags & 7) + 1))?; }
+
+    loop {
+        match reader.u8()? {
+            // Extensions are skipped
+            0x21 => {
+                reader.u8()?;
+                reader.sub_blocks()?;
+            },
+            0x2C => {
+                let left = reader.u16()? as usize;
+                let top = reader.u16()? as usize;
+                let frame_width = reader.u16()? as usize;
+                let frame_height = reader.u16()? as usize;
+                if frame_width * frame_height > MAX_PIXELS { return Err("GIF is too large") }
+                let flags = reader.u8()?;
+                if flags & 0x80 != 0 { reader.skip(3 << ((flags & 7) + 1))?; }
+                let code_size = reader.u8()?;
+                let indices = lzw_decode(&reader.sub_blocks()?, code_size, frame_width * frame_height)?;
+
+                let rows: Vec<usize> = if flags & 0x40 != 0 { interlaced_rows(frame_height) } else { (0..frame_height).collect() };
+                let mut screen = vec![background; width * height];
+                for (row, y) in rows.into_iter().enumerate() {
+                    for x in 0..frame_width {
+                        let (screen_x, screen_y) = (left + x, top + y);
+                        if screen_x < width && screen_y < height {
+                            screen[screen_y * width + screen_x] = indices[row * frame_width + x];
+                        }
+                    }
+                }
+                return Ok(screen);
+            },
+            0x3B => return Err("the GIF has no image"),
+            _ => return Err("unknown GIF block")
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        let byte = *self.bytes.get(self.at).ok_or("GIF is truncated")?;
+        self.at += 1;
+        Ok(byte)
+    }
+
+    fn u16(&mut self) -> Result<u16, &'static str> { Ok(self.u8()? as u16 | (self.u8()? as u16) << 8) }
+
+    fn skip(&mut self, length: usize) -> Result<(), &'static str> {
+        if self.at + length > self.bytes.len() { return Err("GIF is truncated") }
+        self.at += length;
+        Ok(())
+    }
+
+    // Data split into blocks of up to 255 bytes, ending with an empty block
+    fn sub_blocks(&mut self) -> Result<Vec<u8>, &'static str> {
+        let mut data = Vec::new();
+        loop {
+            let length = self.u8()? as usize;
+            if length == 0 { return Ok(data) }
+            let start = self.at;
+            self.skip(length)?;
+            data.extend_from_slice(&self.bytes[start..start + length]);
+        }
+    }
+}
+
+// Interlaced images store every 8th row from 0, every 8th from 4, every 4th from 2, then the odd rows
+fn interlaced_rows(height: usize) -> Vec<usize> {
+    [(0, 8), (4, 8), (2, 4), (1, 2)].iter()
+        .flat_map(|(start, step)| (*start..height).step_by(*step))
+        .collect()
+}
+
+// Variable-width LZW with least significant bit first codes, as used by GIF
+fn lzw_decode(data: &[u8], min_code_size: u8, length: usize) -> Result<Vec<u8>, &'static str> {
+    if !(1..=11).contains(&min_code_size) { return Err("invalid LZW code size") }
+    let clear = 1usize << min_code_size;
+    let end = clear + 1;
+
+    // Each code is a previous code plus one byte; the first `clear` codes are single bytes
+    let mut prefixes: Vec<Option<usize>> = (0..clear).map(|_| None).collect();
+    let mut suffixes: Vec<u8> = (0..clear).map(|byte| byte as u8).collect();
+    let reset = |prefixes: &mut Vec<Option<usize>>, suffixes: &mut Vec<u8>| {
+        prefixes.truncate(clear);
+        suffixes.truncate(clear);
+        // clear and end of information take up two codes
+        prefixes.extend([None, None]);
+        suffixes.extend([0, 0]);
+    };
+    reset(&mut prefixes, &mut suffixes);
+
+    let mut output = Vec::with_capacity(length);
+    let mut code_size = min_code_size as u32 + 1;
+    let mut previous: Option<usize> = None;
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    let mut bytes = data.iter();
+    let mut sequence = Vec::new();
+
+    while output.len() < length {
+        while bits < code_size {
+            let Some(byte) = bytes.next() else { return Err("LZW data is truncated") };
+            buffer |= (*byte as u32) << bits;
+            bits += 8;
+        }
+        let code = (buffer & ((1 << code_size) - 1)) as usize;
+        buffer >>= code_size;
+        bits -= code_size;
+
+        if code == clear {
+            reset(&mut prefixes, &mut suffixes);
+            code_size = min_code_size as u32 + 1;
+            previous = None;
+            continue;
+        }
+        if code == end { break }
+
+        let Some(last) = previous else {
+            if code >= clear { return Err("invalid LZW code") }
+            output.push(code as u8);
+            previous = Some(code);
+            continue;
+        };
+
+        // A code not in the table yet is the previous sequence plus its own first byte
+        let known = code < suffixes.len();
+        if !known && code != suffixes.len() { return Err("invalid LZW code") }
+        sequence.clear();
+        let mut at = Some(if known { code } else { last });
+        while let Some(index) = at {
+            sequence.push(suffixes[index]);
+            at = prefixes[index];
+        }
+        sequence.reverse();
+        let first = sequence[0];
+        if !known { sequence.push(first); }
+        output.extend_from_slice(&sequence);
+
+        if suffixes.len() < 4096 {
+            prefixes.push(Some(last));
+            suffixes.push(first);
+            if suffixes.len() == 1 << code_size && code_size < 12 { code_size += 1; }
+        }
+        previous = Some(code);
+    }
+
+    output.resize(length, 0);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The 10x10 sample from "What's in a GIF": its codes grow from 3 to 6 bits, it starts with
+    // a clear code, ends with an end code and its third code is the one being defined (KwKwK)
+    const SAMPLE: [u8; 69] = [
+        0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x0A, 0x00, 0x0A, 0x00, 0x91, 0x00, 0x00,
+        0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00,
+        0x21, 0xF9, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x2C, 0x00, 0x00, 0x00, 0x00, 0x0A, 0x00, 0x0A, 0x00, 0x00,
+        0x02, 0x16, 0x8C, 0x2D, 0x99, 0x87, 0x2A, 0x1C, 0xDC, 0x33, 0xA0, 0x02, 0x75,
+        0xEC, 0x95, 0xFA, 0xA8, 0xDE, 0x60, 0x8C, 0x04, 0x91, 0x4C, 0x01, 0x00, 0x3B
+    ];
+
+    const SAMPLE_ROWS: [&str; 10] = [
+        "1111122222", "1111122222", "1111122222", "1110000222", "1110000222",
+        "2220000111", "2220000111", "2222211111", "2222211111", "2222211111"
+    ];
+
+    fn rows(screen: &[u8]) -> Vec<String> {
+        screen.chunks(10).map(|row| row.iter().map(|index| char::from(b'0' + index)).collect()).collect()
+    }
+
+    #[test]
+    fn decodes_the_sample() {
+        assert_eq!(rows(&first_frame(&SAMPLE).unwrap()), SAMPLE_ROWS);
+    }
+
+    #[test]
+    fn places_interlaced_rows() {
+        assert_eq!(interlaced_rows(10), [0, 8, 4, 2, 6, 1, 3, 5, 7, 9]);
+
+        let mut gif = SAMPLE;
+        gif[42] |= 0x40;
+        let screen = rows(&first_frame(&gif).unwrap());
+        for (row, y) in interlaced_rows(10).into_iter().enumerate() {
+            assert_eq!(screen[y], SAMPLE_ROWS[row]);
+        }
+    }
+
+    #[test]
+    fn clear_codes_reset_the_table() {
+        // 3-bit codes: clear, 1, 1 (defines 6 as 1 1), clear, 2, 6 (now 2 2), end
+        assert_eq!(lzw_decode(&[0x4C, 0x28, 0x17], 2, 5).unwrap(), [1, 1, 2, 2, 2]);
+        // Output stops at the end code and the rest of the image is index 0
+        assert_eq!(lzw_decode(&[0x4C, 0x28, 0x17], 2, 8).unwrap(), [1, 1, 2, 2, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_bad_codes() {
+        // Clear, then 7, which is neither defined nor the next code
+        assert_eq!(lzw_decode(&[0x3C], 2, 1), Err("invalid LZW code"));
+        assert_eq!(lzw_decode(&[0x00], 12, 1), Err("invalid LZW code size"));
+    }
+
+    #[test]
+    fn rejects_truncated_images() {
+        for length in 0..SAMPLE.len() - 1 {
+            assert!(first_frame(&SAMPLE[..length]).is_err(), "{length} bytes");
+        }
+        assert_eq!(lzw_decode(&[0x4C], 2, 5), Err("LZW data is truncated"));
+    }
+
+    #[test]
+    fn rejects_huge_images() {
+        let mut gif = SAMPLE;
+        gif[6..10].copy_from_slice(&[0xFF; 4]);
+        assert_eq!(first_frame(&gif), Err("GIF is too large"));
+
+        let mut gif = SAMPLE;
+        gif[38..42].copy_from_slice(&[0xFF; 4]);
+        assert_eq!(first_frame(&gif), Err("GIF is too large"));
+    }
+}
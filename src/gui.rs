@@ -1,15 +1,17 @@
 use std::ffi::CString;
-use rfd;
 use std::env;
-use crate::config::Config;
+use std::path::Path;
+use chip_8::config::{Config, Platform, Quirks};
+use chip_8::settings::Settings;
+use chip_8::{Rom, RomDatabase};
 use raylib::prelude::*;
 use raylib::ffi::GuiControl::*;
 use raylib::ffi::GuiControlProperty::*;
 
 const SCREEN_WIDTH: i32 = 690;
-const SCREEN_HEIGHT: i32 = 200;
+const SCREEN_HEIGHT: i32 = 400;
 
-pub fn run() -> (bool, Config){
+pub fn run(settings: &Settings, database: &RomDatabase) -> (bool, Config){
     let (mut raylib, thread) = raylib::init()
         .size(SCREEN_WIDTH, SCREEN_HEIGHT)
         .title("Chip-8 Emulator")
@@ -17,24 +19,42 @@ pub fn run() -> (bool, Config){
     
     raylib.set_target_fps(60);
 
-    let mut rom_path: String = String::new();
-    let mut rom_offset: i32 = 512; // u16
-    let mut cpu_hertz: i32 = 700; // u32
-    let mut delay_timer_hertz: i32 = 60; // u8
-    let mut sound_timer_hertz: i32 = 60; // u8
+    let defaults = settings.config.clone();
+    let mut rom_path: String = defaults.rom_path.clone();
+    let mut rom_offset: i32 = defaults.rom_offset as i32; // u16
+    let mut cpu_hertz: i32 = defaults.cpu_hertz as i32; // u32
+    let mut delay_timer_hertz: i32 = defaults.delay_timer_hertz as i32; // u8
+    let mut sound_timer_hertz: i32 = defaults.sound_timer_hertz as i32; // u8
+    let mut beep_frequency: i32 = defaults.beep_frequency as i32; // u32
+    let mut volume: i32 = (defaults.volume * 100.0) as i32; // percent
+    let mut muted = defaults.muted;
+    let mut quirks_preset: i32 = preset_index(defaults.platform, defaults.quirks).unwrap_or(0);
+    let (mut platform, mut quirks) = (defaults.platform, defaults.quirks);
+    let (mut palette, mut keys) = (defaults.palette, defaults.keys);
+    let mut rom_title = String::new();
+    let quirks_presets = Quirks::PRESETS.iter().map(|(name, _, _)| *name).collect::<Vec<_>>().join(";");
+    let recent_roms = settings.recent_roms.iter()
+        .map(|path| Path::new(path).file_name().map_or(path.clone(), |name| name.to_string_lossy().into_owned()))
+        .collect::<Vec<_>>().join(";");
+    let mut recent_scroll: i32 = 0;
 
     let mut cpu_hertz_flag = false;
     let mut rom_offset_flag = false;
     let mut dt_hertz_flag = false;
     let mut st_hertz_flag = false;
+    let mut beep_frequency_flag = false;
+    let mut volume_flag = false;
     let mut play_flag = false;
     let mut rom_empty = false;
+    // The settings restored for the last ROM already went through its database entry
+    let mut rom_changed = false;
 
     while !raylib.window_should_close() && !play_flag{
         if raylib.is_file_dropped() {
             rom_path = raylib.get_dropped_files().pop().unwrap();
             raylib.clear_dropped_files();
             rom_empty = false;
+            rom_changed = true;
         }
 
         let mut draw = raylib.begin_drawing(&thread);
@@ -50,16 +70,14 @@ pub fn run() -> (bool, Config){
 
         if browse_clicked {
             let option_file = rfd::FileDialog::new()
-                .add_filter("rom", &["ch8"])
-                .set_directory(&env::current_dir().unwrap())
+                .add_filter("rom", &["ch8", "sc8", "xo8", "8o", "gif"])
+                .set_directory(env::current_dir().unwrap())
                 .pick_file();
 
-            match option_file {
-                Some(path_buff) => {
-                    rom_empty = false;
-                    rom_path = String::from(path_buff.to_str().unwrap());
-                }
-                None => ()
+            if let Some(path_buff) = option_file {
+                rom_empty = false;
+                rom_changed = true;
+                rom_path = String::from(path_buff.to_str().unwrap());
             }
         }
 
@@ -69,7 +87,7 @@ pub fn run() -> (bool, Config){
         draw_value_box(&mut draw, 40 , &mut rom_offset, &mut rom_offset_flag, "ROM OFFSET", 0, u16::MAX as i32);
 
         draw.gui_label(rrect(170, 70, 100, 20), Some(&CString::new("How many instruction per second the CPU will execute (normal values 500-1000)").unwrap()));
-        draw_value_box(&mut draw, 70 , &mut cpu_hertz, &mut cpu_hertz_flag, "CPU HERTZ", 0, i32::MAX);
+        draw_value_box(&mut draw, 70 , &mut cpu_hertz, &mut cpu_hertz_flag, "CPU HERTZ", 1, i32::MAX);
 
         draw.gui_label(rrect(170, 100, 100, 20), Some(&CString::new("Count down per second. Used for timing events in games").unwrap()));
         draw_value_box(&mut draw, 100 , &mut delay_timer_hertz, &mut dt_hertz_flag, "DT HERTZ", 0, u8::MAX as i32);
@@ -77,38 +95,110 @@ pub fn run() -> (bool, Config){
         draw.gui_label(rrect(170, 130, 100, 20), Some(&CString::new("Count down per second. Used for sound effects").unwrap()));
         draw_value_box(&mut draw, 130 , &mut sound_timer_hertz, &mut st_hertz_flag, "ST HERTZ", 0, u8::MAX as i32);
 
+        draw.gui_label(rrect(170, 160, 100, 20), Some(&CString::new("Pitch of the beep played while the sound timer runs").unwrap()));
+        draw_value_box(&mut draw, 160 , &mut beep_frequency, &mut beep_frequency_flag, "BEEP HERTZ", 20, 20000);
+
+        draw.gui_label(rrect(170, 190, 100, 20), Some(&CString::new("Beep volume in percent").unwrap()));
+        draw_value_box(&mut draw, 190 , &mut volume, &mut volume_flag, "VOLUME", 0, 100);
+        muted = draw.gui_check_box(rrect(350, 190, 20, 20), Some(&CString::new("Mute (M)").unwrap()), muted);
+
+        draw.gui_label(rrect(10, 220, 100, 20), Some(&CString::new("QUIRKS").unwrap()));
+        let selected_preset = draw.gui_combo_box(rrect(90, 220, 130, 20), Some(&CString::new(quirks_presets.as_str()).unwrap()), quirks_preset);
+        if selected_preset != quirks_preset {
+            quirks_preset = selected_preset;
+            (_, platform, quirks) = Quirks::PRESETS[quirks_preset as usize];
+        }
+        draw.gui_label(rrect(230, 220, 100, 20), Some(&CString::new("Platform the ROM was written for").unwrap()));
+
         // Play Button
 
-        if draw.gui_button(rrect(10, 160, 70, 20), Some(&CString::new("Play").unwrap())){
-            if rom_path != "" { play_flag = true; }
+        if draw.gui_button(rrect(10, 250, 70, 20), Some(&CString::new("Play").unwrap())){
+            if !rom_path.is_empty() { play_flag = true; }
             else {
                 rom_empty = true;
             }
         }
 
         if rom_empty {
-            draw.gui_label(rrect(90, 160, 100, 20), Some(&CString::new("Please select a ROM").unwrap()));
+            draw.gui_label(rrect(90, 250, 100, 20), Some(&CString::new("Please select a ROM").unwrap()));
+        } else if !rom_title.is_empty() {
+            draw.gui_label(rrect(90, 250, 100, 20), Some(&CString::new(format!("Settings for {rom_title} loaded from the ROM database")).unwrap()));
         }
 
         // GitHub
 
         let image = draw.gui_icon_text(guiIconName::RICON_HEART, None);
-        draw.gui_label(rrect(620, 160, 100, 20), Some(&CString::new("Github").unwrap()));
-        if draw.gui_button(rrect(660, 160, 20, 20), Some(&CString::new(image.as_str()).unwrap())) {
+        draw.gui_label(rrect(620, 250, 100, 20), Some(&CString::new("Github").unwrap()));
+        if draw.gui_button(rrect(660, 250, 20, 20), Some(&CString::new(image.as_str()).unwrap())) {
             open_url("https://github.com/Diego-Avila-Acosta");
         }
 
+        // Recent ROMs
+
+        draw.gui_label(rrect(10, 280, 100, 20), Some(&CString::new("RECENT ROMS").unwrap()));
+        let recent = draw.gui_list_view(rrect(90, 280, 590, 110), Some(&CString::new(recent_roms.as_str()).unwrap()), &mut recent_scroll, -1);
+        if let Some(path) = usize::try_from(recent).ok().and_then(|index| settings.recent_roms.get(index)) {
+            rom_path = path.clone();
+            rom_changed = true;
+            play_flag = true;
+        }
+
+        // Per-ROM settings from the database
+        if rom_changed {
+            rom_changed = false;
+            rom_title.clear();
+            let current = Config { rom_offset: rom_offset as u16, cpu_hertz: cpu_hertz as u32, platform, quirks, palette, keys, ..defaults.clone() };
+            if let Some((title, rom_config)) = rom_config(database, &rom_path, current) {
+                rom_title = title;
+                rom_offset = rom_config.rom_offset as i32;
+                cpu_hertz = rom_config.cpu_hertz as i32;
+                (platform, quirks) = (rom_config.platform, rom_config.quirks);
+                (palette, keys) = (rom_config.palette, rom_config.keys);
+                quirks_preset = preset_index(platform, quirks)
+                    .or(Quirks::PRESETS.iter().position(|(_, preset, _)| *preset == platform).map(|index| index as i32))
+                    .unwrap_or(quirks_preset);
+            }
+        }
     }
 
     (play_flag, Config {
         rom_path,
         rom_offset: rom_offset as u16,
-        cpu_hertz: cpu_hertz as u32,
+        cpu_hertz: cpu_hertz.max(1) as u32,
         delay_timer_hertz: delay_timer_hertz as u8,
-        sound_timer_hertz: sound_timer_hertz as u8
+        sound_timer_hertz: sound_timer_hertz as u8,
+        platform,
+        quirks,
+        palette,
+        keys,
+        beep_frequency: beep_frequency as u32,
+        volume: volume as f32 / 100.0,
+        muted,
+        ..defaults
     })
 }
 
+fn preset_index(platform: Platform, quirks: Quirks) -> Option<i32> {
+    Quirks::PRESETS.iter()
+        .position(|(_, preset_platform, preset_quirks)| *preset_platform == platform && *preset_quirks == quirks)
+        .map(|index| index as i32)
+}
+
+// `config` with the database entry for the ROM at `path` and then the settings the ROM carries
+// itself applied, and its title; None when neither has anything, so the launcher keeps what it shows
+fn rom_config(database: &RomDatabase, path: &str, mut config: Config) -> Option<(String, Config)> {
+    let rom = Rom::read_rom(path).ok()?;
+    let hash = rom.sha1();
+    let settings = database.lookup(&hash).merge(&rom.settings);
+    if settings.is_empty() && rom.origin.is_none() { return None }
+
+    settings.apply(&mut config);
+    config.rom_offset = rom.load_address(&config);
+
+    let title = database.entry(&hash).map(|entry| entry.title.clone()).unwrap_or_default();
+    Some((title, config))
+}
+
 fn draw_value_box(draw: &mut RaylibDrawHandle, y: i32, value: &mut i32, flag: &mut bool, text: &str, min: i32, max: i32){
     draw.gui_label(rrect(10, y, 100, 20), Some(&CString::new(text).unwrap()));
 
@@ -0,0 +1,303 @@
+use crate::config::Platform;
+
+// Decoded opcodes. Operands name registers by index, so decoding does not depend on the
+// machine state and is shared by the interpreter, the debugger and the disassembler.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArithmeticLogic {
+    BitwiseOr,
+    BitwiseAnd,
+    BitwiseXor,
+    AddsWithCarry,
+    SubtractWithBorrow,
+    SubtractYWithBorrow,
+    ShiftLeft,
+    ShiftRight,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Display {
+    Clear,
+    DrawSprite(usize, usize, u8),
+    ScrollDown(u8),
+    ScrollUp(u8),
+    ScrollLeft,
+    ScrollRight,
+    LowResolution,
+    HighResolution,
+    SelectPlanes(u8)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Subroutine {
+    Return,
+    Call(usize)
+}
+
+// Equal and NotEqual compare VX with a byte, EqualRegister and NotEqualRegister with VY
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Skip {
+    Equal,
+    NotEqual,
+    EqualRegister,
+    NotEqualRegister,
+    KeyPressed,
+    KeyNotPressed
+}
+
+// Set and Add take a byte; Copy, StoreRange and ReadRange take the index of VY
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Register {
+    Set,
+    Add,
+    Copy,
+    SetToDelayTimer,
+    StoreInMemory,
+    ReadFromMemory,
+    StoreFlags,
+    ReadFlags,
+    StoreRange,
+    ReadRange
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimerInstruction {
+    SetDelay,
+    SetSound
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IRegister {
+    Set(u16),
+    AddRegister(usize),
+    SetToLocationSprite(usize),
+    SetToLocationBigSprite(usize),
+    SetLong
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Audio {
+    LoadPattern,
+    SetPitch(usize)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Instruction {
+    Display(Display),
+    Subroutine(Subroutine),
+    Skip(Skip, usize, u8),
+    ArithmeticLogic(ArithmeticLogic, usize, usize),
+    Jump(usize),
+    JumpOffset(usize, usize),   // BNNN: NNN plus V0, or VX with the jump quirk
+    Register(Register, usize, u8),
+    IRegister(IRegister),
+    Timer(TimerInstruction, usize),
+    RandomByte(usize, u8),
+    StoreBCD(usize),
+    WaitKeyPress(usize),
+    Audio(Audio),
+    Exit
+}
+
+pub fn decode(instruction: u16, platform: Platform) -> Option<Instruction> {
+    let i = (0xF000 & instruction) >> 12;
+    let x = ((0x0F00 & instruction) >> 8) as usize;
+    let y = ((0x00F0 & instruction) >> 4) as usize;
+    let n = (0x000F & instruction) as u8;
+    let nn = (0x00FF & instruction) as u8;
+    let nnn = 0x0FFF & instruction;
+    let super_chip = platform != Platform::Chip8;
+    let xo_chip = platform == Platform::XoChip;
+
+    Some(match i {
+        // 0NNN machine code routines are not supported, so the whole address has to match
+        0x0 => {
+            match nnn {
+                0x0E0 => Instruction::Display(Display::Clear),
+                0x0EE => Instruction::Subroutine(Subroutine::Return),
+                0x0C0..=0x0CF if super_chip => Instruction::Display(Display::ScrollDown(n)),
+                0x0D0..=0x0DF if xo_chip => Instruction::Display(Display::ScrollUp(n)),
+                0x0FB if super_chip => Instruction::Display(Display::ScrollRight),
+                0x0FC if super_chip => Instruction::Display(Display::ScrollLeft),
+                0x0FD if super_chip => Instruction::Exit,
+                0x0FE if super_chip => Instruction::Display(Display::LowResolution),
+                0x0FF if super_chip => Instruction::Display(Display::HighResolution),
+                _ => return None
+            }
+        },
+        0x1 => Instruction::Jump(nnn as usize),
+        0x2 => Instruction::Subroutine(Subroutine::Call(nnn as usize)),
+        0x3 => Instruction::Skip(Skip::Equal, x, nn),
+        0x4 => Instruction::Skip(Skip::NotEqual, x, nn),
+        0x5 => {
+            match n {
+                0x0 => Instruction::Skip(Skip::EqualRegister, x, y as u8),
+                0x2 if xo_chip => Instruction::Register(Register::StoreRange, x, y as u8),
+                0x3 if xo_chip => Instruction::Register(Register::ReadRange, x, y as u8),
+                _ => return None
+            }
+        },
+        0x6 => Instruction::Register(Register::Set, x, nn),
+        0x7 => Instruction::Register(Register::Add, x, nn),
+        0x8 => {
+            match n {
+                0x0 => Instruction::Register(Register::Copy, x, y as u8),
+                0x1 => Instruction::ArithmeticLogic(ArithmeticLogic::BitwiseOr, x, y),
+                0x2 => Instruction::ArithmeticLogic(ArithmeticLogic::BitwiseAnd, x, y),
+                0x3 => Instruction::ArithmeticLogic(ArithmeticLogic::BitwiseXor, x, y),
+                0x4 => Instruction::ArithmeticLogic(ArithmeticLogic::AddsWithCarry, x, y),
+                0x5 => Instruction::ArithmeticLogic(ArithmeticLogic::SubtractWithBorrow, x, y),
+                0x6 => Instruction::ArithmeticLogic(ArithmeticLogic::ShiftRight, x, y),
+                0x7 => Instruction::ArithmeticLogic(ArithmeticLogic::SubtractYWithBorrow, x, y),
+                0xE => Instruction::ArithmeticLogic(ArithmeticLogic::ShiftLeft, x, y),
+                _ => return None
+            }
+        },
+        0x9 if n == 0 => Instruction::Skip(Skip::NotEqualRegister, x, y as u8),
+        0xA => Instruction::IRegister(IRegister::Set(nnn)),
+        0xB => Instruction::JumpOffset(nnn as usize, x),
+        0xC => Instruction::RandomByte(x, nn),
+        0xD => Instruction::Display(Display::DrawSprite(x, y, n)),
+        0xE => {
+            match nn {
+                0x9E => Instruction::Skip(Skip::KeyPressed, x, 0),
+                0xA1 => Instruction::Skip(Skip::KeyNotPressed, x, 0),
+                _ => return None
+            }
+        },
+        0xF => {
+            match nn {
+                0x00 if xo_chip && x == 0 => Instruction::IRegister(IRegister::SetLong),
+                0x01 if xo_chip => Instruction::Display(Display::SelectPlanes(x as u8)),
+                0x02 if xo_chip && x == 0 => Instruction::Audio(Audio::LoadPattern),
+                0x07 => Instruction::Register(Register::SetToDelayTimer, x, 0),
+                0x0A => Instruction::WaitKeyPress(x),
+                0x15 => Instruction::Timer(TimerInstruction::SetDelay, x),
+                0x18 => Instruction::Timer(TimerInstruction::SetSound, x),
+                0x1E => Instruction::IRegister(IRegister::AddRegister(x)),
+                0x29 => Instruction::IRegister(IRegister::SetToLocationSprite(x)),
+                0x30 if super_chip => Instruction::IRegister(IRegister::SetToLocationBigSprite(x)),
+                0x33 => Instruction::StoreBCD(x),
+                0x3A if xo_chip => Instruction::Audio(Audio::SetPitch(x)),
+                0x55 => Instruction::Register(Register::StoreInMemory, x, 0),
+                0x65 => Instruction::Register(Register::ReadFromMemory, x, 0),
+                0x75 if super_chip => Instruction::Register(Register::StoreFlags, x, 0),
+                0x85 if super_chip => Instruction::Register(Register::ReadFlags, x, 0),
+                _ => return None
+            }
+        },
+        _ => return None
+    })
+}
+
+// The opcode of an instruction; every opcode `decode` accepts encodes back to itself
+pub fn encode(instruction: Instruction) -> u16 {
+    let xy = |x: usize, y: usize| ((x as u16) << 8) | ((y as u16) << 4);
+    let xnn = |x: usize, nn: u8| ((x as u16) << 8) | nn as u16;
+
+    match instruction {
+        Instruction::Display(typ) => match typ {
+            Display::Clear => 0x00E0,
+            Display::DrawSprite(x, y, n) => 0xD000 | xy(x, y) | n as u16,
+            Display::ScrollDown(n) => 0x00C0 | n as u16,
+            Display::ScrollUp(n) => 0x00D0 | n as u16,
+            Display::ScrollLeft => 0x00FC,
+            Display::ScrollRight => 0x00FB,
+            Display::LowResolution => 0x00FE,
+            Display::HighResolution => 0x00FF,
+            Display::SelectPlanes(mask) => 0xF001 | xy(mask as usize, 0)
+        },
+        Instruction::Subroutine(Subroutine::Return) => 0x00EE,
+        Instruction::Subroutine(Subroutine::Call(addr)) => 0x2000 | addr as u16,
+        Instruction::Skip(typ, x, value) => match typ {
+            Skip::Equal => 0x3000 | xnn(x, value),
+            Skip::NotEqual => 0x4000 | xnn(x, value),
+            Skip::EqualRegister => 0x5000 | xy(x, value as usize),
+            Skip::NotEqualRegister => 0x9000 | xy(x, value as usize),
+            Skip::KeyPressed => 0xE09E | xy(x, 0),
+            Skip::KeyNotPressed => 0xE0A1 | xy(x, 0)
+        },
+        Instruction::ArithmeticLogic(typ, x, y) => 0x8000 | xy(x, y) | match typ {
+            ArithmeticLogic::BitwiseOr => 0x1,
+            ArithmeticLogic::BitwiseAnd => 0x2,
+            ArithmeticLogic::BitwiseXor => 0x3,
+            ArithmeticLogic::AddsWithCarry => 0x4,
+            ArithmeticLogic::SubtractWithBorrow => 0x5,
+            ArithmeticLogic::ShiftRight => 0x6,
+            ArithmeticLogic::SubtractYWithBorrow => 0x7,
+            ArithmeticLogic::ShiftLeft => 0xE
+        },
+        Instruction::Jump(addr) => 0x1000 | addr as u16,
+        Instruction::JumpOffset(addr, _) => 0xB000 | addr as u16,
+        Instruction::Register(typ, x, value) => match typ {
+            Register::Set => 0x6000 | xnn(x, value),
+            Register::Add => 0x7000 | xnn(x, value),
+            Register::Copy => 0x8000 | xy(x, value as usize),
+            Register::SetToDelayTimer => 0xF007 | xy(x, 0),
+            Register::StoreInMemory => 0xF055 | xy(x, 0),
+            Register::ReadFromMemory => 0xF065 | xy(x, 0),
+            Register::StoreFlags => 0xF075 | xy(x, 0),
+            Register::ReadFlags => 0xF085 | xy(x, 0),
+            Register::StoreRange => 0x5002 | xy(x, value as usize),
+            Register::ReadRange => 0x5003 | xy(x, value as usize)
+        },
+        Instruction::IRegister(typ) => match typ {
+            IRegister::Set(nnn) => 0xA000 | nnn,
+            IRegister::AddRegister(x) => 0xF01E | xy(x, 0),
+            IRegister::SetToLocationSprite(x) => 0xF029 | xy(x, 0),
+            IRegister::SetToLocationBigSprite(x) => 0xF030 | xy(x, 0),
+            IRegister::SetLong => 0xF000
+        },
+        Instruction::Timer(TimerInstruction::SetDelay, x) => 0xF015 | xy(x, 0),
+        Instruction::Timer(TimerInstruction::SetSound, x) => 0xF018 | xy(x, 0),
+        Instruction::RandomByte(x, nn) => 0xC000 | xnn(x, nn),
+        Instruction::StoreBCD(x) => 0xF033 | xy(x, 0),
+        Instruction::WaitKeyPress(x) => 0xF00A | xy(x, 0),
+        Instruction::Audio(Audio::LoadPattern) => 0xF002,
+        Instruction::Audio(Audio::SetPitch(x)) => 0xF03A | xy(x, 0),
+        Instruction::Exit => 0x00FD
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_instructions_need_the_whole_address() {
+        assert_eq!(decode(0x00E0, Platform::Chip8), Some(Instruction::Display(Display::Clear)));
+        assert_eq!(decode(0x00EE, Platform::Chip8), Some(Instruction::Subroutine(Subroutine::Return)));
+        assert_eq!(decode(0x01E0, Platform::Chip8), None);
+        assert_eq!(decode(0x0AEE, Platform::Chip8), None);
+        assert_eq!(decode(0x00C4, Platform::SuperChip), Some(Instruction::Display(Display::ScrollDown(4))));
+        assert_eq!(decode(0x01C4, Platform::SuperChip), None);
+        assert_eq!(decode(0x02FF, Platform::SuperChip), None);
+        assert_eq!(decode(0x0FD3, Platform::XoChip), None);
+    }
+
+    #[test]
+    fn platform_instructions_need_their_platform() {
+        assert_eq!(decode(0x00FF, Platform::Chip8), None);
+        assert_eq!(decode(0x00FF, Platform::SuperChip), Some(Instruction::Display(Display::HighResolution)));
+        assert_eq!(decode(0xF000, Platform::SuperChip), None);
+        assert_eq!(decode(0xF000, Platform::XoChip), Some(Instruction::IRegister(IRegister::SetLong)));
+    }
+
+    #[test]
+    fn register_skips_need_a_zero_low_nibble() {
+        assert_eq!(decode(0x9120, Platform::Chip8), Some(Instruction::Skip(Skip::NotEqualRegister, 1, 2)));
+        assert_eq!(decode(0x9121, Platform::Chip8), None);
+        assert_eq!(decode(0x912F, Platform::XoChip), None);
+        assert_eq!(decode(0x5121, Platform::Chip8), None);
+    }
+
+    #[test]
+    fn every_decoded_opcode_encodes_back() {
+        for platform in [Platform::Chip8, Platform::SuperChip, Platform::XoChip] {
+            for opcode in 0..=u16::MAX {
+                if let Some(instruction) = decode(opcode, platform) {
+                    assert_eq!(encode(instruction), opcode, "{opcode:04X} on {platform:?}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,22 @@
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Keypad {
+    pressed: u16
+}
+
+impl Keypad {
+    pub fn new() -> Keypad { Keypad { pressed: 0 } }
+
+    pub fn from_bits(bits: u16) -> Keypad { Keypad { pressed: bits } }
+
+    pub fn bits(&self) -> u16 { self.pressed }
+
+    pub fn press(&mut self, key: u8) { self.pressed |= 1 << (key & 0xF); }
+
+    pub fn release(&mut self, key: u8) { self.pressed &= !(1 << (key & 0xF)); }
+
+    pub fn is_pressed(&self, key: u8) -> bool { self.pressed & (1 << (key & 0xF)) != 0 }
+
+    pub fn first_pressed(&self) -> Option<u8> {
+        if self.pressed == 0 { None } else { Some(self.pressed.trailing_zeros() as u8) }
+    }
+}
@@ -0,0 +1,34 @@
+pub mod stack;
+pub mod chip;
+pub mod rom;
+pub mod timer;
+pub mod config;
+pub mod error;
+pub mod framebuffer;
+pub mod audio;
+pub mod keypad;
+pub mod settings;
+pub mod database;
+pub mod savestate;
+pub mod rewind;
+pub mod movie;
+pub mod rng;
+pub mod debugger;
+pub mod instruction;
+pub mod disasm;
+pub mod asm;
+pub mod octo;
+pub mod symbols;
+pub mod gif;
+pub mod cartridge;
+pub mod trace;
+pub mod number;
+
+pub use chip::Chip8;
+pub use rom::Rom;
+pub use config::{Config, Platform, Quirks};
+pub use error::Chip8Error;
+pub use framebuffer::Framebuffer;
+pub use keypad::Keypad;
+pub use settings::Settings;
+pub use database::{RomDatabase, RomSettings};
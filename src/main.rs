@@ -1,83 +1,243 @@
-use chip::Chip8;
-use rom::Rom;
-use raylib::prelude::*;
-use bit::BitIndex;
-use std::time::{Instant, Duration};
-use spin_sleep::sleep;
-use config::Config;
-
-pub mod stack;
-pub mod chip;
-pub mod rom;
-pub mod timer;
-pub mod config;
-pub mod gui;
-
-static mut DELTA_TIME: f64= 0.0;
+use chip_8::{Chip8, Config, Keypad, Rom, RomDatabase};
+use chip_8::debugger::{self, Debugger};
+use chip_8::{asm, disasm};
+use chip_8::movie::Movie;
+use chip_8::symbols::{symbol_path, SymbolTable};
+use chip_8::trace::Tracer;
+use cli::{AsmOptions, Command, DisasmOptions, RunOptions};
+use std::path::Path;
+use std::process::exit;
+
+mod cli;
+#[cfg(feature = "gui")]
+mod gui;
+#[cfg(feature = "gui")]
+mod window;
 
 fn main() {
-    let (play_flag, config): (bool, Config)= gui::run();
+    let RunOptions { mut config, rom_settings, headless, frames, record, replay, debug, breakpoints, watchpoints, symbols, trace } = match cli::parse(std::env::args().skip(1)) {
+        Ok(Command::Run(options)) => *options,
+        Ok(Command::Launcher) => match launcher() {
+            Some(config) => RunOptions {
+                config, rom_settings: None, headless: false, frames: 0, record: None, replay: None, debug: false,
+                breakpoints: Vec::new(), watchpoints: Vec::new(), symbols: None, trace: None
+            },
+            None => return
+        },
+        Ok(Command::Disasm(options)) => return disasm(options),
+        Ok(Command::Asm(options)) => return assemble(options),
+        Ok(Command::Help) => return println!("{}", cli::USAGE),
+        Err(e) => {
+            eprintln!("{e}\n\n{}", cli::USAGE);
+            exit(2);
+        }
+    };
 
-    if !play_flag { return }
-    
-    let rom = Rom::read_rom(&config.rom_path);
-    let mut chip8 = Chip8::new(rom, &config);
+    let mut rom = match Rom::read_rom(&config.rom_path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("Could not load {}: {e}", config.rom_path);
+            exit(1);
+        }
+    };
+    // The launcher already applied these; a cartridge's own settings win over the database and
+    // explicit options on the command line win over both
+    if let Some(rom_settings) = rom_settings {
+        load_database().lookup(&rom.sha1()).merge(&rom.settings).apply(&mut config);
+        rom_settings.apply(&mut config);
+    }
 
-    let (mut raylib_handler, raylib_thread_handler) = raylib::init()
-    .size(640, 320)
-    .build();
+    load_symbols(&mut rom, symbols.as_deref(), &config.rom_path);
 
-    let cycle = 1.0_f64 / config.cpu_hertz as f64;
-    raylib_handler.set_target_fps(config.cpu_hertz);
-    
-    while !raylib_handler.window_should_close() {
-        let now = Instant::now();
-        let key_pressed = is_key_down(&mut raylib_handler);
+    // A movie replays with the settings it was recorded with
+    let replay = replay.map(|path| match Movie::read(&path) {
+        Ok(movie) if movie.rom_hash == rom.digest() => movie,
+        Ok(_) => {
+            eprintln!("{path} was recorded with a different ROM");
+            exit(1);
+        },
+        Err(e) => {
+            eprintln!("Could not load {path}: {e}");
+            exit(1);
+        }
+    });
+    if let Some(movie) = &replay { movie.apply(&mut config); }
 
-        chip8.run_cycle(key_pressed);
+    let mut chip8 = match Chip8::new(rom, &config) {
+        Ok(chip8) => chip8,
+        Err(e) => {
+            eprintln!("Could not load {}: {e}", config.rom_path);
+            exit(1);
+        }
+    };
 
-        if chip8.draw_flag { 
-            draw(&mut raylib_handler, &raylib_thread_handler, &chip8); 
-            chip8.draw_flag = false;
+    if let Some((path, options)) = trace {
+        match Tracer::create(&path, options) {
+            Ok(tracer) => chip8.set_tracer(Some(tracer)),
+            Err(e) => {
+                eprintln!("Could not create {path}: {e}");
+                exit(1);
+            }
         }
+    }
+
+    let recording = record.map(|path| (path, Movie::new(&config, &chip8)));
+    let mut debugger = Debugger::new();
+    for breakpoint in breakpoints { debugger.add_breakpoint(breakpoint); }
+    for watchpoint in watchpoints { debugger.add_watchpoint(watchpoint); }
+    if debug { debugger.pause("started"); }
+
+    if headless { run_headless(chip8, frames, recording, replay, debugger) } else { run_window(chip8, &config, recording, replay, debugger) }
+}
 
-        if let Some(dur) = Duration::from_secs_f64(cycle).checked_sub(now.elapsed()){
-            sleep(dur);
+fn disasm(DisasmOptions { rom_path, rom_settings, syntax, symbols }: DisasmOptions) {
+    let mut rom = match Rom::read_rom(&rom_path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("Could not load {rom_path}: {e}");
+            exit(1);
         }
+    };
+    let mut config = Config::default();
+    load_database().lookup(&rom.sha1()).merge(&rom.settings).apply(&mut config);
+    rom_settings.apply(&mut config);
+    load_symbols(&mut rom, symbols.as_deref(), &rom_path);
 
-        unsafe { DELTA_TIME = now.elapsed().as_secs_f64(); }
+    println!("{}", disasm::disassemble(&rom.program, rom.load_address(&config) as usize, config.platform, syntax, &rom.symbols));
+}
+
+fn load_database() -> RomDatabase {
+    let (database, warnings) = RomDatabase::load();
+    for warning in warnings { eprintln!("{warning}"); }
+    database
+}
+
+// An explicit symbol file has to load; the one beside the ROM is only used when it fits
+fn load_symbols(rom: &mut Rom, path: Option<&str>, rom_path: &str) {
+    let default = symbol_path(rom_path);
+    let (path, explicit) = match path {
+        Some(path) => (Path::new(path), true),
+        None if default.exists() => (default.as_path(), false),
+        None => return
+    };
+    match SymbolTable::read(path, &rom.sha1()) {
+        Ok(symbols) => rom.symbols.extend(&symbols),
+        Err(e) if explicit => {
+            eprintln!("Could not load {}: {e}", path.display());
+            exit(1);
+        },
+        Err(e) => eprintln!("Ignoring {}: {e}", path.display())
     }
 }
 
-fn draw(raylib_handler: &mut RaylibHandle, raylib_thread_handler: &RaylibThread, chip8: &Chip8){
-    let mut draw_handler = raylib_handler.begin_drawing(raylib_thread_handler);
-    draw_handler.clear_background(Color::BLACK);
+fn assemble(AsmOptions { source, output, offset }: AsmOptions) {
+    let assembly = match asm::assemble_file(&source, offset) {
+        Ok(assembly) => assembly,
+        Err(e) => {
+            eprintln!("{e}");
+            exit(1);
+        }
+    };
+    if let Err(e) = std::fs::write(&output, &assembly.program) {
+        eprintln!("Could not save {output}: {e}");
+        exit(1);
+    }
+    eprintln!("Wrote {output} ({} bytes)", assembly.program.len());
 
-    for (y, row) in chip8.display.iter().enumerate(){
-        for (x ,i) in (0..64).rev().enumerate(){
-            if row.bit(i) {
-                draw_handler.draw_rectangle(x as i32 * 10, y as i32 * 10, 10, 10, Color::WHITE);
-            }
+    // Tie the labels to the ROM so the emulator and disassembler can name addresses
+    if !assembly.symbols.is_empty() {
+        let rom = Rom::new(assembly.program.clone());
+        let path = symbol_path(&output);
+        if let Err(e) = assembly.symbols.write(&path, &rom.sha1()) {
+            eprintln!("Could not save {}: {e}", path.display());
+            exit(1);
         }
+        eprintln!("Wrote {} ({} symbols)", path.display(), assembly.symbols.len());
     }
 }
 
-fn is_key_down(raylib_handler: &mut RaylibHandle) -> Option<u8> {
-    if raylib_handler.is_key_down(KeyboardKey::KEY_ONE) { return Some(0) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_TWO) { return Some(1) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_THREE) { return Some(2) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_FOUR) { return Some(3) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_Q) { return Some(4) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_W) { return Some(5) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_E) { return Some(6) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_R) { return Some(7) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_A) { return Some(8) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_S) { return Some(9) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_D) { return Some(10) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_F) { return Some(11) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_Z) { return Some(12) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_X) { return Some(13) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_C) { return Some(14) }
-    if raylib_handler.is_key_down(KeyboardKey::KEY_V) { return Some(15) }
-    None
-}
\ No newline at end of file
+#[cfg(feature = "gui")]
+fn launcher() -> Option<Config> {
+    let mut settings = chip_8::Settings::load().unwrap_or_else(|e| {
+        eprintln!("Ignoring the settings in {e}");
+        chip_8::Settings::default()
+    });
+    let mut database = load_database();
+    let (play_flag, config): (bool, Config) = gui::run(&settings, &database);
+    if !play_flag { return None }
+
+    if let Ok(rom) = Rom::read_rom(&config.rom_path) {
+        database.remember(&rom.sha1(), &config);
+        if let Err(e) = database.save_overrides() { eprintln!("Could not save ROM overrides: {e}"); }
+    }
+
+    settings.add_recent_rom(&config.rom_path);
+    settings.config = config.clone();
+    if let Err(e) = settings.save() { eprintln!("Could not save settings: {e}"); }
+
+    Some(config)
+}
+
+#[cfg(not(feature = "gui"))]
+fn launcher() -> Option<Config> {
+    eprintln!("This build of chip-8 has no launcher window; pass a ROM path (see --help)");
+    exit(2);
+}
+
+#[cfg(feature = "gui")]
+fn run_window(chip8: Chip8, config: &Config, recording: Option<(String, Movie)>, replay: Option<Movie>, debugger: Debugger) {
+    window::run(chip8, config, recording, replay, debugger)
+}
+
+#[cfg(not(feature = "gui"))]
+fn run_window(_: Chip8, _: &Config, _: Option<(String, Movie)>, _: Option<Movie>, _: Debugger) {
+    eprintln!("This build of chip-8 has no window; rebuild it with the `gui` feature or pass --headless");
+    exit(2);
+}
+
+// Without a window a breakpoint ends the run and prints the machine state
+fn run_headless(mut chip8: Chip8, frames: u64, mut recording: Option<(String, Movie)>, replay: Option<Movie>, mut debugger: Debugger) {
+    let frames = replay.as_ref().map_or(frames, |movie| movie.frames.len() as u64);
+
+    let mut halted = false;
+    for frame in 0..frames {
+        if debugger.is_paused() { break }
+        let keypad = replay.as_ref().map_or_else(Keypad::new, |movie| movie.frames[frame as usize]);
+        if let Some((_, movie)) = &mut recording { movie.frames.push(keypad); }
+        if let Err(e) = debugger.run_frame(&mut chip8, keypad) {
+            eprintln!("Emulation halted: {}", debugger::describe_error(&chip8, &e));
+            halted = true;
+            break;
+        }
+        if chip8.has_exited() || debugger.is_paused() { break }
+    }
+
+    if debugger.is_paused() {
+        eprintln!("Stopped: {}", debugger.reason());
+        for line in debugger::state_lines(&chip8) { eprintln!("{line}"); }
+    }
+
+    let display = chip8.framebuffer();
+    for y in 0..display.height() {
+        let row = (0..display.width())
+            .map(|x| match display.pixel(x, y) { 0 => '.', 1 => '#', 2 => '+', _ => '@' })
+            .collect::<String>();
+        println!("{row}");
+    }
+
+    if let Some((path, mut movie)) = recording {
+        movie.finish(&chip8);
+        if let Err(e) = movie.write(&path) { eprintln!("Could not save {path}: {e}"); }
+    }
+    if let Some(tracer) = chip8.take_tracer() {
+        if let Err(e) = tracer.finish() { eprintln!("Could not save the trace: {e}"); }
+    }
+    if let Some(movie) = replay.filter(|_| !debugger.is_paused()) {
+        if !movie.matches(&chip8) {
+            eprintln!("Replay diverged: the final screen does not match the recording");
+            exit(1);
+        }
+        eprintln!("Replay matches the recording ({} frames)", movie.frames.len());
+    }
+    if halted { exit(1) }
+}
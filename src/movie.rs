@@ -0,0 +1,193 @@
+use std::fs;
+use sha1::{Digest, Sha1};
+use crate::chip::Chip8;
+use crate::config::{Config, Platform, Quirks};
+use crate::error::Chip8Error;
+use crate::framebuffer::Framebuffer;
+use crate::keypad::Keypad;
+use crate::savestate::{platform_from_u8, quirk_bits, quirks_from_bits, StateReader, StateWriter};
+
+// A movie is MAGIC, the format version (u16), the ROM hash, the settings that affect
+// emulation, the keypad state of every frame and the hash of the final screen.
+// All numbers are little-endian.
+pub const MAGIC: [u8; 4] = *b"C8MV";
+pub const VERSION: u16 = 2;
+
+pub struct Movie {
+    pub rom_hash: [u8; 20],
+    pub seed: u64,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub cpu_hertz: u32,
+    pub delay_timer_hertz: u8,
+    pub sound_timer_hertz: u8,
+    pub rom_offset: u16,
+    pub frames: Vec<Keypad>,
+    pub framebuffer_hash: [u8; 20]
+}
+
+impl Movie {
+    // An empty movie for a machine that was just created from `config`
+    pub fn new(config: &Config, chip8: &Chip8) -> Movie {
+        Movie {
+            rom_hash: *chip8.rom_hash(),
+            seed: chip8.seed(),
+            platform: config.platform,
+            quirks: config.quirks,
+            cpu_hertz: config.cpu_hertz,
+            delay_timer_hertz: config.delay_timer_hertz,
+            sound_timer_hertz: config.sound_timer_hertz,
+            rom_offset: config.rom_offset,
+            frames: Vec::new(),
+            framebuffer_hash: [0; 20]
+        }
+    }
+
+    // Makes `config` start the machine exactly as it was when recording began
+    pub fn apply(&self, config: &mut Config) {
+        config.seed = Some(self.seed);
+        config.platform = self.platform;
+        config.quirks = self.quirks;
+        config.cpu_hertz = self.cpu_hertz;
+        config.delay_timer_hertz = self.delay_timer_hertz;
+        config.sound_timer_hertz = self.sound_timer_hertz;
+        config.rom_offset = self.rom_offset;
+    }
+
+    pub fn finish(&mut self, chip8: &Chip8) { self.framebuffer_hash = framebuffer_hash(chip8.framebuffer()); }
+
+    pub fn matches(&self, chip8: &Chip8) -> bool { self.framebuffer_hash == framebuffer_hash(chip8.framebuffer()) }
+
+    pub fn read(path: &str) -> Result<Movie, Chip8Error> { Movie::from_bytes(&fs::read(path)?) }
+
+    pub fn write(&self, path: &str) -> Result<(), Chip8Error> { Ok(fs::write(path, self.to_bytes())?) }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        writer.bytes(&MAGIC);
+        writer.u16(VERSION);
+        writer.bytes(&self.rom_hash);
+        writer.bytes(&self.seed.to_le_bytes());
+        writer.u8(self.platform as u8);
+        writer.u8(quirk_bits(&self.quirks));
+        writer.u32(self.cpu_hertz);
+        writer.u8(self.delay_timer_hertz);
+        writer.u8(self.sound_timer_hertz);
+        writer.u16(self.rom_offset);
+        writer.u32(self.frames.len() as u32);
+        for keypad in &self.frames { writer.u16(keypad.bits()); }
+        writer.bytes(&self.framebuffer_hash);
+
+        writer.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Movie, Chip8Error> {
+        Movie::decode(&mut StateReader::new(bytes)).map_err(|e| match e {
+            Chip8Error::BadSaveState { reason } => Chip8Error::BadMovie { reason },
+            e => e
+        })
+    }
+
+    fn decode(reader: &mut StateReader) -> Result<Movie, Chip8Error> {
+        if reader.array::<4>().ok() != Some(MAGIC) { return Err(Chip8Error::BadMovie { reason: "not a movie" }) }
+        if reader.u16()? != VERSION { return Err(Chip8Error::BadMovie { reason: "unsupported version" }) }
+
+        let mut movie = Movie {
+            rom_hash: reader.array()?,
+            seed: u64::from_le_bytes(reader.array()?),
+            platform: platform_from_u8(reader.u8()?)?,
+            quirks: quirks_from_bits(reader.u8()?),
+            cpu_hertz: reader.u32()?.max(1),
+            delay_timer_hertz: reader.u8()?,
+            sound_timer_hertz: reader.u8()?,
+            rom_offset: reader.u16()?,
+            frames: Vec::new(),
+            framebuffer_hash: [0; 20]
+        };
+        for _ in 0..reader.u32()? {
+            movie.frames.push(Keypad::from_bits(reader.u16()?));
+        }
+        movie.framebuffer_hash = reader.array()?;
+        reader.finish()?;
+
+        Ok(movie)
+    }
+}
+
+// SHA-1 of the screen as palette indices, row by row
+pub fn framebuffer_hash(display: &Framebuffer) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update([display.width() as u8, display.height() as u8]);
+    for y in 0..display.height() {
+        hasher.update((0..display.width()).map(|x| display.pixel(x, y)).collect::<Vec<u8>>());
+    }
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::Rom;
+
+    // Draws the font's 0 at random positions and clears the screen while key 5 is held
+    const PROGRAM: [u8; 14] = [
+        0xC1, 0x3F,     // 200: RND V1, 0x3F
+        0xC2, 0x1F,     // 202: RND V2, 0x1F
+        0x60, 0x05,     // 204: LD V0, 5
+        0xE0, 0xA1,     // 206: SKNP V0
+        0x00, 0xE0,     // 208: CLS
+        0xD1, 0x25,     // 20A: DRW V1, V2, 5
+        0x12, 0x00,     // 20C: JP 0x200
+    ];
+
+    fn play(config: &Config, frames: &[Keypad]) -> Chip8 {
+        let mut chip8 = Chip8::new(Rom::new(PROGRAM.to_vec()), config).unwrap();
+        for keypad in frames { chip8.run_frame(*keypad).unwrap(); }
+        chip8
+    }
+
+    #[test]
+    fn replay_reproduces_the_recording() {
+        let config = Config { seed: Some(42), cpu_hertz: 1000, ..Config::default() };
+        let mut movie = Movie::new(&config, &Chip8::new(Rom::new(PROGRAM.to_vec()), &config).unwrap());
+        movie.frames = (0..120).map(|frame| Keypad::from_bits(if frame % 50 == 7 { 1 << 5 } else { 0 })).collect();
+        movie.finish(&play(&config, &movie.frames));
+
+        let path = std::env::temp_dir().join(format!("chip-8-movie-{}.c8mv", std::process::id()));
+        let path = path.to_str().unwrap();
+        movie.write(path).unwrap();
+        let read = Movie::read(path);
+        fs::remove_file(path).unwrap();
+        let read = read.unwrap();
+        assert_eq!(read.to_bytes(), movie.to_bytes());
+
+        // The replay starts from the defaults and takes everything else from the movie
+        let mut replay_config = Config::default();
+        read.apply(&mut replay_config);
+        assert!(read.matches(&play(&replay_config, &read.frames)));
+
+        let other_seed = Config { seed: Some(43), ..replay_config.clone() };
+        assert!(!read.matches(&play(&other_seed, &read.frames)));
+        let no_keys = vec![Keypad::new(); read.frames.len()];
+        assert!(!read.matches(&play(&replay_config, &no_keys)));
+    }
+
+    #[test]
+    fn rejects_other_files_and_versions() {
+        let config = Config { seed: Some(1), ..Config::default() };
+        let bytes = Movie::new(&config, &play(&config, &[])).to_bytes();
+        assert!(Movie::from_bytes(&bytes).is_ok());
+
+        let reason = |bytes: &[u8]| match Movie::from_bytes(bytes) {
+            Err(Chip8Error::BadMovie { reason }) => reason,
+            _ => panic!("expected a bad movie")
+        };
+        assert_eq!(reason(b"C8STnot a movie"), "not a movie");
+        assert_eq!(reason(&bytes[..bytes.len() - 1]), "file is truncated");
+        for version in [VERSION - 1, VERSION + 1] {
+            let mut other = bytes.clone();
+            other[4..6].copy_from_slice(&version.to_le_bytes());
+            assert_eq!(reason(&other), "unsupported version");
+        }
+    }
+}
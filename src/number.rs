@@ -0,0 +1,52 @@
+// Numbers as the command line, debugger, symbol files and both assemblers write them:
+// decimal, 0x or # hexadecimal, or 0b or % binary
+pub fn parse<T: TryFrom<u64>>(text: &str) -> Option<T> {
+    let (digits, radix) = if let Some(hex) = text.strip_prefix("0x").or(text.strip_prefix("0X")).or(text.strip_prefix('#')) {
+        (hex, 16)
+    } else if let Some(binary) = text.strip_prefix("0b").or(text.strip_prefix("0B")).or(text.strip_prefix('%')) {
+        (binary, 2)
+    } else {
+        (text, 10)
+    };
+    if digits.starts_with('+') { return None }
+    T::try_from(u64::from_str_radix(digits, radix).ok()?).ok()
+}
+
+// A number with an optional leading -
+pub fn parse_signed(text: &str) -> Option<i64> {
+    match text.strip_prefix('-') {
+        Some(digits) => parse::<u64>(digits).and_then(|value| 0i64.checked_sub_unsigned(value)),
+        None => parse(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_base() {
+        assert_eq!(parse::<u64>("512"), Some(512));
+        assert_eq!(parse::<u64>("0x2A4"), Some(0x2A4));
+        assert_eq!(parse::<u64>("#ff"), Some(0xFF));
+        assert_eq!(parse::<u64>("0b101"), Some(5));
+        assert_eq!(parse::<u64>("%11110000"), Some(0xF0));
+    }
+
+    #[test]
+    fn rejects_signs_garbage_and_overflow() {
+        for text in ["", "0x", "+5", "0x+5", "-5", "12a", "0b2"] {
+            assert_eq!(parse::<u64>(text), None, "{text}");
+        }
+        assert_eq!(parse::<u8>("256"), None);
+        assert_eq!(parse::<u16>("0xFFFF"), Some(0xFFFF));
+    }
+
+    #[test]
+    fn parses_negative_numbers() {
+        assert_eq!(parse_signed("-0x10"), Some(-16));
+        assert_eq!(parse_signed("-9223372036854775808"), Some(i64::MIN));
+        assert_eq!(parse_signed("9223372036854775808"), None);
+        assert_eq!(parse_signed("--1"), None);
+    }
+}
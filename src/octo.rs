@@ -0,0 +1,809 @@
+use std::collections::BTreeMap;
+use crate::error::Chip8Error;
+use crate::number;
+use crate::symbols::SymbolTable;
+
+// Compiles Octo (.8o) source into a program loaded at 0x200. Supports labels, :next,
+// :alias, :const, :calc, :byte, :org, :macro, :call, :unpack, if/then, if/begin/else/end,
+// loop/while/again and bare numbers as data bytes. Like Octo, 0x200 holds a jump to
+// `main` unless `: main` is the first thing in the program.
+
+pub const START: usize = 0x200;
+
+// Macros expanding into themselves would otherwise never finish
+const MAX_MACRO_DEPTH: usize = 64;
+
+struct Token {
+    text: String,
+    line: usize,
+    depth: usize    // how many macro expansions produced the token
+}
+
+// A use of a label that was not defined yet
+struct Patch {
+    addr: usize,
+    kind: PatchKind,
+    name: String,
+    line: usize
+}
+
+enum PatchKind {
+    Address,        // the NNN of an opcode
+    Long,           // the word after F000
+    Unpack(u8)      // v0 := nibble and high bits, v1 := low byte
+}
+
+#[derive(Clone, Copy)]
+enum Condition {
+    Equal(usize, Operand),
+    NotEqual(usize, Operand),
+    Less(usize, Operand),
+    Greater(usize, Operand),
+    LessEqual(usize, Operand),
+    GreaterEqual(usize, Operand),
+    Key(usize),
+    NotKey(usize)
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(usize),
+    Byte(u8)
+}
+
+struct Macro {
+    arguments: Vec<String>,
+    body: Vec<Token>
+}
+
+pub fn compile(source: &str) -> Result<(Vec<u8>, SymbolTable), Chip8Error> {
+    let mut compiler = Compiler::new(source);
+    compiler.run().map_err(|message| Chip8Error::BadSource { line: compiler.line, message })?;
+
+    let mut symbols = SymbolTable::new();
+    for (name, addr) in &compiler.labels { symbols.insert(*addr, name); }
+    Ok((compiler.rom, symbols))
+}
+
+struct Compiler {
+    tokens: Vec<Token>,     // reversed, so the next token is popped off the end
+    line: usize,
+    depth: usize,           // macro depth of the last token read
+    rom: Vec<u8>,
+    here: usize,
+    labels: BTreeMap<String, usize>,
+    constants: BTreeMap<String, f64>,
+    aliases: BTreeMap<String, usize>,
+    macros: BTreeMap<String, Macro>,
+    patches: Vec<Patch>,
+    branches: Vec<usize>,               // jumps of open if/else blocks
+    loops: Vec<(usize, Vec<usize>)>,    // start of each open loop and the jumps of its whiles
+    main_jump: bool
+}
+
+impl Compiler {
+    fn new(source: &str) -> Compiler {
+        let mut tokens: Vec<Token> = source.lines().enumerate()
+            .flat_map(|(number, line)| {
+                let code = line.split('#').next().unwrap_or("");
+                code.split_whitespace().map(move |text| Token { text: text.to_string(), line: number + 1, depth: 0 })
+            })
+            .collect();
+        tokens.reverse();
+
+        let mut compiler = Compiler {
+            tokens, line: 1, depth: 0, rom: Vec::new(), here: START,
+            labels: BTreeMap::new(), constants: BTreeMap::new(), aliases: BTreeMap::new(), macros: BTreeMap::new(),
+            patches: Vec::new(), branches: Vec::new(), loops: Vec::new(), main_jump: true
+        };
+        compiler.emit(0x1000);
+        compiler
+    }
+
+    fn run(&mut self) -> Result<(), String> {
+        while let Some(token) = self.tokens.pop() {
+            (self.line, self.depth) = (token.line, token.depth);
+            self.statement(&token.text)?;
+        }
+
+        if !self.branches.is_empty() { return Err(String::from("`if ... begin` without `end`")) }
+        if !self.loops.is_empty() { return Err(String::from("`loop` without `again`")) }
+        if self.main_jump {
+            let main = *self.labels.get("main").ok_or("the program has no `main` label")?;
+            self.patch(START, &PatchKind::Address, main)?;
+        }
+        for patch in std::mem::take(&mut self.patches) {
+            self.line = patch.line;
+            let addr = *self.labels.get(&patch.name).ok_or_else(|| format!("undefined label `{}`", patch.name))?;
+            self.patch(patch.addr, &patch.kind, addr)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, token: &str) -> Result<(), String> {
+        match token {
+            ":" => {
+                let name = self.name()?;
+                // A leading `: main` replaces the jump to it
+                if name == "main" && self.main_jump && self.here == START + 2 {
+                    self.rom.clear();
+                    self.here = START;
+                    self.main_jump = false;
+                }
+                self.define_label(name, self.here)?;
+            },
+            ":next" => {
+                let name = self.name()?;
+                self.define_label(name, self.here + 1)?;
+            },
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+            },
+            ":const" => {
+                let name = self.name()?;
+                let value = self.value()?;
+                self.define_constant(name, value as f64)?;
+            },
+            ":calc" => {
+                let name = self.name()?;
+                let value = self.calc()?;
+                self.define_constant(name, value)?;
+            },
+            ":byte" => {
+                let value = if self.peek() == Some("{") { self.calc()? as i64 } else { self.value()? };
+                self.data(value)?;
+            },
+            ":org" => {
+                let addr = self.value()?;
+                if addr < START as i64 || addr > 0xFFFF { return Err(format!(":org {addr:#X} is outside the program")) }
+                self.here = addr as usize;
+            },
+            ":macro" => self.define_macro()?,
+            ":call" => {
+                let addr = self.address(PatchKind::Address)?;
+                self.emit(0x2000 | addr);
+            },
+            ":unpack" => {
+                let nibble = self.value()?;
+                if !(0..=0xF).contains(&nibble) { return Err(format!(":unpack needs a nibble, not {nibble}")) }
+                let addr = self.address(PatchKind::Unpack(nibble as u8))?;
+                self.emit(0x6000 | ((nibble as u16) << 4) | (addr >> 8));
+                self.emit(0x6100 | (addr & 0xFF));
+            },
+            ":breakpoint" => { self.name()?; },
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            },
+            "return" | ";" => self.emit(0x00EE),
+            "clear" => self.emit(0x00E0),
+            "exit" => self.emit(0x00FD),
+            "scroll-left" => self.emit(0x00FC),
+            "scroll-right" => self.emit(0x00FB),
+            "lores" => self.emit(0x00FE),
+            "hires" => self.emit(0x00FF),
+            "audio" => self.emit(0xF002),
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(0x00C0 | n);
+            },
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(0x00D0 | n);
+            },
+            "plane" => {
+                let n = self.nibble()?;
+                self.emit(0xF001 | (n << 8));
+            },
+            "bcd" => self.register_op(0xF033)?,
+            "saveflags" => self.register_op(0xF075)?,
+            "loadflags" => self.register_op(0xF085)?,
+            "save" | "load" => {
+                let x = self.register()? as u16;
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()? as u16;
+                    self.emit(if token == "save" { 0x5002 } else { 0x5003 } | (x << 8) | (y << 4));
+                } else {
+                    self.emit(if token == "save" { 0xF055 } else { 0xF065 } | (x << 8));
+                }
+            },
+            "sprite" => {
+                let x = self.register()? as u16;
+                let y = self.register()? as u16;
+                let n = self.nibble()?;
+                self.emit(0xD000 | (x << 8) | (y << 4) | n);
+            },
+            "jump" => {
+                let addr = self.address(PatchKind::Address)?;
+                self.emit(0x1000 | addr);
+            },
+            "jump0" => {
+                let addr = self.address(PatchKind::Address)?;
+                self.emit(0xB000 | addr);
+            },
+            "native" => return Err(String::from("native machine code calls are not supported")),
+            "i" => self.i_register()?,
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                self.register_op(match token { "delay" => 0xF015, "buzzer" => 0xF018, _ => 0xF03A })?;
+            },
+            "if" => {
+                let condition = self.condition()?;
+                match self.next()?.as_str() {
+                    "then" => self.skip_unless(condition),
+                    "begin" => {
+                        self.skip_unless(condition.negate());
+                        self.branches.push(self.here);
+                        self.emit(0x1000);
+                    },
+                    other => return Err(format!("expected `then` or `begin`, found `{other}`"))
+                }
+            },
+            "else" => {
+                let branch = self.branches.pop().ok_or("`else` without `if ... begin`")?;
+                self.branches.push(self.here);
+                self.emit(0x1000);
+                self.patch(branch, &PatchKind::Address, self.here)?;
+            },
+            "end" => {
+                let branch = self.branches.pop().ok_or("`end` without `if ... begin`")?;
+                self.patch(branch, &PatchKind::Address, self.here)?;
+            },
+            "loop" => self.loops.push((self.here, Vec::new())),
+            "while" => {
+                let condition = self.condition()?;
+                self.skip_unless(condition.negate());
+                let here = self.here;
+                self.loops.last_mut().ok_or("`while` outside a loop")?.1.push(here);
+                self.emit(0x1000);
+            },
+            "again" => {
+                let (start, exits) = self.loops.pop().ok_or("`again` without `loop`")?;
+                self.emit(0x1000 | self.checked_address(start)?);
+                for exit in exits { self.patch(exit, &PatchKind::Address, self.here)?; }
+            },
+            _ if self.register_name(token).is_some() => self.assignment(token)?,
+            _ if self.macros.contains_key(token) => self.expand(token)?,
+            _ => {
+                // Numbers and constants are data; any other name calls a label
+                if let Some(value) = self.number(token) {
+                    self.data(value)?;
+                } else if is_name(token) {
+                    self.tokens.push(Token { text: token.to_string(), line: self.line, depth: self.depth });
+                    let addr = self.address(PatchKind::Address)?;
+                    self.emit(0x2000 | addr);
+                } else {
+                    return Err(format!("unexpected `{token}`"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn i_register(&mut self) -> Result<(), String> {
+        match self.next()?.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    self.register_op(0xF029)
+                },
+                Some("bighex") => {
+                    self.next()?;
+                    self.register_op(0xF030)
+                },
+                Some("long") => {
+                    self.next()?;
+                    self.emit(0xF000);
+                    let addr = self.address(PatchKind::Long)?;
+                    self.emit(addr);
+                    Ok(())
+                },
+                _ => {
+                    let addr = self.address(PatchKind::Address)?;
+                    self.emit(0xA000 | addr);
+                    Ok(())
+                }
+            },
+            "+=" => self.register_op(0xF01E),
+            other => Err(format!("expected `:=` or `+=` after i, found `{other}`"))
+        }
+    }
+
+    fn assignment(&mut self, target: &str) -> Result<(), String> {
+        let x = self.register_name(target).unwrap() as u16;
+        let operator = self.next()?;
+        let rhs = self.next()?;
+        if let Some(y) = self.register_name(&rhs) {
+            let y = y as u16;
+            let n = match operator.as_str() {
+                ":=" => 0x0,
+                "|=" => 0x1,
+                "&=" => 0x2,
+                "^=" => 0x3,
+                "+=" => 0x4,
+                "-=" => 0x5,
+                ">>=" => 0x6,
+                "=-" => 0x7,
+                "<<=" => 0xE,
+                other => return Err(format!("unknown operator `{other}`"))
+            };
+            self.emit(0x8000 | (x << 8) | (y << 4) | n);
+            return Ok(());
+        }
+
+        match (operator.as_str(), rhs.as_str()) {
+            (":=", "key") => self.emit(0xF00A | (x << 8)),
+            (":=", "delay") => self.emit(0xF007 | (x << 8)),
+            (":=", "random") => {
+                let mask = self.byte()?;
+                self.emit(0xC000 | (x << 8) | mask as u16);
+            },
+            (":=" | "+=" | "-=", _) => {
+                self.tokens.push(Token { text: rhs, line: self.line, depth: self.depth });
+                let value = self.byte()?;
+                let opcode = match operator.as_str() {
+                    ":=" => 0x6000 | value as u16,
+                    "+=" => 0x7000 | value as u16,
+                    _ => 0x7000 | value.wrapping_neg() as u16
+                };
+                self.emit(opcode | (x << 8));
+            },
+            (other, _) => return Err(format!("`{other}` needs a register on the right"))
+        }
+        Ok(())
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let x = self.register()?;
+        let operator = self.next()?;
+        match operator.as_str() {
+            "key" => return Ok(Condition::Key(x)),
+            "-key" => return Ok(Condition::NotKey(x)),
+            _ => ()
+        }
+
+        let rhs = match self.peek().and_then(|token| self.register_name(token)) {
+            Some(y) => {
+                self.next()?;
+                Operand::Register(y)
+            },
+            None => Operand::Byte(self.byte()?)
+        };
+        Ok(match operator.as_str() {
+            "==" => Condition::Equal(x, rhs),
+            "!=" => Condition::NotEqual(x, rhs),
+            "<" => Condition::Less(x, rhs),
+            ">" => Condition::Greater(x, rhs),
+            "<=" => Condition::LessEqual(x, rhs),
+            ">=" => Condition::GreaterEqual(x, rhs),
+            other => return Err(format!("unknown comparison `{other}`"))
+        })
+    }
+
+    // Emits a skip so the next instruction only runs when `condition` holds. Ordered
+    // comparisons subtract into VF first and test the borrow flag.
+    fn skip_unless(&mut self, condition: Condition) {
+        let xy = |x: usize, y: usize| ((x as u16) << 8) | ((y as u16) << 4);
+        match condition {
+            Condition::Equal(x, Operand::Byte(n)) => self.emit(0x4000 | xy(x, 0) | n as u16),
+            Condition::Equal(x, Operand::Register(y)) => self.emit(0x9000 | xy(x, y)),
+            Condition::NotEqual(x, Operand::Byte(n)) => self.emit(0x3000 | xy(x, 0) | n as u16),
+            Condition::NotEqual(x, Operand::Register(y)) => self.emit(0x5000 | xy(x, y)),
+            Condition::Key(x) => self.emit(0xE0A1 | xy(x, 0)),
+            Condition::NotKey(x) => self.emit(0xE09E | xy(x, 0)),
+            Condition::Less(x, rhs) | Condition::GreaterEqual(x, rhs) => {
+                // VF = x >= rhs
+                match rhs {
+                    Operand::Register(y) => {
+                        self.emit(0x8F00 | xy(0, x));
+                        self.emit(0x8F05 | xy(0, y));
+                    },
+                    Operand::Byte(n) => {
+                        self.emit(0x6F00 | n as u16);
+                        self.emit(0x8F07 | xy(0, x));
+                    }
+                }
+                self.emit(if matches!(condition, Condition::Less(..)) { 0x4F00 } else { 0x3F00 });
+            },
+            Condition::Greater(x, rhs) | Condition::LessEqual(x, rhs) => {
+                // VF = rhs >= x
+                match rhs {
+                    Operand::Register(y) => self.emit(0x8F00 | xy(0, y)),
+                    Operand::Byte(n) => self.emit(0x6F00 | n as u16)
+                }
+                self.emit(0x8F05 | xy(0, x));
+                self.emit(if matches!(condition, Condition::Greater(..)) { 0x4F00 } else { 0x3F00 });
+            }
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), String> {
+        let name = self.name()?;
+        let mut arguments = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token == "{" { break }
+            arguments.push(token);
+        }
+        let body = self.block()?;
+        self.macros.insert(name, Macro { arguments, body });
+        Ok(())
+    }
+
+    // Replaces a macro call by its body with the arguments substituted
+    fn expand(&mut self, name: &str) -> Result<(), String> {
+        if self.depth >= MAX_MACRO_DEPTH { return Err(format!("macro `{name}` nests more than {MAX_MACRO_DEPTH} levels deep")) }
+        let depth = self.depth + 1;
+        let count = self.macros[name].arguments.len();
+        let mut values = Vec::new();
+        for _ in 0..count { values.push(self.next()?); }
+
+        let line = self.line;
+        let definition = &self.macros[name];
+        let body: Vec<Token> = definition.body.iter()
+            .map(|token| {
+                let text = match definition.arguments.iter().position(|argument| *argument == token.text) {
+                    Some(i) => values[i].clone(),
+                    None => token.text.clone()
+                };
+                Token { text, line, depth }
+            })
+            .collect();
+        self.tokens.extend(body.into_iter().rev());
+        Ok(())
+    }
+
+    // The tokens up to the `}` matching an already consumed `{`
+    fn block(&mut self) -> Result<Vec<Token>, String> {
+        let mut depth = 0;
+        let mut body = Vec::new();
+        loop {
+            let token = self.tokens.pop().ok_or("missing `}`")?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(body),
+                "}" => depth -= 1,
+                _ => ()
+            }
+            body.push(token);
+        }
+    }
+
+    // `{ expression }`, evaluated right to left without precedence like Octo does
+    fn calc(&mut self) -> Result<f64, String> {
+        self.expect("{")?;
+        let tokens: Vec<String> = self.block()?.into_iter().map(|token| token.text).collect();
+        let mut at = 0;
+        let value = self.expression(&tokens, &mut at)?;
+        if at != tokens.len() { return Err(format!("unexpected `{}` in expression", tokens[at])) }
+        Ok(value)
+    }
+
+    fn expression(&self, tokens: &[String], at: &mut usize) -> Result<f64, String> {
+        let left = self.term(tokens, at)?;
+        let Some(operator) = tokens.get(*at).filter(|token| *token != ")") else { return Ok(left) };
+        *at += 1;
+        let right = self.expression(tokens, at)?;
+        let (a, b) = (left as i64, right as i64);
+        Ok(match operator.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" if right == 0.0 => return Err(String::from("division by zero")),
+            "/" => left / right,
+            "%" if b == 0 => return Err(String::from("division by zero")),
+            "%" => (a % b) as f64,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => (a << (b & 63)) as f64,
+            ">>" => (a >> (b & 63)) as f64,
+            "min" => left.min(right),
+            "max" => left.max(right),
+            other => return Err(format!("unknown operator `{other}`"))
+        })
+    }
+
+    fn term(&self, tokens: &[String], at: &mut usize) -> Result<f64, String> {
+        let token = tokens.get(*at).ok_or("incomplete expression")?;
+        *at += 1;
+        match token.as_str() {
+            "(" => {
+                let value = self.expression(tokens, at)?;
+                if tokens.get(*at).map(String::as_str) != Some(")") { return Err(String::from("missing `)`")) }
+                *at += 1;
+                Ok(value)
+            },
+            "-" => Ok(-self.term(tokens, at)?),
+            "~" => Ok(!(self.term(tokens, at)? as i64) as f64),
+            "!" => Ok(if self.term(tokens, at)? == 0.0 { 1.0 } else { 0.0 }),
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            name => self.constants.get(name).copied()
+                .or_else(|| self.labels.get(name).map(|addr| *addr as f64))
+                .or_else(|| number::parse_signed(name).map(|value| value as f64))
+                .ok_or_else(|| format!("unknown name `{name}` in expression"))
+        }
+    }
+
+    fn define_label(&mut self, name: String, addr: usize) -> Result<(), String> {
+        if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+            return Err(format!("`{name}` is already defined"));
+        }
+        self.labels.insert(name, addr);
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: String, value: f64) -> Result<(), String> {
+        if self.labels.contains_key(&name) { return Err(format!("`{name}` is already a label")) }
+        self.constants.insert(name, value);
+        Ok(())
+    }
+
+    fn emit(&mut self, word: u16) {
+        self.put(self.here, (word >> 8) as u8);
+        self.put(self.here + 1, word as u8);
+        self.here += 2;
+    }
+
+    fn data(&mut self, value: i64) -> Result<(), String> {
+        if !(-128..=255).contains(&value) { return Err(format!("{value} does not fit in a byte")) }
+        self.put(self.here, value as u8);
+        self.here += 1;
+        Ok(())
+    }
+
+    fn put(&mut self, addr: usize, byte: u8) {
+        let index = addr - START;
+        if index >= self.rom.len() { self.rom.resize(index + 1, 0); }
+        self.rom[index] = byte;
+    }
+
+    fn patch(&mut self, at: usize, kind: &PatchKind, addr: usize) -> Result<(), String> {
+        let index = at - START;
+        match kind {
+            PatchKind::Address => {
+                let addr = self.checked_address(addr)?;
+                self.rom[index] = (self.rom[index] & 0xF0) | (addr >> 8) as u8;
+                self.rom[index + 1] = addr as u8;
+            },
+            PatchKind::Long => self.rom[index..index + 2].copy_from_slice(&(addr as u16).to_be_bytes()),
+            PatchKind::Unpack(nibble) => {
+                let addr = self.checked_address(addr)?;
+                self.rom[index + 1] = (nibble << 4) | (addr >> 8) as u8;
+                self.rom[index + 3] = addr as u8;
+            }
+        }
+        Ok(())
+    }
+
+    fn checked_address(&self, addr: usize) -> Result<u16, String> {
+        if addr > 0xFFF { return Err(format!("address {addr:#X} is out of reach of a 12-bit operand")) }
+        Ok(addr as u16)
+    }
+
+    // An address operand; unknown names are patched once every label is defined
+    fn address(&mut self, kind: PatchKind) -> Result<u16, String> {
+        let token = self.next()?;
+        if let Some(value) = self.number(&token) {
+            let max = if matches!(kind, PatchKind::Long) { 0xFFFF } else { 0xFFF };
+            if !(0..=max).contains(&value) { return Err(format!("address {value:#X} is out of range")) }
+            return Ok(value as u16);
+        }
+        if let Some(addr) = self.labels.get(&token) {
+            return match kind {
+                PatchKind::Long => Ok(*addr as u16),
+                _ => self.checked_address(*addr)
+            };
+        }
+        if !is_name(&token) { return Err(format!("expected an address, found `{token}`")) }
+
+        // Every caller emits the word (or for :unpack, the pair) being patched next
+        self.patches.push(Patch { addr: self.here, kind, name: token, line: self.line });
+        Ok(0)
+    }
+
+    fn register_op(&mut self, opcode: u16) -> Result<(), String> {
+        let x = self.register()? as u16;
+        self.emit(opcode | (x << 8));
+        Ok(())
+    }
+
+    fn register_name(&self, token: &str) -> Option<usize> {
+        if let Some(register) = self.aliases.get(token) { return Some(*register) }
+        let digit = token.strip_prefix('v').or(token.strip_prefix('V'))?;
+        if digit.len() != 1 { return None }
+        usize::from_str_radix(digit, 16).ok()
+    }
+
+    fn register(&mut self) -> Result<usize, String> {
+        let token = self.next()?;
+        self.register_name(&token).ok_or_else(|| format!("expected a register, found `{token}`"))
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let token = self.next()?;
+        if is_name(&token) { Ok(token) } else { Err(format!("`{token}` is not a valid name")) }
+    }
+
+    // A number or constant
+    fn value(&mut self) -> Result<i64, String> {
+        let token = self.next()?;
+        self.number(&token).ok_or_else(|| format!("expected a number, found `{token}`"))
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let value = self.value()?;
+        if !(-128..=255).contains(&value) { return Err(format!("{value} does not fit in a byte")) }
+        Ok(value as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u16, String> {
+        let value = self.value()?;
+        if !(0..=15).contains(&value) { return Err(format!("{value} does not fit in a nibble")) }
+        Ok(value as u16)
+    }
+
+    fn number(&self, token: &str) -> Option<i64> {
+        number::parse_signed(token).or_else(|| self.constants.get(token).map(|value| *value as i64))
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token == expected { Ok(()) } else { Err(format!("expected `{expected}`, found `{token}`")) }
+    }
+
+    fn peek(&self) -> Option<&str> { self.tokens.last().map(|token| token.text.as_str()) }
+
+    fn next(&mut self) -> Result<String, String> {
+        let token = self.tokens.pop().ok_or("unexpected end of file")?;
+        (self.line, self.depth) = (token.line, token.depth);
+        Ok(token.text)
+    }
+}
+
+impl Condition {
+    fn negate(self) -> Condition {
+        match self {
+            Condition::Equal(x, rhs) => Condition::NotEqual(x, rhs),
+            Condition::NotEqual(x, rhs) => Condition::Equal(x, rhs),
+            Condition::Less(x, rhs) => Condition::GreaterEqual(x, rhs),
+            Condition::GreaterEqual(x, rhs) => Condition::Less(x, rhs),
+            Condition::Greater(x, rhs) => Condition::LessEqual(x, rhs),
+            Condition::LessEqual(x, rhs) => Condition::Greater(x, rhs),
+            Condition::Key(x) => Condition::NotKey(x),
+            Condition::NotKey(x) => Condition::Key(x)
+        }
+    }
+}
+
+fn is_name(token: &str) -> bool {
+    let mut chars = token.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Platform;
+    use crate::disasm::{self, Syntax};
+
+    fn program(source: &str) -> Vec<u8> {
+        compile(source).unwrap().0
+    }
+
+    fn error(source: &str) -> (usize, String) {
+        match compile(source) {
+            Err(Chip8Error::BadSource { line, message }) => (line, message),
+            Err(e) => panic!("{e}"),
+            Ok(_) => panic!("`{source}` compiled")
+        }
+    }
+
+    #[test]
+    fn jumps_to_main_unless_it_comes_first() {
+        assert_eq!(program(": main clear"), [0x00, 0xE0]);
+        assert_eq!(program(": sub return\n: main sub"), [0x12, 0x04, 0x00, 0xEE, 0x22, 0x02]);
+    }
+
+    #[test]
+    fn patches_forward_labels() {
+        let source = ": main jump later i := sprite :call sub i := long sprite\n: later exit\n: sub return\n: sprite 0xFF";
+        assert_eq!(program(source), [0x12, 0x0A, 0xA2, 0x0E, 0x22, 0x0C, 0xF0, 0x00, 0x02, 0x0E, 0x00, 0xFD, 0x00, 0xEE, 0xFF]);
+    }
+
+    #[test]
+    fn if_then_skips_with_the_opposite_condition() {
+        assert_eq!(program(": main if v1 == 5 then v2 := 1"), [0x41, 0x05, 0x62, 0x01]);
+        assert_eq!(program(": main if v1 != v3 then v2 := 1"), [0x51, 0x30, 0x62, 0x01]);
+        assert_eq!(program(": main if v4 key then v2 := 1"), [0xE4, 0xA1, 0x62, 0x01]);
+    }
+
+    #[test]
+    fn if_begin_else_end() {
+        let source = ": main\n if v0 == 1 begin\n v1 := 2\n else\n v1 := 3\n end\n exit";
+        assert_eq!(program(source), [0x30, 0x01, 0x12, 0x08, 0x61, 0x02, 0x12, 0x0A, 0x61, 0x03, 0x00, 0xFD]);
+        assert_eq!(program(": main if v0 == 1 begin v1 := 2 end"), [0x30, 0x01, 0x12, 0x06, 0x61, 0x02]);
+    }
+
+    #[test]
+    fn loop_while_again() {
+        let source = ": main\n loop\n v0 += 1\n while v0 != 10\n again";
+        assert_eq!(program(source), [0x70, 0x01, 0x40, 0x0A, 0x12, 0x08, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn comparisons_test_the_borrow_in_vf() {
+        assert_eq!(program(": main if v1 < v2 then v3 := 1"), [0x8F, 0x10, 0x8F, 0x25, 0x4F, 0x00, 0x63, 0x01]);
+        assert_eq!(program(": main if v1 >= v2 then v3 := 1"), [0x8F, 0x10, 0x8F, 0x25, 0x3F, 0x00, 0x63, 0x01]);
+        assert_eq!(program(": main if v1 > 5 then v3 := 1"), [0x6F, 0x05, 0x8F, 0x15, 0x4F, 0x00, 0x63, 0x01]);
+        assert_eq!(program(": main if v1 <= v2 then v3 := 1"), [0x8F, 0x20, 0x8F, 0x15, 0x3F, 0x00, 0x63, 0x01]);
+        assert_eq!(program(": main if v1 < 7 then v3 := 1"), [0x6F, 0x07, 0x8F, 0x17, 0x4F, 0x00, 0x63, 0x01]);
+    }
+
+    #[test]
+    fn expands_macros() {
+        assert_eq!(program(":macro twice reg { reg += 1 reg += 1 }\n: main twice v3 twice v4"), [0x73, 0x01, 0x73, 0x01, 0x74, 0x01, 0x74, 0x01]);
+        assert_eq!(program(":macro one { v0 += 1 }\n:macro two { one one }\n: main two"), [0x70, 0x01, 0x70, 0x01]);
+    }
+
+    #[test]
+    fn stops_recursive_macros() {
+        let message = String::from("macro `m` nests more than 64 levels deep");
+        assert_eq!(error(":macro m { m }\n: main m"), (2, message.clone()));
+        assert_eq!(error(":macro m { v0 += 1 m }\n: main m"), (2, message));
+        assert_eq!(error(":macro a { b }\n:macro b { a }\n: main\n a").0, 4);
+    }
+
+    #[test]
+    fn calc_evaluates_right_to_left() {
+        assert_eq!(program(":calc SIZE { 2 * 3 + 1 }\n: main v0 := SIZE"), [0x60, 0x08]);
+        assert_eq!(program(": main v0 := 1 :calc NEXT { HERE - 0x200 } v1 := NEXT"), [0x60, 0x01, 0x61, 0x02]);
+        assert_eq!(error(":calc X { 1 / 0 }"), (1, String::from("division by zero")));
+    }
+
+    #[test]
+    fn unpacks_addresses_into_v0_and_v1() {
+        assert_eq!(program(": main :unpack 0xA data exit\n: data 0x12"), [0x60, 0xA2, 0x61, 0x06, 0x00, 0xFD, 0x12]);
+    }
+
+    #[test]
+    fn reports_errors_on_their_line() {
+        assert_eq!(error(": main\n clear\n jump nowhere"), (3, String::from("undefined label `nowhere`")));
+        assert_eq!(error(": main\n v0 := 300"), (2, String::from("300 does not fit in a byte")));
+        assert_eq!(error(": main\n\n end"), (3, String::from("`end` without `if ... begin`")));
+        assert_eq!(error(": main : main"), (1, String::from("`main` is already defined")));
+        assert_eq!(error(": start clear").1, "the program has no `main` label");
+    }
+
+    #[test]
+    fn round_trips_through_the_disassembler() {
+        let source = "
+: main
+  clear hires lores scroll-down 3 scroll-up 2 scroll-left scroll-right plane 3
+  sprite v1 v2 5
+  if v1 != 0x12 then v2 := v3
+  if v1 == v4 then v5 |= v6
+  if v7 -key then v8 &= v9
+  va ^= vb vc += vd ve -= vf v0 >>= v1 v2 =- v3 v4 <<= v5
+  v6 += 0x20 v7 := delay v8 := key v9 := random 0x0F
+  save v1 load v2 saveflags v3 loadflags v4 save v1 - v4 load v5 - v2
+  i := sprite i += v6 i := hex v7 i := bighex v8 i := long sprite
+  delay := v9 buzzer := va pitch := vb bcd vc audio
+  sub
+  jump0 next
+: next jump done
+: sub return
+: done exit
+: sprite 0x01 0x02 0x03";
+        let compiled = program(source);
+        let listing = disasm::disassemble(&compiled, START, Platform::XoChip, Syntax::Octo, &SymbolTable::new());
+        let listing: String = listing.lines().map(|line| line.split('#').next().unwrap()).collect::<Vec<_>>().join("\n");
+        assert_eq!(program(&format!(": main\n{listing}")), compiled, "{listing}");
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+
+// History of save states for rewinding. Only the newest state is kept whole; every older
+// one is stored as the run-length encoded XOR against the state that followed it, which
+// is mostly zeros from one frame to the next.
+pub struct Rewind {
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    capacity: usize
+}
+
+impl Rewind {
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            latest: None,
+            deltas: VecDeque::new(),
+            capacity
+        }
+    }
+
+    pub fn len(&self) -> usize { self.deltas.len() }
+
+    pub fn is_empty(&self) -> bool { self.deltas.is_empty() }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if self.capacity == 0 { return }
+
+        match self.latest.take() {
+            // Loading a state for another platform changes the size; older history is useless then
+            Some(latest) if latest.len() != state.len() => self.deltas.clear(),
+            Some(latest) => {
+                self.deltas.push_back(encode(&latest, &state));
+                if self.deltas.len() > self.capacity { self.deltas.pop_front(); }
+            },
+            None => {}
+        }
+        self.latest = Some(state);
+    }
+
+    // Steps one state back, returning it
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let delta = self.deltas.pop_back()?;
+        let latest = self.latest.as_mut()?;
+        decode(latest, &delta);
+        Some(latest.clone())
+    }
+}
+
+// (zero run, literal count, literals) triples, counts as LEB128
+fn encode(previous: &[u8], next: &[u8]) -> Vec<u8> {
+    let xor = previous.iter().zip(next).map(|(a, b)| a ^ b).collect::<Vec<u8>>();
+    let mut encoded = Vec::new();
+    let mut at = 0;
+
+    while at < xor.len() {
+        let zeros = xor[at..].iter().take_while(|byte| **byte == 0).count();
+        at += zeros;
+        let literals = xor[at..].iter().take_while(|byte| **byte != 0).count();
+
+        write_varint(&mut encoded, zeros);
+        write_varint(&mut encoded, literals);
+        encoded.extend_from_slice(&xor[at..at + literals]);
+        at += literals;
+    }
+
+    encoded
+}
+
+// XORs an encoded delta back into `state`
+fn decode(state: &mut [u8], delta: &[u8]) {
+    let mut at = 0;
+    let mut read = 0;
+
+    while read < delta.len() {
+        at += read_varint(delta, &mut read);
+        let literals = read_varint(delta, &mut read);
+        for (byte, xor) in state[at..at + literals].iter_mut().zip(&delta[read..read + literals]) {
+            *byte ^= xor;
+        }
+        at += literals;
+        read += literals;
+    }
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], at: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*at];
+        *at += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 { return value }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Chip8, Config, Keypad, Rom};
+
+    #[test]
+    fn pops_states_newest_first() {
+        let states = [vec![0; 300], vec![1; 300], [vec![1; 150], vec![0; 150]].concat(), vec![0xFF; 300]];
+        let mut rewind = Rewind::new(10);
+        for state in &states { rewind.push(state.clone()); }
+
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.pop().as_ref(), Some(&states[2]));
+        assert_eq!(rewind.pop().as_ref(), Some(&states[1]));
+        assert_eq!(rewind.pop().as_ref(), Some(&states[0]));
+        assert_eq!(rewind.pop(), None);
+    }
+
+    #[test]
+    fn keeps_only_capacity_states() {
+        let mut rewind = Rewind::new(2);
+        for byte in 0..5 { rewind.push(vec![byte; 4]); }
+        assert_eq!(rewind.pop(), Some(vec![3; 4]));
+        assert_eq!(rewind.pop(), Some(vec![2; 4]));
+        assert_eq!(rewind.pop(), None);
+
+        let mut disabled = Rewind::new(0);
+        disabled.push(vec![0; 4]);
+        disabled.push(vec![1; 4]);
+        assert!(disabled.is_empty());
+    }
+
+    #[test]
+    fn a_different_state_size_drops_the_history() {
+        let mut rewind = Rewind::new(10);
+        rewind.push(vec![0; 4]);
+        rewind.push(vec![1; 4]);
+        rewind.push(vec![2; 8]);
+        assert!(rewind.is_empty());
+        rewind.push(vec![3; 8]);
+        assert_eq!(rewind.pop(), Some(vec![2; 8]));
+    }
+
+    #[test]
+    fn rewound_states_load_into_the_machine() {
+        // LD V0, 60; LD DT, V0; RND V1, 0x3F; DRW V1, V1, 5; JP 0x204
+        let program = vec![0x60, 0x3C, 0xF0, 0x15, 0xC1, 0x3F, 0xD1, 0x15, 0x12, 0x04];
+        let config = Config { seed: Some(7), ..Config::default() };
+        let mut chip8 = Chip8::new(Rom::new(program), &config).unwrap();
+        let mut rewind = Rewind::new(60);
+
+        let mut states = Vec::new();
+        for _ in 0..30 {
+            chip8.run_frame(Keypad::new()).unwrap();
+            states.push(chip8.save_state());
+            rewind.push(chip8.save_state());
+        }
+
+        states.pop();
+        while let Some(state) = rewind.pop() {
+            chip8.load_state(&state).unwrap();
+            assert_eq!(chip8.save_state(), states.pop().unwrap());
+        }
+        assert!(states.is_empty());
+    }
+}
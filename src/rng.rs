@@ -0,0 +1,107 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use crate::error::Chip8Error;
+use crate::savestate::{Snapshot, StateReader, StateWriter};
+
+// SplitMix64: fast, good enough for games and fully described by one u64,
+// so runs can be reproduced from a seed and the generator fits in a save state
+#[derive(Clone)]
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng { Rng { state: seed } }
+
+    // A different seed every run, taken from the hasher keys std already randomizes
+    pub fn random_seed() -> u64 { RandomState::new().build_hasher().finish() }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_u8(&mut self) -> u8 { (self.next_u64() >> 56) as u8 }
+}
+
+impl Snapshot for Rng {
+    fn save(&self, writer: &mut StateWriter) { writer.bytes(&self.state.to_le_bytes()); }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), Chip8Error> {
+        self.state = u64::from_le_bytes(reader.array()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::Chip8;
+    use crate::config::Config;
+    use crate::keypad::Keypad;
+    use crate::rom::Rom;
+
+    // RND V0, 0xFF; JP 0x200
+    fn random_bytes(seed: u64, count: usize) -> Vec<u8> {
+        let config = Config { seed: Some(seed), ..Config::default() };
+        let mut chip8 = Chip8::new(Rom::new(vec![0xC0, 0xFF, 0x12, 0x00]), &config).unwrap();
+        (0..count).map(|_| {
+            chip8.run_cycle(Keypad::new()).unwrap();
+            chip8.run_cycle(Keypad::new()).unwrap();
+            chip8.registers()[0]
+        }).collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_bytes() {
+        assert_eq!(random_bytes(7, 100), random_bytes(7, 100));
+        assert_ne!(random_bytes(7, 100), random_bytes(8, 100));
+    }
+
+    #[test]
+    fn bytes_are_roughly_uniform() {
+        let mut rng = Rng::new(1);
+        let mut counts = [0; 256];
+        for _ in 0..256 * 100 { counts[rng.next_u8() as usize] += 1; }
+        assert!(counts.iter().all(|count| (50..150).contains(count)));
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let mut rng = Rng::new(3);
+        rng.next_u64();
+        let mut writer = StateWriter::default();
+        rng.save(&mut writer);
+        let bytes = writer.into_bytes();
+
+        let mut restored = Rng::new(0);
+        let mut reader = StateReader::new(&bytes);
+        restored.load(&mut reader).unwrap();
+        reader.finish().unwrap();
+        assert_eq!((0..10).map(|_| restored.next_u64()).collect::<Vec<_>>(), (0..10).map(|_| rng.next_u64()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn save_states_keep_the_generator() {
+        let config = Config { seed: Some(5), ..Config::default() };
+        let mut chip8 = Chip8::new(Rom::new(vec![0xC0, 0xFF, 0x12, 0x00]), &config).unwrap();
+        for _ in 0..10 { chip8.run_cycle(Keypad::new()).unwrap(); }
+        let state = chip8.save_state();
+
+        let run = |chip8: &mut Chip8| (0..20).map(|_| {
+            chip8.run_cycle(Keypad::new()).unwrap();
+            chip8.registers()[0]
+        }).collect::<Vec<_>>();
+        let expected = run(&mut chip8);
+
+        // A machine started with another seed continues the saved sequence
+        let other = Config { seed: Some(6), ..Config::default() };
+        let mut restored = Chip8::new(Rom::new(vec![0xC0, 0xFF, 0x12, 0x00]), &other).unwrap();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.seed(), 5);
+        assert_eq!(run(&mut restored), expected);
+    }
+}
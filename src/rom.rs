@@ -1,27 +1,69 @@
-use std::fs::File;
-use std::io::prelude::*;
+use std::fs;
+use std::io;
+use std::path::Path;
+use sha1::{Digest, Sha1};
+use crate::cartridge;
+use crate::config::Config;
+use crate::database::RomSettings;
+use crate::error::Chip8Error;
+use crate::octo;
+use crate::symbols::SymbolTable;
+
 pub struct Rom {
-    pub program: [u8; 3584],
-    pub length: usize
+    pub program: Vec<u8>,
+    pub symbols: SymbolTable,
+    pub settings: RomSettings,    // settings the ROM file carries itself, like those of an Octo cartridge
+    pub origin: Option<u16>       // where a compiled program has to be loaded for its labels to be right
 }
 
 impl Rom {
-    pub fn read_rom(path: &str) -> Rom {
-        let mut file = match File::open(path){
-            Ok(file) => file,
-            Err(e) => panic!("{e}")
-        };
-
-        let mut instance = Rom {
-            program: [0; 3584],
-            length: 0
-        };
-
-        match file.read(&mut instance.program) {
-            Ok(n) => instance.length = n,
-            Err(e) => panic!("{e}")
-        };
-
-        instance
+    pub fn new(program: Vec<u8>) -> Rom {
+        Rom { program, symbols: SymbolTable::new(), settings: RomSettings::default(), origin: None }
+    }
+
+    // Octo cartridges are recognised by their content and Octo sources (.8o) by their
+    // extension; both are compiled. Anything else is loaded as a binary.
+    pub fn read_rom(path: &str) -> Result<Rom, Chip8Error> {
+        let bytes = fs::read(path)?;
+
+        if cartridge::is_cartridge(&bytes) {
+            let cartridge = cartridge::decode(&bytes)?;
+            let (program, symbols) = octo::compile(&cartridge.source)?;
+            return Ok(Rom { program, symbols, settings: cartridge.settings, origin: Some(octo::START as u16) });
+        }
+        if Path::new(path).extension().is_some_and(|extension| extension.eq_ignore_ascii_case("8o")) {
+            let source = String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let (program, symbols) = octo::compile(&source)?;
+            return Ok(Rom { program, symbols, settings: RomSettings::default(), origin: Some(octo::START as u16) });
+        }
+
+        Ok(Rom::new(bytes))
     }
-}
\ No newline at end of file
+
+    // The configured offset, unless the program was compiled for a fixed address
+    pub fn load_address(&self, config: &Config) -> u16 { self.origin.unwrap_or(config.rom_offset) }
+
+    pub fn digest(&self) -> [u8; 20] { Sha1::digest(&self.program).into() }
+
+    // Lowercase hex SHA-1 of the program, the key used by the CHIP-8 database
+    pub fn sha1(&self) -> String {
+        self.digest().iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiled_sources_ignore_the_configured_offset() {
+        let path = std::env::temp_dir().join(format!("chip-8-rom-{}.8o", std::process::id()));
+        fs::write(&path, ": main clear").unwrap();
+        let rom = Rom::read_rom(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let config = Config { rom_offset: 0x300, ..Config::default() };
+        assert_eq!(rom.unwrap().load_address(&config), 0x200);
+        assert_eq!(Rom::new(vec![0x00, 0xE0]).load_address(&config), 0x300);
+    }
+}
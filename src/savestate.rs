@@ -0,0 +1,109 @@
+use std::path::PathBuf;
+use crate::config::{Platform, Quirks};
+use crate::error::Chip8Error;
+
+// A save state is MAGIC, the format version (u16) and the SHA-1 of the ROM it was taken
+// from, followed by the machine state. All numbers are little-endian.
+pub const MAGIC: [u8; 4] = *b"C8ST";
+pub const VERSION: u16 = 2;
+pub const SLOTS: u8 = 4;
+
+// Slot files live beside the ROM: game.ch8 -> game.ch8.state1
+pub fn slot_path(rom_path: &str, slot: u8) -> PathBuf {
+    PathBuf::from(format!("{rom_path}.state{slot}"))
+}
+
+pub(crate) trait Snapshot {
+    fn save(&self, writer: &mut StateWriter);
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), Chip8Error>;
+}
+
+#[derive(Default)]
+pub(crate) struct StateWriter {
+    bytes: Vec<u8>
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, value: u8) { self.bytes.push(value); }
+
+    pub fn bool(&mut self, value: bool) { self.u8(value as u8); }
+
+    pub fn u16(&mut self, value: u16) { self.bytes.extend_from_slice(&value.to_le_bytes()); }
+
+    pub fn u32(&mut self, value: u32) { self.bytes.extend_from_slice(&value.to_le_bytes()); }
+
+    pub fn u128(&mut self, value: u128) { self.bytes.extend_from_slice(&value.to_le_bytes()); }
+
+    pub fn bytes(&mut self, bytes: &[u8]) { self.bytes.extend_from_slice(bytes); }
+
+    pub fn into_bytes(self) -> Vec<u8> { self.bytes }
+}
+
+pub(crate) struct StateReader<'a> {
+    bytes: &'a [u8],
+    at: usize
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> StateReader<'a> { StateReader { bytes, at: 0 } }
+
+    pub fn bytes(&mut self, length: usize) -> Result<&'a [u8], Chip8Error> {
+        let bytes = self.bytes.get(self.at..self.at + length).ok_or(Chip8Error::BadSaveState { reason: "file is truncated" })?;
+        self.at += length;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], Chip8Error> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Chip8Error> { Ok(self.array::<1>()?[0]) }
+
+    pub fn bool(&mut self) -> Result<bool, Chip8Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Chip8Error::BadSaveState { reason: "invalid flag" })
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Chip8Error> { Ok(u16::from_le_bytes(self.array()?)) }
+
+    pub fn u32(&mut self) -> Result<u32, Chip8Error> { Ok(u32::from_le_bytes(self.array()?)) }
+
+    pub fn u128(&mut self) -> Result<u128, Chip8Error> { Ok(u128::from_le_bytes(self.array()?)) }
+
+    pub fn finish(&self) -> Result<(), Chip8Error> {
+        if self.at == self.bytes.len() { Ok(()) }
+        else { Err(Chip8Error::BadSaveState { reason: "unexpected data after the machine state" }) }
+    }
+}
+
+pub(crate) fn platform_from_u8(byte: u8) -> Result<Platform, Chip8Error> {
+    match byte {
+        0 => Ok(Platform::Chip8),
+        1 => Ok(Platform::SuperChip),
+        2 => Ok(Platform::XoChip),
+        _ => Err(Chip8Error::BadSaveState { reason: "unknown platform" })
+    }
+}
+
+pub(crate) fn quirk_bits(quirks: &Quirks) -> u8 {
+    [quirks.shift_uses_vy, quirks.load_store_increments_i, quirks.jump_uses_vx,
+     quirks.logic_resets_vf, quirks.clip_sprites, quirks.display_wait, quirks.increment_i_by_x]
+        .iter().enumerate()
+        .fold(0, |bits, (bit, set)| bits | ((*set as u8) << bit))
+}
+
+pub(crate) fn quirks_from_bits(bits: u8) -> Quirks {
+    let bit = |n: u8| bits & (1 << n) != 0;
+    Quirks {
+        shift_uses_vy: bit(0),
+        load_store_increments_i: bit(1),
+        jump_uses_vx: bit(2),
+        logic_resets_vf: bit(3),
+        clip_sprites: bit(4),
+        display_wait: bit(5),
+        increment_i_by_x: bit(6),
+    }
+}
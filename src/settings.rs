@@ -0,0 +1,105 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::config::Config;
+
+const MAX_RECENT_ROMS: usize = 10;
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub config: Config,
+    pub recent_roms: Vec<String>,
+}
+
+impl Settings {
+    pub fn path() -> Option<PathBuf> { config_dir().map(|dir| dir.join("settings.toml")) }
+
+    // Missing settings fall back to the defaults; broken ones are an error for the caller to report
+    pub fn load() -> Result<Settings, String> {
+        let Some(path) = Settings::path() else { return Ok(Settings::default()) };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Settings::default()),
+            Err(e) => return Err(format!("{}: {e}", path.display()))
+        };
+
+        Settings::parse(&text).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Settings, String> { toml::from_str(text).map_err(|e| e.to_string()) }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = Settings::path().ok_or(io::Error::new(io::ErrorKind::NotFound, "no config directory"))?;
+        let text = toml::to_string(self).map_err(io::Error::other)?;
+
+        fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+        fs::write(path, text)
+    }
+
+    pub fn add_recent_rom(&mut self, rom_path: &str) {
+        self.recent_roms.retain(|path| path != rom_path);
+        self.recent_roms.insert(0, String::from(rom_path));
+        self.recent_roms.truncate(MAX_RECENT_ROMS);
+    }
+}
+
+// $XDG_CONFIG_HOME/chip-8, falling back to ~/.config/chip-8
+pub fn config_dir() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(base.join("chip-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Platform, Quirks};
+
+    #[test]
+    fn round_trips_through_toml() {
+        let mut settings = Settings::default();
+        settings.config.rom_path = String::from("games/pong.ch8");
+        settings.config.cpu_hertz = 1200;
+        settings.config.platform = Platform::SuperChip;
+        settings.config.quirks = Quirks::super_chip();
+        settings.config.seed = Some(7);
+        settings.add_recent_rom("games/pong.ch8");
+
+        let parsed = Settings::parse(&toml::to_string(&settings).unwrap()).unwrap();
+        assert_eq!(parsed.config.rom_path, "games/pong.ch8");
+        assert_eq!(parsed.config.cpu_hertz, 1200);
+        assert_eq!(parsed.config.platform, Platform::SuperChip);
+        assert_eq!(parsed.config.quirks, Quirks::super_chip());
+        assert_eq!(parsed.config.seed, Some(7));
+        assert_eq!(parsed.recent_roms, ["games/pong.ch8"]);
+    }
+
+    #[test]
+    fn missing_values_fall_back_to_the_defaults() {
+        let settings = Settings::parse("[config]\ncpu_hertz = 900\n").unwrap();
+        assert_eq!(settings.config.cpu_hertz, 900);
+        assert_eq!(settings.config.rom_offset, Config::default().rom_offset);
+        assert_eq!(settings.config.quirks, Quirks::classic());
+        assert!(settings.recent_roms.is_empty());
+
+        assert!(Settings::parse("[config]\ncpu_hertz = \"fast\"\n").is_err());
+    }
+
+    #[test]
+    fn recent_roms_are_most_recent_first_without_duplicates() {
+        let mut settings = Settings::default();
+        for rom in ["a", "b", "c", "a"] { settings.add_recent_rom(rom); }
+        assert_eq!(settings.recent_roms, ["a", "c", "b"]);
+
+        for rom in 0..20 { settings.add_recent_rom(&rom.to_string()); }
+        assert_eq!(settings.recent_roms.len(), MAX_RECENT_ROMS);
+        assert_eq!(settings.recent_roms[0], "19");
+        assert_eq!(settings.recent_roms[MAX_RECENT_ROMS - 1], "10");
+    }
+}
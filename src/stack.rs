@@ -1,3 +1,6 @@
+use crate::error::Chip8Error;
+use crate::savestate::{Snapshot, StateReader, StateWriter};
+
 pub struct StackPointer{
     array: [usize; 16],
     length: usize
@@ -11,21 +14,47 @@ impl StackPointer {
         }
     }
 
-    pub fn push(&mut self, addr: usize){
-        if self.length == 16 { panic!("Stack Overflow") }
+    pub fn push(&mut self, addr: usize) -> Option<()>{
+        if self.length == 16 { return None }
 
         self.array[self.length] = addr;
         self.length += 1;
+
+        Some(())
     }
 
-    pub fn pop(&mut self) -> usize{
-        if self.length == 0 { panic!("The stack pointer is empty") }
+    pub fn pop(&mut self) -> Option<usize>{
+        if self.length == 0 { return None }
 
         let result: usize = self.array[self.length - 1];
 
         self.array[self.length - 1] = 0;
         self.length -= 1;
 
-        result
+        Some(result)
     }
-}
\ No newline at end of file
+
+    pub fn as_slice(&self) -> &[usize] { &self.array[..self.length] }
+}
+
+impl Default for StackPointer {
+    fn default() -> StackPointer { StackPointer::new() }
+}
+
+impl Snapshot for StackPointer {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.u8(self.length as u8);
+        for addr in self.as_slice() { writer.u32(*addr as u32); }
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), Chip8Error> {
+        let length = reader.u8()? as usize;
+        if length > self.array.len() { return Err(Chip8Error::BadSaveState { reason: "stack is too deep" }) }
+
+        *self = StackPointer::new();
+        for _ in 0..length {
+            self.push(reader.u32()? as usize);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::error::Chip8Error;
+use crate::number;
+
+// Names for addresses in a program, such as the labels of a compiled Octo source
+#[derive(Clone, Default, Debug)]
+pub struct SymbolTable {
+    names: BTreeMap<usize, String>
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable { SymbolTable::default() }
+
+    // Several names for one address keep the first
+    pub fn insert(&mut self, addr: usize, name: &str) {
+        self.names.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    pub fn name(&self, addr: usize) -> Option<&str> { self.names.get(&addr).map(String::as_str) }
+
+    pub fn addr(&self, name: &str) -> Option<usize> {
+        self.names.iter().find(|(_, symbol)| *symbol == name).map(|(addr, _)| *addr)
+    }
+
+    pub fn is_empty(&self) -> bool { self.names.is_empty() }
+
+    pub fn len(&self) -> usize { self.names.len() }
+
+    pub fn extend(&mut self, other: &SymbolTable) {
+        for (addr, name) in other.iter() { self.insert(addr, name); }
+    }
+
+    // The closest symbol at or before `addr` as `name` or `name+offset`
+    pub fn describe(&self, addr: usize) -> Option<String> {
+        let (start, name) = self.names.range(..=addr).next_back()?;
+        Some(if *start == addr { name.clone() } else { format!("{name}+{}", addr - start) })
+    }
+
+    // `addr` in hex, followed by the symbol it falls under when there is one
+    pub fn annotate(&self, addr: usize) -> String {
+        match self.describe(addr) {
+            Some(symbol) => format!("{addr:#05X} ({symbol})"),
+            None => format!("{addr:#05X}")
+        }
+    }
+
+    // Reads a symbol file, refusing one that was written for a different ROM
+    pub fn read(path: &Path, rom_hash: &str) -> Result<SymbolTable, Chip8Error> {
+        let (hash, symbols) = SymbolTable::parse(&fs::read_to_string(path)?)?;
+        if hash.is_some_and(|hash| !hash.eq_ignore_ascii_case(rom_hash)) { return Err(Chip8Error::SymbolsRomMismatch) }
+        Ok(symbols)
+    }
+
+    pub fn write(&self, path: &Path, rom_hash: &str) -> Result<(), Chip8Error> {
+        let mut text = format!("rom {rom_hash}\n");
+        for (addr, name) in self.iter() { text.push_str(&format!("{addr:#05X} {name}\n")); }
+        Ok(fs::write(path, text)?)
+    }
+
+    // A symbol file lists one `ADDR NAME` pair per line and may start with `rom SHA1` to tie
+    // it to one ROM; `;` starts a comment, like in the assembler, so `#2A4` stays a hex address.
+    // Returns the ROM hash, if any, and the symbols.
+    //
+    //     rom 0b1b5a3e7a9e5c37b1c2b2ddbcd1f3fbe6f5f0b5
+    //     0x200 main
+    //     #2A4 draw-player     ; called once per frame
+    pub fn parse(text: &str) -> Result<(Option<String>, SymbolTable), Chip8Error> {
+        let mut hash = None;
+        let mut symbols = SymbolTable::new();
+        for (number, line) in text.lines().enumerate() {
+            let error = |message: String| Chip8Error::BadSource { line: number + 1, message };
+            let words: Vec<&str> = line.split(';').next().unwrap_or("").split_whitespace().collect();
+            let (first, second) = match words[..] {
+                [] => continue,
+                [first, second] => (first, second),
+                _ => return Err(error(String::from("expected an address and a name")))
+            };
+
+            if first == "rom" {
+                hash = Some(second.to_string());
+                continue;
+            }
+            let addr = number::parse(first).ok_or_else(|| error(format!("invalid address `{first}`")))?;
+            symbols.insert(addr, second);
+        }
+        Ok((hash, symbols))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.names.iter().map(|(addr, name)| (*addr, name.as_str()))
+    }
+}
+
+// Symbol files live beside the ROM: game.ch8 -> game.ch8.sym
+pub fn symbol_path(rom_path: &str) -> PathBuf {
+    PathBuf::from(format!("{rom_path}.sym"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0b1b5a3e7a9e5c37b1c2b2ddbcd1f3fbe6f5f0b5";
+
+    fn error_line(text: &str) -> (usize, String) {
+        match SymbolTable::parse(text) {
+            Err(Chip8Error::BadSource { line, message }) => (line, message),
+            _ => panic!("`{text}` parsed")
+        }
+    }
+
+    #[test]
+    fn parses_addresses_in_every_base() {
+        let text = format!("; labels of the game\nrom {HASH}\n0x200 main\n#2A4 loop ; the main loop\n\n%1010101010 data\n700 end\n0x200 start\n");
+        let (hash, symbols) = SymbolTable::parse(&text).unwrap();
+        assert_eq!(hash.as_deref(), Some(HASH));
+        assert_eq!(symbols.iter().collect::<Vec<_>>(), [(0x200, "main"), (0x2A4, "loop"), (0x2AA, "data"), (700, "end")]);
+        assert_eq!(symbols.addr("loop"), Some(0x2A4));
+
+        assert_eq!(error_line("0x200 main\n0x2A4"), (2, String::from("expected an address and a name")));
+        assert_eq!(error_line("0x200 main extra"), (1, String::from("expected an address and a name")));
+        assert_eq!(error_line("\n\nstart main"), (3, String::from("invalid address `start`")));
+    }
+
+    #[test]
+    fn describes_addresses_from_the_closest_symbol() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x200, "main");
+        symbols.insert(0x2A4, "loop");
+        assert_eq!(symbols.describe(0x1FE), None);
+        assert_eq!(symbols.describe(0x200).as_deref(), Some("main"));
+        assert_eq!(symbols.describe(0x2A6).as_deref(), Some("loop+2"));
+        assert_eq!(symbols.annotate(0x202), "0x202 (main+2)");
+        assert_eq!(symbols.annotate(0x100), "0x100");
+    }
+
+    #[test]
+    fn round_trips_through_a_file_for_its_rom() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x200, "main");
+        symbols.insert(0x2A4, "draw-player");
+        let path = std::env::temp_dir().join(format!("chip-8-symbols-{}.sym", std::process::id()));
+        symbols.write(&path, HASH).unwrap();
+
+        let read = SymbolTable::read(&path, &HASH.to_ascii_uppercase());
+        let other = SymbolTable::read(&path, "ffffffffffffffffffffffffffffffffffffffff");
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.unwrap().iter().collect::<Vec<_>>(), symbols.iter().collect::<Vec<_>>());
+        assert!(matches!(other, Err(Chip8Error::SymbolsRomMismatch)));
+    }
+
+    #[test]
+    fn files_without_a_hash_fit_any_rom() {
+        let path = std::env::temp_dir().join(format!("chip-8-symbols-nohash-{}.sym", std::process::id()));
+        fs::write(&path, "0x200 main\n").unwrap();
+        let read = SymbolTable::read(&path, HASH);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap().name(0x200), Some("main"));
+        assert_eq!(symbol_path("games/pong.ch8"), PathBuf::from("games/pong.ch8.sym"));
+    }
+}
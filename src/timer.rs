@@ -1,43 +1,94 @@
-use crate::DELTA_TIME;
+use crate::error::Chip8Error;
+use crate::savestate::{Snapshot, StateReader, StateWriter};
 
 pub struct Timer{
     pub number: u8,
-    flag: bool,
-    acc: f64,
-    cycle: f64
+    hertz: u32,
+    acc: u32
 }
 
+// Timers run on emulated time: every CPU cycle adds `hertz` to the accumulator and
+// the timer decrements each time it has gathered a full second's worth of CPU cycles.
 impl Timer {
     pub fn new(hertz: u8) -> Timer{
         Timer {
             number: 0,
-            flag: false,
-            acc: 0.0,
-            cycle: 1.0 / (hertz as f64)
+            hertz: hertz as u32,
+            acc: 0
         }
     }
 
-    pub fn set(&mut self, number: u8){ 
-        if number == 0 { return; }
-        self.number = number;
-        self.flag = true;
-    }
+    pub fn set(&mut self, number: u8){ self.number = number; }
 
     pub fn get(&self) -> u8 { self.number }
 
-    pub fn check(&mut self) {
-        if self.flag {
-            unsafe { self.acc += DELTA_TIME; }
+    // Between cycles a timer never holds a whole CPU second of time
+    pub(crate) fn is_valid(&self, cpu_hertz: u32) -> bool { self.acc < cpu_hertz }
 
-            while self.acc >= self.cycle{
-                self.decrement();
-                self.acc -= self.cycle;
-            }
+    pub fn check(&mut self, cpu_hertz: u32) {
+        self.acc += self.hertz;
+
+        while self.acc >= cpu_hertz {
+            self.acc -= cpu_hertz;
+            self.number = self.number.saturating_sub(1);
         }
     }
+}
 
-    fn decrement(&mut self) {
-        self.number -= 1;
-        if self.number == 0 { self.flag = false; }
+impl Snapshot for Timer {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.u8(self.number);
+        writer.u32(self.hertz);
+        writer.u32(self.acc);
     }
-}
\ No newline at end of file
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), Chip8Error> {
+        self.number = reader.u8()?;
+        self.hertz = reader.u32()?;
+        self.acc = reader.u32()?;
+        if self.hertz > u8::MAX as u32 { return Err(Chip8Error::BadSaveState { reason: "invalid timer rate" }) }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_down_at_its_rate_in_emulated_time() {
+        for cpu_hertz in [30, 60, 500, 700, 1000, 3000] {
+            let mut timer = Timer::new(60);
+            timer.set(200);
+            for _ in 0..cpu_hertz { timer.check(cpu_hertz); }
+            assert_eq!(timer.get(), 140, "at {cpu_hertz} Hz");
+            assert!(timer.is_valid(cpu_hertz));
+        }
+    }
+
+    #[test]
+    fn ticks_evenly_and_stops_at_zero() {
+        let mut timer = Timer::new(60);
+        timer.set(3);
+        let ticks = (0..70).filter(|_| {
+            let before = timer.get();
+            timer.check(700);
+            timer.get() != before
+        }).count();
+        assert_eq!(ticks, 3);
+        assert_eq!(timer.get(), 0);
+
+        // 1000 Hz puts a tick every 16 or 17 cycles
+        let mut timer = Timer::new(60);
+        timer.set(255);
+        let mut last = 0;
+        for cycle in 1..=200 {
+            let before = timer.get();
+            timer.check(1000);
+            if timer.get() != before {
+                if last != 0 { assert!((16..=17).contains(&(cycle - last))); }
+                last = cycle;
+            }
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use crate::chip::Chip8;
+use crate::disasm::{self, Syntax};
+use crate::error::Chip8Error;
+use crate::number;
+
+// A text trace has one line per instruction with the state it started from:
+//
+//        152  0x2A6  D015  DRW V0, V1, 5             V 0A1F0000...  I 0x2C0  DT 00  ST 00  ; draw+2
+//
+// A binary trace is MAGIC and the format version (u16), then one 32-byte record per
+// instruction: the cycle (u64), PC (u16), opcode (u16), V0 to VF, I (u16), the delay
+// timer and the sound timer. All numbers are little-endian.
+pub const MAGIC: [u8; 4] = *b"C8TR";
+pub const VERSION: u16 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TraceFormat {
+    Text,
+    Binary
+}
+
+impl TraceFormat {
+    pub fn parse(name: &str) -> Option<TraceFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "text" => Some(TraceFormat::Text),
+            "binary" => Some(TraceFormat::Binary),
+            _ => None
+        }
+    }
+}
+
+// Where tracing starts or stops: when the PC reaches an address or after a number of cycles
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Trigger {
+    Address(usize),
+    Cycle(u64)
+}
+
+impl Trigger {
+    // "0x2A4" or "cycle:5000"
+    pub fn parse(text: &str) -> Result<Trigger, String> {
+        let invalid = || format!("invalid trace trigger `{text}`");
+        let text = text.trim();
+
+        match text.strip_prefix("cycle:") {
+            Some(cycle) => number::parse(cycle.trim()).map(Trigger::Cycle).ok_or_else(invalid),
+            None => number::parse(text).map(Trigger::Address).ok_or_else(invalid)
+        }
+    }
+
+    fn hit(self, cycle: u64, pc: usize) -> bool {
+        match self {
+            Trigger::Address(addr) => addr == pc,
+            Trigger::Cycle(at) => at == cycle
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceOptions {
+    pub format: TraceFormat,
+    pub start: Option<Trigger>,
+    pub stop: Option<Trigger>,
+    pub ring: Option<usize>     // keep only the last N instructions and write them when emulation fails
+}
+
+impl Default for TraceOptions {
+    fn default() -> TraceOptions {
+        TraceOptions { format: TraceFormat::Text, start: None, stop: None, ring: None }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Waiting,
+    Tracing,
+    Stopped
+}
+
+struct Entry {
+    cycle: u64,
+    pc: usize,
+    opcode: u16,
+    registers: [u8; 16],
+    i_register: u16,
+    delay_timer: u8,
+    sound_timer: u8,
+    mnemonic: String,
+    symbol: Option<String>
+}
+
+pub struct Tracer {
+    output: BufWriter<File>,
+    options: TraceOptions,
+    cycle: u64,
+    state: State,
+    ring: VecDeque<Entry>
+}
+
+impl Tracer {
+    pub fn create(path: &str, options: TraceOptions) -> Result<Tracer, Chip8Error> {
+        let mut output = BufWriter::new(File::create(path)?);
+        if options.format == TraceFormat::Binary {
+            output.write_all(&MAGIC)?;
+            output.write_all(&VERSION.to_le_bytes())?;
+        }
+        let state = if options.start.is_some() { State::Waiting } else { State::Tracing };
+        Ok(Tracer { output, options, cycle: 0, state, ring: VecDeque::new() })
+    }
+
+    // Called before every cycle, while the machine still holds the state the instruction starts from
+    pub(crate) fn record(&mut self, chip8: &Chip8) -> Result<(), Chip8Error> {
+        let (cycle, pc) = (self.cycle, chip8.pc());
+        self.cycle += 1;
+
+        if self.state == State::Waiting && self.options.start.is_some_and(|start| start.hit(cycle, pc)) {
+            self.state = State::Tracing;
+        }
+        if self.state == State::Tracing && self.options.stop.is_some_and(|stop| stop.hit(cycle, pc)) {
+            self.state = State::Stopped;
+            self.output.flush()?;
+        }
+        if self.state != State::Tracing { return Ok(()) }
+
+        let memory = chip8.memory();
+        let opcode = memory.get(pc..pc + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]])).unwrap_or_default();
+        let entry = Entry {
+            cycle,
+            pc,
+            opcode,
+            registers: *chip8.registers(),
+            i_register: chip8.i_register(),
+            delay_timer: chip8.delay_timer(),
+            sound_timer: chip8.sound_timer(),
+            mnemonic: disasm::mnemonic(memory, pc, chip8.platform(), Syntax::Cowgod, chip8.symbols()).unwrap_or_else(|| String::from("???")),
+            symbol: chip8.symbols().describe(pc)
+        };
+
+        match self.options.ring {
+            Some(length) => {
+                if self.ring.len() >= length { self.ring.pop_front(); }
+                self.ring.push_back(entry);
+            },
+            None => self.write(&entry)?
+        }
+        Ok(())
+    }
+
+    // Writes out the instructions that led up to an error
+    pub(crate) fn dump(&mut self) -> Result<(), Chip8Error> {
+        while let Some(entry) = self.ring.pop_front() { self.write(&entry)?; }
+        Ok(self.output.flush()?)
+    }
+
+    // Flushes the trace; a ring buffer is only written when emulation fails
+    pub fn finish(mut self) -> Result<(), Chip8Error> { Ok(self.output.flush()?) }
+
+    fn write(&mut self, entry: &Entry) -> io::Result<()> {
+        match self.options.format {
+            TraceFormat::Text => {
+                let registers: String = entry.registers.iter().map(|value| format!("{value:02X}")).collect();
+                write!(self.output, "{:>10}  {:#05X}  {:04X}  {:<24}  V {registers}  I {:#05X}  DT {:02X}  ST {:02X}",
+                    entry.cycle, entry.pc, entry.opcode, entry.mnemonic, entry.i_register, entry.delay_timer, entry.sound_timer)?;
+                match &entry.symbol {
+                    Some(symbol) => writeln!(self.output, "  ; {symbol}"),
+                    None => writeln!(self.output)
+                }
+            },
+            TraceFormat::Binary => {
+                self.output.write_all(&entry.cycle.to_le_bytes())?;
+                self.output.write_all(&(entry.pc as u16).to_le_bytes())?;
+                self.output.write_all(&entry.opcode.to_le_bytes())?;
+                self.output.write_all(&entry.registers)?;
+                self.output.write_all(&entry.i_register.to_le_bytes())?;
+                self.output.write_all(&[entry.delay_timer, entry.sound_timer])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+    use crate::config::Config;
+    use crate::keypad::Keypad;
+    use crate::rom::Rom;
+
+    // v0 := 1, then v0 += 1 forever: cycle 0 is at 0x200, odd cycles at 0x202 and even ones at 0x204
+    const LOOP: [u8; 6] = [0x60, 0x01, 0x70, 0x01, 0x12, 0x02];
+
+    // Runs `program` for up to `cycles` cycles with a tracer and returns the trace and whether emulation failed
+    fn trace(name: &str, program: &[u8], options: TraceOptions, cycles: usize) -> (Vec<u8>, bool) {
+        let path = std::env::temp_dir().join(format!("chip-8-trace-{}-{name}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut chip8 = Chip8::new(Rom::new(program.to_vec()), &Config::default()).unwrap();
+        chip8.set_tracer(Some(Tracer::create(path, options).unwrap()));
+
+        let failed = (0..cycles).any(|_| chip8.run_cycle(Keypad::new()).is_err());
+        chip8.take_tracer().unwrap().finish().unwrap();
+        let bytes = fs::read(path).unwrap();
+        fs::remove_file(path).unwrap();
+        (bytes, failed)
+    }
+
+    // The cycle, PC and opcode of each record in a binary trace
+    fn records(bytes: &[u8]) -> Vec<(u64, u16, u16)> {
+        assert_eq!(bytes[..4], MAGIC);
+        assert_eq!(u16::from_le_bytes([bytes[4], bytes[5]]), VERSION);
+        assert_eq!((bytes.len() - 6) % 32, 0);
+        bytes[6..].chunks(32)
+            .map(|record| (
+                u64::from_le_bytes(record[..8].try_into().unwrap()),
+                u16::from_le_bytes([record[8], record[9]]),
+                u16::from_le_bytes([record[10], record[11]])
+            ))
+            .collect()
+    }
+
+    fn binary() -> TraceOptions { TraceOptions { format: TraceFormat::Binary, ..TraceOptions::default() } }
+
+    #[test]
+    fn writes_one_text_line_per_instruction() {
+        let (bytes, _) = trace("text", &LOOP, TraceOptions::default(), 3);
+        let text = String::from_utf8(bytes).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("         0  0x200  6001  LD V0, 0x01"), "{}", lines[0]);
+        assert!(lines[2].contains("JP 0x202") && lines[2].contains("V 02000000"), "{}", lines[2]);
+    }
+
+    #[test]
+    fn binary_records_hold_the_state_before_each_instruction() {
+        let (bytes, _) = trace("binary", &LOOP, binary(), 3);
+        assert_eq!(records(&bytes), [(0, 0x200, 0x6001), (1, 0x202, 0x7001), (2, 0x204, 0x1202)]);
+
+        let record = &bytes[6 + 32..6 + 64];
+        assert_eq!(record[12], 1, "V0 after v0 := 1");
+        assert_eq!(&record[13..28], &[0; 15]);
+        assert_eq!(u16::from_le_bytes([record[28], record[29]]), 0, "I");
+    }
+
+    #[test]
+    fn traces_between_the_triggers() {
+        let options = TraceOptions { start: Some(Trigger::Address(0x204)), stop: Some(Trigger::Cycle(6)), ..binary() };
+        let (bytes, _) = trace("triggers", &LOOP, options, 20);
+        let cycles: Vec<u64> = records(&bytes).iter().map(|(cycle, _, _)| *cycle).collect();
+        assert_eq!(cycles, [2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn a_ring_buffer_is_only_written_when_emulation_fails() {
+        let options = TraceOptions { ring: Some(2), ..binary() };
+        let (bytes, failed) = trace("ring-ok", &LOOP, options.clone(), 20);
+        assert!(!failed);
+        assert!(records(&bytes).is_empty());
+
+        // v0 := 1, three v0 += 1 and an opcode that does not exist
+        let program = [0x60, 0x01, 0x70, 0x01, 0x70, 0x01, 0x70, 0x01, 0xFF, 0xFF];
+        let (bytes, failed) = trace("ring-error", &program, options, 20);
+        assert!(failed);
+        assert_eq!(records(&bytes), [(3, 0x206, 0x7001), (4, 0x208, 0xFFFF)]);
+    }
+
+    #[test]
+    fn parses_triggers() {
+        assert_eq!(Trigger::parse("0x2A4"), Ok(Trigger::Address(0x2A4)));
+        assert_eq!(Trigger::parse(" cycle: 5000 "), Ok(Trigger::Cycle(5000)));
+        assert!(Trigger::parse("cycle:").is_err());
+        assert!(Trigger::parse("here").is_err());
+    }
+}
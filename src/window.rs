@@ -0,0 +1,297 @@
+use chip_8::{Chip8, Chip8Error, Config, Keypad};
+use chip_8::audio::{Beeper, SAMPLE_RATE};
+use chip_8::config::KeyBindings;
+use chip_8::debugger::{self, Debugger};
+use chip_8::disasm::{self, Syntax};
+use chip_8::movie::Movie;
+use chip_8::rewind::Rewind;
+use chip_8::savestate;
+use raylib::prelude::*;
+use std::fs;
+use std::time::{Instant, Duration};
+use spin_sleep::sleep;
+
+const AUDIO_BUFFER_SIZE: usize = 1024;
+
+// Debugger panel to the right of the screen
+const PANEL_WIDTH: i32 = 300;
+const FONT_SIZE: i32 = 10;
+const LINE_HEIGHT: i32 = 12;
+const LISTING_LINES: usize = 12;
+const LISTING_Y: i32 = 8 + 10 * LINE_HEIGHT;
+
+const KEY_MAP: [KeyboardKey; 16] = [
+    KeyboardKey::KEY_ONE, KeyboardKey::KEY_TWO, KeyboardKey::KEY_THREE, KeyboardKey::KEY_FOUR,
+    KeyboardKey::KEY_Q, KeyboardKey::KEY_W, KeyboardKey::KEY_E, KeyboardKey::KEY_R,
+    KeyboardKey::KEY_A, KeyboardKey::KEY_S, KeyboardKey::KEY_D, KeyboardKey::KEY_F,
+    KeyboardKey::KEY_Z, KeyboardKey::KEY_X, KeyboardKey::KEY_C, KeyboardKey::KEY_V,
+];
+
+// F1-F4 save to a slot, Shift+F1-F4 load from it
+const SLOT_KEYS: [KeyboardKey; savestate::SLOTS as usize] = [
+    KeyboardKey::KEY_F1, KeyboardKey::KEY_F2, KeyboardKey::KEY_F3, KeyboardKey::KEY_F4,
+];
+
+pub fn run(mut chip8: Chip8, config: &Config, mut recording: Option<(String, Movie)>, mut replay: Option<Movie>, mut debugger: Debugger) {
+    let (width, height) = (64 * config.scale as i32, 32 * config.scale as i32);
+    let mut panel = debugger.is_paused();
+    let (mut raylib_handler, raylib_thread_handler) = raylib::init()
+    .size(width + if panel { PANEL_WIDTH } else { 0 }, height)
+    .build();
+
+    unsafe { raylib::ffi::SetAudioStreamBufferSizeDefault(AUDIO_BUFFER_SIZE as i32); }
+    let mut audio = RaylibAudio::init_audio_device();
+    let mut audio_stream = AudioStream::init_audio_stream(&raylib_thread_handler, SAMPLE_RATE, 16, 1);
+    let mut audio_buffer = [0_i16; AUDIO_BUFFER_SIZE];
+    let mut beeper = Beeper::new(config);
+    audio.play_audio_stream(&mut audio_stream);
+
+    // Like Chip8::new, a rate of 0 from a settings file or the database runs at 1 Hz
+    let cpu_hertz = config.cpu_hertz.max(1);
+    let cycle = 1.0_f64 / cpu_hertz as f64;
+    raylib_handler.set_target_fps(cpu_hertz);
+    let mut halted = false;
+    let mut rewind = Rewind::new(config.rewind_seconds as usize * 60);
+    let mut frame_acc = 0;
+    // Movies hold one keypad state for a whole emulated frame
+    let mut frame_keypad: Option<Keypad> = None;
+    let mut replay_frame = 0;
+    
+    while !raylib_handler.window_should_close() {
+        let now = Instant::now();
+
+        if let Some(slot) = SLOT_KEYS.iter().position(|key| raylib_handler.is_key_pressed(*key)) {
+            let slot = slot as u8 + 1;
+            let path = savestate::slot_path(&config.rom_path, slot);
+            let load = raylib_handler.is_key_down(KeyboardKey::KEY_LEFT_SHIFT) || raylib_handler.is_key_down(KeyboardKey::KEY_RIGHT_SHIFT);
+
+            let result = if load {
+                fs::read(&path).map_err(Chip8Error::from).and_then(|state| chip8.load_state(&state))
+            } else {
+                fs::write(&path, chip8.save_state()).map_err(Chip8Error::from)
+            };
+            let message = match result {
+                Ok(()) if load => {
+                    halted = false;
+                    stop_movie(&mut recording, &mut replay);
+                    format!("loaded slot {slot}")
+                },
+                Ok(()) => format!("saved slot {slot}"),
+                Err(e) => format!("slot {slot}: {e}")
+            };
+            raylib_handler.set_window_title(&raylib_thread_handler, &format!("Chip-8 Emulator - {message}"));
+        }
+
+        // One snapshot per 60 Hz frame, counted in emulated cycles like the timers
+        frame_acc += 60;
+        let frame = frame_acc >= cpu_hertz;
+        if frame { frame_acc -= cpu_hertz; }
+
+        // Holding Backspace walks back through the snapshots, one per frame
+        if raylib_handler.is_key_down(KeyboardKey::KEY_BACKSPACE) && !rewind.is_empty() {
+            if frame {
+                if let Some(state) = rewind.pop() {
+                    if chip8.load_state(&state).is_ok() {
+                        halted = false;
+                        stop_movie(&mut recording, &mut replay);
+                    }
+                }
+            }
+            draw(&mut raylib_handler, &raylib_thread_handler, &chip8, config, panel.then_some(&debugger));
+            continue;
+        }
+
+        if halted {
+            draw(&mut raylib_handler, &raylib_thread_handler, &chip8, config, panel.then_some(&debugger));
+            continue;
+        }
+
+        // F5 pause/resume, F6 step, F7 step over, F8 step out, F9 breakpoint at PC, F10 panel;
+        // in the panel listing a left click runs to a line and a right click toggles its breakpoint
+        let live_keypad = read_keypad(&raylib_handler, &config.keys);
+        let mut step = Ok(());
+        if raylib_handler.is_key_pressed(KeyboardKey::KEY_F5) {
+            if debugger.is_paused() { debugger.resume(); } else { debugger.pause("paused"); }
+        }
+        // Steps run cycles outside the frames a movie stores keypads for, so it could not reproduce them
+        let (step_key, step_over_key) = (raylib_handler.is_key_pressed(KeyboardKey::KEY_F6), raylib_handler.is_key_pressed(KeyboardKey::KEY_F7));
+        if (step_key || step_over_key) && (recording.is_some() || replay.is_some()) {
+            raylib_handler.set_window_title(&raylib_thread_handler, "Chip-8 Emulator - stepping is disabled while a movie is recorded or replayed");
+        } else if step_key {
+            step = debugger.step(&mut chip8, live_keypad);
+        } else if step_over_key {
+            step = debugger.step_over(&mut chip8, live_keypad);
+        }
+        if raylib_handler.is_key_pressed(KeyboardKey::KEY_F8) { debugger.step_out(&chip8); }
+        if raylib_handler.is_key_pressed(KeyboardKey::KEY_F9) { debugger.toggle_breakpoint(chip8.pc()); }
+        if raylib_handler.is_key_pressed(KeyboardKey::KEY_F10) { panel = !panel; }
+        if panel {
+            let mouse = raylib_handler.get_mouse_position();
+            let line = (mouse.y as i32 - LISTING_Y).div_euclid(LINE_HEIGHT);
+            if mouse.x as i32 >= width && (0..LISTING_LINES as i32).contains(&line) {
+                let addr = chip8.pc() + 2 * line as usize;
+                if raylib_handler.is_mouse_button_pressed(MouseButton::MOUSE_LEFT_BUTTON) { debugger.run_to(addr); }
+                if raylib_handler.is_mouse_button_pressed(MouseButton::MOUSE_RIGHT_BUTTON) { debugger.toggle_breakpoint(addr); }
+            }
+        }
+        if let Err(e) = step {
+            let e = debugger::describe_error(&chip8, &e);
+            eprintln!("Emulation halted: {e}");
+            raylib_handler.set_window_title(&raylib_thread_handler, &format!("Chip-8 Emulator - halted: {e}"));
+            halted = true;
+        }
+
+        // Breakpoints open the panel
+        panel |= debugger.is_paused();
+        let window_width = width + if panel { PANEL_WIDTH } else { 0 };
+        if raylib_handler.get_screen_width() != window_width { raylib_handler.set_window_size(window_width, height); }
+
+        if debugger.is_paused() {
+            draw(&mut raylib_handler, &raylib_thread_handler, &chip8, config, panel.then_some(&debugger));
+            continue;
+        }
+
+        let keypad = match frame_keypad {
+            Some(keypad) => keypad,
+            None => {
+                let keypad = match &replay {
+                    Some(movie) => movie.frames.get(replay_frame).copied().unwrap_or_default(),
+                    None => read_keypad(&raylib_handler, &config.keys)
+                };
+                if replay.is_some() { replay_frame += 1; }
+                if let Some((_, movie)) = &mut recording { movie.frames.push(keypad); }
+                if recording.is_some() || replay.is_some() { frame_keypad = Some(keypad); }
+                keypad
+            }
+        };
+
+        if let Err(e) = debugger.run_cycle(&mut chip8, keypad) {
+            let e = debugger::describe_error(&chip8, &e);
+            eprintln!("Emulation halted: {e}");
+            raylib_handler.set_window_title(&raylib_thread_handler, &format!("Chip-8 Emulator - halted: {e}"));
+            halted = true;
+        }
+
+        if chip8.is_vblank() || halted || chip8.has_exited() {
+            frame_keypad = None;
+            if replay.as_ref().is_some_and(|movie| replay_frame >= movie.frames.len()) {
+                let message = if replay.take().is_some_and(|movie| movie.matches(&chip8)) { "replay matches the recording" }
+                    else { "replay diverged from the recording" };
+                eprintln!("{message}");
+                raylib_handler.set_window_title(&raylib_thread_handler, &format!("Chip-8 Emulator - {message}"));
+            }
+        }
+
+        if chip8.has_exited() { break }
+
+        if frame { rewind.push(chip8.save_state()); }
+
+        if raylib_handler.is_key_pressed(KeyboardKey::KEY_M) { beeper.toggle_mute(); }
+
+        if audio.is_audio_stream_processed(&audio_stream) {
+            beeper.fill(&mut audio_buffer, &chip8);
+            audio_stream.update_audio_stream(&audio_buffer);
+        }
+
+        if chip8.draw_flag { 
+            draw(&mut raylib_handler, &raylib_thread_handler, &chip8, config, panel.then_some(&debugger)); 
+            chip8.draw_flag = false;
+        }
+
+        if let Some(dur) = Duration::from_secs_f64(cycle).checked_sub(now.elapsed()){
+            sleep(dur);
+        }
+    }
+
+    if let Some((path, mut movie)) = recording {
+        movie.finish(&chip8);
+        if let Err(e) = movie.write(&path) { eprintln!("Could not save {path}: {e}"); }
+    }
+    if let Some(tracer) = chip8.take_tracer() {
+        if let Err(e) = tracer.finish() { eprintln!("Could not save the trace: {e}"); }
+    }
+}
+
+// Loading a state breaks the link between the movie and the machine
+fn stop_movie(recording: &mut Option<(String, Movie)>, replay: &mut Option<Movie>) {
+    if recording.take().is_some() { eprintln!("Stopped recording: the machine state was replaced"); }
+    if replay.take().is_some() { eprintln!("Stopped the replay: the machine state was replaced"); }
+}
+
+fn draw(raylib_handler: &mut RaylibHandle, raylib_thread_handler: &RaylibThread, chip8: &Chip8, config: &Config, panel: Option<&Debugger>){
+    let palette = &config.palette;
+    let mut draw_handler = raylib_handler.begin_drawing(raylib_thread_handler);
+    draw_handler.clear_background(rgb(palette[0]));
+
+    // High resolution pixels are half a scale unit, so odd scales need fractional rectangles
+    let display = chip8.framebuffer();
+    let size = 64.0 * config.scale as f32 / display.width() as f32;
+    for y in 0..display.height() {
+        for x in 0..display.width() {
+            let color = display.pixel(x, y);
+            if color != 0 {
+                let pixel = Rectangle::new(x as f32 * size, y as f32 * size, size, size);
+                draw_handler.draw_rectangle_rec(pixel, rgb(palette[color as usize]));
+            }
+        }
+    }
+
+    if let Some(debugger) = panel {
+        draw_panel(&mut draw_handler, 64 * config.scale as i32, chip8, debugger);
+    }
+}
+
+fn draw_panel(draw_handler: &mut RaylibDrawHandle, x: i32, chip8: &Chip8, debugger: &Debugger) {
+    draw_handler.draw_rectangle(x, 0, PANEL_WIDTH, draw_handler.get_screen_height(), Color::new(24, 24, 24, 255));
+    let x = x + 8;
+
+    let status = if debugger.is_paused() { format!("PAUSED ({})", debugger.reason()) } else { String::from("RUNNING") };
+    let mut lines = vec![status];
+    lines.extend(debugger::state_lines(chip8));
+    let breakpoints = debugger.breakpoints().iter().map(|breakpoint| breakpoint.to_string()).collect::<Vec<_>>().join(", ");
+    lines.push(format!("Breaks {}", if breakpoints.is_empty() { "-" } else { &breakpoints }));
+    let watchpoints = debugger.watchpoints().iter().map(|watchpoint| watchpoint.to_string()).collect::<Vec<_>>().join(", ");
+    lines.push(format!("Watch {}", if watchpoints.is_empty() { "-" } else { &watchpoints }));
+    for (row, line) in lines.iter().enumerate() {
+        draw_handler.draw_text(line, x, 8 + row as i32 * LINE_HEIGHT, FONT_SIZE, Color::LIGHTGRAY);
+    }
+
+    // The next instructions from PC, breakpoints marked with *
+    for row in 0..LISTING_LINES {
+        let addr = chip8.pc() + 2 * row;
+        let Some(bytes) = chip8.memory().get(addr..addr + 2) else { break };
+        let marker = if debugger.breakpoints().iter().any(|breakpoint| breakpoint.addr == addr) { '*' } else { ' ' };
+        let text = disasm::mnemonic(chip8.memory(), addr, chip8.platform(), Syntax::Cowgod, chip8.symbols()).unwrap_or_default();
+        let label = chip8.symbols().name(addr).map(|name| format!("{name}: ")).unwrap_or_default();
+        let line = format!("{marker} {addr:#05X}  {:02X}{:02X}  {label}{text}", bytes[0], bytes[1]);
+        let color = if row == 0 { Color::YELLOW } else { Color::LIGHTGRAY };
+        draw_handler.draw_text(&line, x, LISTING_Y + row as i32 * LINE_HEIGHT, FONT_SIZE, color);
+    }
+
+    let help = ["F5 run/pause  F6 step  F7 over  F8 out", "F9 break at PC  F10 panel", "Click: run to line  Right click: break"];
+    for (row, line) in help.iter().enumerate() {
+        let y = LISTING_Y + (LISTING_LINES + 1 + row) as i32 * LINE_HEIGHT;
+        draw_handler.draw_text(line, x, y, FONT_SIZE, Color::GRAY);
+    }
+}
+
+fn rgb(hex: u32) -> Color {
+    Color::new((hex >> 16) as u8, (hex >> 8) as u8, hex as u8, 255)
+}
+
+fn read_keypad(raylib_handler: &RaylibHandle, keys: &KeyBindings) -> Keypad {
+    let mut keypad = Keypad::new();
+    for (key, raylib_key) in KEY_MAP.iter().enumerate() {
+        if raylib_handler.is_key_down(*raylib_key) { keypad.press(key as u8); }
+    }
+
+    let bindings = [
+        (KeyboardKey::KEY_UP, keys.up), (KeyboardKey::KEY_DOWN, keys.down),
+        (KeyboardKey::KEY_LEFT, keys.left), (KeyboardKey::KEY_RIGHT, keys.right),
+        (KeyboardKey::KEY_SPACE, keys.a), (KeyboardKey::KEY_ENTER, keys.b),
+    ];
+    for (raylib_key, key) in bindings {
+        if let Some(key) = key.filter(|_| raylib_handler.is_key_down(raylib_key)) { keypad.press(key); }
+    }
+    keypad
+}